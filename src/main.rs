@@ -3,11 +3,23 @@ use aws_sdk_s3::types::CompletedMultipartUpload;
 use aws_sdk_s3::types::CompletedPart;
 use clap::Parser;
 use crossbeam::channel;
+use futures::FutureExt;
 use http::StatusCode;
+use sha2::{Digest, Sha256};
+use std::io::Read;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
+/// Prints a line of diagnostic output in order with log messages
+macro_rules! status {
+    ($($arg:tt)*) => {
+        log_write(format!("{}\n", format_args!($($arg)*)).into_bytes())
+    };
+}
+
 fn parse_size(x: &str) -> anyhow::Result<usize> {
     let x = x.to_ascii_lowercase();
     if let Some(value) = x.strip_suffix("gb") {
@@ -22,271 +34,10617 @@ fn parse_size(x: &str) -> anyhow::Result<usize> {
     anyhow::bail!("Cannot parse size: '{}'", x)
 }
 
-#[derive(Parser)]
+fn format_size(x: usize) -> String {
+    const UNITS: [(&str, usize); 4] = [
+        ("TB", 1024 * 1024 * 1024 * 1024),
+        ("GB", 1024 * 1024 * 1024),
+        ("MB", 1024 * 1024),
+        ("KB", 1024),
+    ];
+    for (suffix, unit) in UNITS {
+        if x >= unit {
+            return format!("{:.1}{suffix}", x as f64 / unit as f64);
+        }
+    }
+    format!("{x}B")
+}
+
+fn format_duration(x: Duration) -> String {
+    let seconds = x.as_secs();
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+fn parse_duration(x: &str) -> anyhow::Result<Duration> {
+    let x = x.to_ascii_lowercase();
+    if let Some(value) = x.strip_suffix("ms") {
+        return Ok(Duration::from_millis(u64::from_str(value)?));
+    }
+    if let Some(value) = x.strip_suffix('s') {
+        return Ok(Duration::from_secs(u64::from_str(value)?));
+    }
+    if let Some(value) = x.strip_suffix('m') {
+        return Ok(Duration::from_secs(u64::from_str(value)? * 60));
+    }
+    if let Some(value) = x.strip_suffix('h') {
+        return Ok(Duration::from_secs(u64::from_str(value)? * 60 * 60));
+    }
+    if let Some(value) = x.strip_suffix('d') {
+        return Ok(Duration::from_secs(u64::from_str(value)? * 24 * 60 * 60));
+    }
+    anyhow::bail!("Cannot parse duration: '{}'", x)
+}
+
+/// Checks that a value is one the SDK knows, as its enums accept any string
+fn check_known(x: &str, what: &str, values: &[&str]) -> anyhow::Result<()> {
+    if !values.contains(&x) {
+        anyhow::bail!(
+            "Unknown {what} '{}', expected one of {}",
+            x,
+            values.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn parse_acl(x: &str) -> anyhow::Result<s3::types::ObjectCannedAcl> {
+    check_known(x, "canned ACL", s3::types::ObjectCannedAcl::values())?;
+    Ok(x.into())
+}
+
+fn parse_storage_class(x: &str) -> anyhow::Result<s3::types::StorageClass> {
+    let x = x.to_ascii_uppercase();
+    check_known(&x, "storage class", s3::types::StorageClass::values())?;
+    Ok(x.as_str().into())
+}
+
+/// A rule of --storage-rule, picking a storage class for objects that match its condition
+#[derive(Clone)]
+struct StorageRule {
+    rule: String,
+    condition: StorageCondition,
+    class: s3::types::StorageClass,
+}
+
+#[derive(Clone, Copy)]
+enum StorageCondition {
+    SizeAbove(u64),
+    SizeBelow(u64),
+    /// Time since the input file was last modified
+    AgeAbove(Duration),
+    AgeBelow(Duration),
+}
+
+fn parse_storage_rule(x: &str) -> anyhow::Result<StorageRule> {
+    let Some((condition, class)) = x.split_once(':') else {
+        anyhow::bail!("Storage rule '{x}' should be CONDITION:CLASS, e.g. 'size>1GB:GLACIER'");
+    };
+    let size = |x: &str| parse_size(x).map(|x| x as u64);
+    let condition = match condition.get(..5).unwrap_or_default() {
+        "size>" => StorageCondition::SizeAbove(size(&condition[5..])?),
+        "size<" => StorageCondition::SizeBelow(size(&condition[5..])?),
+        _ => match condition.get(..4).unwrap_or_default() {
+            "age>" => StorageCondition::AgeAbove(parse_duration(&condition[4..])?),
+            "age<" => StorageCondition::AgeBelow(parse_duration(&condition[4..])?),
+            _ => anyhow::bail!(
+                "Unknown condition '{condition}' in storage rule, expected size>SIZE, size<SIZE, \
+                age>DURATION or age<DURATION"
+            ),
+        },
+    };
+    Ok(StorageRule {
+        rule: x.to_string(),
+        condition,
+        class: parse_storage_class(class)?,
+    })
+}
+
+/// Rules of --auto-storage-class without any --storage-rule
+const DEFAULT_STORAGE_RULES: &[&str] = &[
+    "size<128KB:STANDARD",
+    "age>90d:GLACIER_IR",
+    "age>30d:STANDARD_IA",
+];
+
+fn parse_object_lock_mode(x: &str) -> anyhow::Result<s3::types::ObjectLockMode> {
+    let x = x.to_ascii_uppercase();
+    check_known(&x, "object lock mode", s3::types::ObjectLockMode::values())?;
+    Ok(x.as_str().into())
+}
+
+/// Decodes a hex digest of `size` bytes and encodes it as base64 for the API
+fn parse_hex_digest(x: &str, size: usize, algorithm: &str) -> anyhow::Result<String> {
+    use base64::Engine;
+
+    if x.len() != 2 * size || !x.bytes().all(|x| x.is_ascii_hexdigit()) {
+        anyhow::bail!("A {algorithm} has to be {} hex digits", 2 * size);
+    }
+    let bytes: Vec<u8> = (0..size)
+        .map(|i| u8::from_str_radix(&x[2 * i..2 * i + 2], 16))
+        .collect::<Result<_, _>>()?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+fn parse_sha256(x: &str) -> anyhow::Result<String> {
+    parse_hex_digest(x, 32, "SHA-256")
+}
+
+fn parse_crc32(x: &str) -> anyhow::Result<String> {
+    parse_hex_digest(x, 4, "CRC32")
+}
+
+/// Checks that an encryption context is a JSON object of strings, and encodes it for the API
+fn parse_encryption_context(x: &str) -> anyhow::Result<String> {
+    use base64::Engine;
+
+    let context: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(x) {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Encryption context should be a JSON object: {e}"),
+    };
+    if let Some((key, _)) = context.iter().find(|(_, value)| !value.is_string()) {
+        anyhow::bail!("Encryption context value of '{key}' should be a string");
+    }
+    let json = serde_json::to_string(&context)?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(json))
+}
+
+fn parse_date_time(x: &str) -> anyhow::Result<s3::primitives::DateTime> {
+    match s3::primitives::DateTime::from_str(x, s3::primitives::DateTimeFormat::DateTime) {
+        Ok(x) => Ok(x),
+        Err(e) => anyhow::bail!("Cannot parse date, expected e.g. 2030-01-31T00:00:00Z: {e}"),
+    }
+}
+
+fn parse_app_name(x: &str) -> anyhow::Result<aws_config::AppName> {
+    match aws_config::AppName::new(x.to_string()) {
+        Ok(x) => Ok(x),
+        Err(e) => anyhow::bail!("{e}"),
+    }
+}
+
+fn parse_header(x: &str) -> anyhow::Result<(String, String)> {
+    let (name, value) = match x.split_once(':') {
+        None => anyhow::bail!("Header should be 'Name: Value': '{}'", x),
+        Some((name, value)) => (name.trim(), value.trim()),
+    };
+    if let Err(e) = http::HeaderName::from_str(name) {
+        anyhow::bail!("Invalid header name '{name}': {e}");
+    }
+    if let Err(e) = http::HeaderValue::from_str(value) {
+        anyhow::bail!("Invalid value for header '{name}': {e}");
+    }
+    Ok((name.to_ascii_lowercase(), value.to_string()))
+}
+
+fn parse_metadata(x: &str) -> anyhow::Result<(String, String)> {
+    match x.split_once('=') {
+        None => anyhow::bail!("Metadata should be 'KEY=VALUE': '{}'", x),
+        Some((key, value)) => check_metadata(key.trim(), value),
+    }
+}
+
+/// Checks that a metadata entry can be sent as `x-amz-meta-` header
+fn check_metadata(key: &str, value: &str) -> anyhow::Result<(String, String)> {
+    if key.is_empty() || http::HeaderName::from_str(&format!("x-amz-meta-{key}")).is_err() {
+        anyhow::bail!("Invalid metadata key '{key}'");
+    }
+    if let Err(e) = http::HeaderValue::from_str(value) {
+        anyhow::bail!("Invalid value for metadata key '{key}': {e}");
+    }
+    Ok((key.to_ascii_lowercase(), value.to_string()))
+}
+
+/// Size of a part given with --part-sizes
+#[derive(Clone, Copy, PartialEq)]
+enum PartSize {
+    Size(usize),
+    /// All of the remaining input
+    Rest,
+}
+
+fn parse_part_size(x: &str) -> anyhow::Result<PartSize> {
+    match x.trim() {
+        "rest" => Ok(PartSize::Rest),
+        x => Ok(PartSize::Size(parse_size(x)?)),
+    }
+}
+
+#[derive(Parser, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// S3 path to upload to
-    s3_path: String,
+    /// S3 paths to upload to, the input is uploaded to each of them
+    #[arg(required_unless_present_any = ["manifest", "checksum_only"], value_name = "S3_PATH")]
+    s3_paths: Vec<String>,
+
+    /// Check that uploads below each S3 path work, instead of uploading the input
+    ///
+    /// Uploads a small random object with a multipart upload, reads it back, compares its content
+    /// and ETag, and deletes it again, printing the outcome of each step.
+    #[arg(long, conflicts_with_all = ["manifest", "input"])]
+    self_test: bool,
+
+    /// Print an approximate cost of the requests and a month of storage for the upload, instead
+    /// of uploading the input
+    ///
+    /// Uses the size of the input file, --storage-class and AWS_REGION with a built-in table of
+    /// AWS list prices, without any network requests. Transfer, tax and discounts are not included.
+    #[arg(long, requires = "input", conflicts_with = "self_test")]
+    estimate_cost: bool,
+
+    /// Print the parts, requests and object settings of the upload before uploading the input
+    ///
+    /// Lists the byte range of every part, e.g. to check the boundaries of --part-sizes. Printed
+    /// as JSON with an --output other than text. Needs a regular file as input.
+    #[arg(long, requires = "input", conflicts_with_all = ["self_test", "estimate_cost"])]
+    show_plan: bool,
+
+    /// Print the plan of --show-plan and stop, instead of uploading the input
+    #[arg(long, requires = "show_plan")]
+    plan_only: bool,
+
+    /// Print the ETag each of these files would be uploaded with, instead of uploading anything
+    ///
+    /// Uses --block-size, --no-multipart and --multipart-threshold like an upload, without any
+    /// network requests. Prints one `etag  path` line per file.
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 1..,
+        conflicts_with_all = [
+            "input", "input_url", "manifest", "self_test", "estimate_cost", "show_plan",
+            "resume_incomplete", "bench", "part_sizes", "part_size_ramp"
+        ]
+    )]
+    checksum_only: Vec<PathBuf>,
+
+    /// List the unfinished multipart uploads below each S3 path and resume them, instead of
+    /// uploading the input
+    ///
+    /// Uploads recorded in --state-dir are resumed from their input file, if it did not change.
+    /// For the others, s3put asks whether to abort them when stdin is a terminal, and keeps them
+    /// otherwise.
+    #[arg(
+        long,
+        conflicts_with_all = ["manifest", "input", "self_test", "estimate_cost"]
+    )]
+    resume_incomplete: bool,
+
+    /// Measure the upload throughput below each S3 path with random data, instead of uploading
+    /// the input
+    ///
+    /// Uploads --bench-size with the usual settings, e.g. --block-size, once for each
+    /// --bench-concurrency, and prints a table of the throughput and part latencies. The objects
+    /// are deleted afterwards.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "manifest",
+            "input",
+            "self_test",
+            "estimate_cost",
+            "resume_incomplete",
+        ]
+    )]
+    bench: bool,
+
+    /// Size of the random data each --bench run uploads
+    #[arg(long, default_value = "1GB", value_parser = parse_size, requires = "bench")]
+    bench_size: usize,
+
+    /// Parts in flight for each --bench run, e.g. '4,8,16,32', defaults to --max-inflight
+    #[arg(long, value_name = "COUNTS", value_delimiter = ',', requires = "bench")]
+    bench_concurrency: Vec<usize>,
+
+    /// Download the object at the S3 path to stdout or --download-file, instead of uploading
+    ///
+    /// Fetches ranges of --block-size with --max-inflight of them in flight, each retried up to
+    /// --max-retries times, and writes them in order. Fails if the object changes meanwhile.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "manifest", "input", "input_url", "self_test", "estimate_cost", "show_plan",
+            "resume_incomplete", "bench", "checksum_only", "split_into", "recursive", "tar",
+            "follow", "append", "resume", "keep_on_failure", "state_dir", "spool_dir",
+            "tee_on_upload", "finalize_as", "content_addressed"
+        ]
+    )]
+    download: bool,
+
+    /// File to write the object of --download to, instead of stdout
+    #[arg(long, value_name = "PATH", requires = "download")]
+    download_file: Option<PathBuf>,
+
+    /// Check each range of --download against the checksum S3 stored for it
+    ///
+    /// A range that does not match is retried like a failed request. S3 only has a checksum for
+    /// ranges that are a whole object or part, the ranges without one are reported at the end.
+    #[arg(long, requires = "download")]
+    verify_checksum: bool,
+
+    /// Only report what --resume-incomplete would do
+    #[arg(long, requires = "resume_incomplete")]
+    dry_run: bool,
+
+    /// Accept bucket names that break the S3 naming rules, for S3-compatible backends with looser
+    /// rules
+    #[arg(long)]
+    skip_bucket_validation: bool,
+
+    /// Keep the object uploaded by --self-test for inspection
+    #[arg(long, requires = "self_test")]
+    keep: bool,
+
+    /// Upload many files, listed one per line as `local_path<TAB>s3://bucket/key`
+    ///
+    /// The files are uploaded concurrently, sharing the --max-inflight parts in flight, so that
+    /// small files do not wait for each other. All lines are checked before the first upload
+    /// starts. With '-' the lines are read from stdin instead,
+    /// and each file is uploaded as soon as its line arrives, e.g. from `find`.
+    #[arg(long, conflicts_with_all = ["s3_paths", "input"])]
+    manifest: Option<PathBuf>,
+
+    /// Order in which the files of the --manifest or --recursive are uploaded, instead of the
+    /// listed or walked order
+    #[arg(long, value_enum)]
+    sort: Option<ManifestOrder>,
+
+    /// Only upload the first N files of the --manifest or --recursive, after --sort
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Number of multipart uploads of a --manifest or --recursive completed at once
+    ///
+    /// Completions do not take parts in flight, so the parts of the next files are sent while
+    /// earlier ones complete.
+    #[arg(long, value_name = "N", default_value = "4")]
+    max_completions: usize,
+
+    /// Skip files of the --manifest or --recursive whose object already has the ETag their upload
+    /// would produce, uploading only new and changed files
+    ///
+    /// The local ETag is computed with the part size of the existing object, which need not be
+    /// --block-size. Objects encrypted with SSE-KMS have ETags that never match.
+    #[arg(
+        long,
+        conflicts_with_all = ["skip_if_same", "content_addressed", "random_suffix"]
+    )]
+    sync: bool,
 
     /// Input file name
     #[arg(long, short)]
     input: Option<PathBuf>,
 
+    /// Upload every file below the --input directory to the S3 path as a key prefix
+    ///
+    /// Each file gets the key `prefix/relative/path` and is uploaded like a file of a --manifest,
+    /// concurrently, reporting failures at the end. Files smaller than --multipart-threshold,
+    /// by default --block-size, are uploaded with a single PUT. Symlinks to files are uploaded,
+    /// symlinks to directories are not followed.
+    #[arg(
+        long,
+        requires = "input",
+        conflicts_with_all = [
+            "manifest", "estimate_cost", "show_plan", "split_into", "spool_dir", "tee_on_upload",
+            "follow", "append", "resume", "finalize_as", "tar", "bench", "self_test", "input_url",
+            "download", "etag_file", "write_checksum", "checksum_only"
+        ]
+    )]
+    recursive: bool,
+
+    /// Upload the body of this http:// or https:// URL, streamed without staging it on disk
+    ///
+    /// Redirects are followed, and an error status fails the upload before anything is uploaded.
+    /// The body cannot be read again, so the upload cannot be resumed or verified.
+    #[arg(
+        long,
+        value_name = "URL",
+        value_parser = parse_input_url,
+        conflicts_with_all = [
+            "input",
+            "manifest",
+            "state_dir",
+            "follow",
+            "verify_sample",
+            "skip_if_same",
+            "tee_on_upload",
+            "spool_dir",
+        ]
+    )]
+    input_url: Option<String>,
+
+    /// Expand placeholders in the keys of the S3 paths when the upload starts, e.g.
+    /// 's3://bucket/logs/{year}/{month}/{day}/{uuid}.log'
+    ///
+    /// Known placeholders are {year}, {month}, {day} and {hour} in UTC, a random {uuid}, the
+    /// {hostname} and the {basename} of the input file. All destinations get the same values.
+    #[arg(long)]
+    key_template: bool,
+
+    /// Append a random token of 16 hex digits to the keys of the S3 paths, before the extension,
+    /// e.g. 'logs/app.log' becomes 'logs/app-3f9c2a1e07b4d85c.log'
+    ///
+    /// Applied after --key-template, all destinations get the same token.
+    #[arg(long, conflicts_with = "content_addressed")]
+    random_suffix: bool,
+
     /// Block size used for data uploads
     #[arg(long, default_value = "32MB", value_parser = parse_size)]
     block_size: usize,
 
+    /// Read the input in chunks of this size and cut the parts from them, e.g. 256MB
+    ///
+    /// Fewer, larger reads help with inputs where each read is slow to start, like tape drives,
+    /// optical media or network file systems with high latency. Has to be at least --block-size.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "mmap")]
+    read_block_size: Option<usize>,
+
+    /// Sizes of the first parts, e.g. '10MB,10MB,rest', before --block-size applies
+    ///
+    /// For layouts that need exact part boundaries. A last `rest` puts the remaining input, up to
+    /// 5GB, into one part.
+    #[arg(
+        long,
+        value_name = "SIZES",
+        value_delimiter = ',',
+        value_parser = parse_part_size,
+        conflicts_with_all = ["verify_sample", "skip_if_same", "state_dir"]
+    )]
+    part_sizes: Vec<PartSize>,
+
+    /// Start with 5MB parts and double their size up to --block-size, to finish parts early
+    ///
+    /// Parts complete soon after the start on high latency links, instead of after a full
+    /// --block-size each. Resuming with --state-dir needs all parts to have --block-size, so the
+    /// two cannot be combined.
+    #[arg(
+        long,
+        conflicts_with_all = ["part_sizes", "verify_sample", "skip_if_same", "state_dir"]
+    )]
+    part_size_ramp: bool,
+
+    /// Most parts a multipart upload may have, for backends with a lower limit than S3's 10000
+    ///
+    /// Inputs of known size that need more parts fail before uploading, streamed inputs once
+    /// they reach the limit.
+    #[arg(long, default_value = "10000")]
+    max_parts: usize,
+
     /// Number of threads to use, defaults to number of logical cores
     #[arg(long, short, default_value = "6")]
     threads: usize,
 
+    /// Expected size of the input, to show progress and check the part count for streamed input
+    #[arg(long, value_parser = parse_size)]
+    expected_size: Option<usize>,
+
+    /// Number of parts uploaded concurrently, defaults to twice the number of threads
+    #[arg(long)]
+    max_inflight: Option<usize>,
+
+    /// Maximum number of uploads sent at the same time, over all destinations
+    ///
+    /// Every part in flight is uploaded to every destination at once, so without this there can be
+    /// up to --max-inflight times the number of destinations connections. Parts beyond the limit
+    /// wait for a free connection while holding their --max-inflight slot.
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Send TCP keepalive probes after a connection has been idle this long, e.g. 30s
+    ///
+    /// Keeps connections that idle between large writes from being dropped by firewalls and NAT
+    /// gateways. Off by default, like the SDK.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    tcp_keepalive: Option<Duration>,
+
+    /// HTTP version to talk to the endpoint with, defaults to 1.1 like the SDK
+    ///
+    /// S3 itself only speaks HTTP/1.1. Some S3-compatible gateways are faster over HTTP/2, while
+    /// others break long uploads on it.
+    #[arg(long, value_enum)]
+    http_version: Option<HttpVersion>,
+
+    /// Initial HTTP/2 flow control window of each request, defaults to 2MB, e.g. 16MB
+    ///
+    /// A window smaller than the data a link holds in flight makes each part wait for window
+    /// updates, larger ones help on links with a high latency.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    http2_initial_window: Option<usize>,
+
+    /// Lease file that caps the uploads in flight of all s3put processes using it
+    ///
+    /// Each process takes a lease from the file before sending a part, and waits while
+    /// --global-concurrency leases are taken. Leases of crashed processes expire after a minute.
+    #[arg(long, value_name = "PATH", requires = "global_concurrency")]
+    global_concurrency_file: Option<PathBuf>,
+
+    /// Maximum number of uploads in flight over all processes using --global-concurrency-file
+    #[arg(long, value_name = "N", requires = "global_concurrency_file")]
+    global_concurrency: Option<usize>,
+
+    /// Maximum upload rate of each part upload in bytes per second, e.g. 10MB
+    ///
+    /// For backends that penalize single connections with a high throughput. Every part upload,
+    /// to every destination, is limited on its own.
+    #[arg(long, value_name = "RATE", value_parser = parse_size, conflicts_with = "no_multipart")]
+    max_bandwidth_per_thread: Option<usize>,
+
+    /// Time each attempt at uploading a part may take per MB of the part, on top of
+    /// --timeout-base, e.g. 2s
+    ///
+    /// Catches hung parts without failing large parts on a slow link. With the default base of
+    /// 30s, 1s per MB gives 32MB parts 62s and 5GB parts about 86 minutes. A part that times out
+    /// is retried like any other failed part.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    timeout_per_mb: Option<Duration>,
+
+    /// Time each attempt at uploading a part may take regardless of its size, with
+    /// --timeout-per-mb
+    #[arg(
+        long,
+        value_name = "DURATION",
+        default_value = "30s",
+        value_parser = parse_duration,
+        requires = "timeout_per_mb"
+    )]
+    timeout_base: Duration,
+
+    /// How part upload bodies are signed, for S3-compatible backends that reject some of them
+    ///
+    /// Parts limited by --max-bandwidth-per-thread are always streamed, unless unsigned. Defaults
+    /// to signed, or unsigned with a --provider other than aws.
+    #[arg(long, value_enum)]
+    payload_signing: Option<PayloadSigning>,
+
+    /// S3-compatible service to upload to, which sets its endpoint, addressing style and
+    /// --payload-signing
+    ///
+    /// Wasabi and B2 endpoints are derived from AWS_REGION, MinIO and R2 need AWS_ENDPOINT_URL.
+    /// AWS_ENDPOINT_URL and --payload-signing still take precedence. Other providers than aws do
+    /// not look up or follow bucket regions.
+    #[arg(long, value_enum, default_value = "aws")]
+    provider: Provider,
+
+    /// Fail before uploading if a bucket is not in this region, e.g. eu-central-1
+    ///
+    /// Checks the region each bucket is looked up or redirected to, to keep data from being
+    /// placed in the wrong geography.
+    #[arg(long, value_name = "REGION")]
+    expect_region: Option<String>,
+
+    /// Start with this many parts in flight, adding one for each completed part up to
+    /// --max-inflight
+    ///
+    /// Avoids opening all connections at once, which can trigger throttling.
+    #[arg(long, value_name = "PARTS")]
+    slow_start: Option<usize>,
+
+    /// Number of parts read ahead of the uploads, waiting for one of the --max-inflight slots
+    ///
+    /// Smooths a bursty input without uploading more parts at once. The parts in memory are at
+    /// most this plus --max-inflight.
+    #[arg(long, value_name = "PARTS", default_value = "1")]
+    read_ahead: usize,
+
+    /// Whether reading the input favors latency or throughput
+    #[arg(long, value_enum, default_value = "latency")]
+    io_strategy: IoStrategy,
+
+    /// Only start a part while it is less than this many parts ahead of the oldest part in flight
+    ///
+    /// A workaround for S3-compatible backends that misbehave when parts arrive far out of order.
+    /// 1 uploads strictly one part after another, larger windows allow more concurrency, up to
+    /// --max-inflight.
+    #[arg(long, value_name = "WINDOW")]
+    ordered: Option<usize>,
+
     /// Print verbose information, statistics, etc
+    ///
+    /// -v shows progress information, -vv debug details. RUST_LOG overrides this.
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// Only print errors, no warnings, retry messages or summary
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Line printed to stderr for each uploaded object, e.g. '{bucket} {key} {bytes}'
+    ///
+    /// Known placeholders are {bucket}, {key}, {etag}, {version_id}, the {checksum} of
+    /// --checksum-algorithm, the size in {bytes} or as {size} like '4.2GB', the {duration} in
+    /// seconds, and those of --key-template. Defaults to
+    /// 'Uploaded s3://{bucket}/{key} ({size}, etag={etag})', with the version in a bucket with
+    /// versioning and the checksum if any. Not printed with --quiet.
+    #[arg(long, value_name = "FORMAT")]
+    success_format: Option<String>,
+
+    /// Print a line about the upload progress this often, e.g. 30s, 0 disables it
+    ///
+    /// Defaults to 1m if stderr is not a terminal, e.g. in CI logs where a silent upload looks
+    /// like a hang, and to off otherwise.
+    #[arg(long, value_name = "INTERVAL", value_parser = parse_duration, conflicts_with = "quiet")]
+    heartbeat: Option<Duration>,
+
+    /// Print the progress every second, like --heartbeat 1s, e.g. to watch a slow upload
+    #[arg(long, conflicts_with_all = ["quiet", "heartbeat"])]
+    progress: bool,
+
     /// Determines how often each chunk should be retried before giving up
+    ///
+    /// Parts whose connection is lost, e.g. reset while sending them, are retried up to 5 more
+    /// times, which counts neither here nor against --retry-budget.
     #[arg(long, default_value = "4")]
     max_retries: u32,
-}
 
-async fn start_upload(
-    bucket: &str,
-    key: &str,
-    verbose: u8,
-) -> anyhow::Result<(aws_config::SdkConfig, String)> {
-    let config = aws_config::load_from_env().await;
-    let region = config.region().cloned();
-    let mut config = config
-        .into_builder()
-        .region(region.or_else(|| Some(s3::config::Region::new("us-east-2"))))
-        .build();
+    /// Total number of retries allowed across all parts, before the upload is aborted
+    #[arg(long)]
+    retry_budget: Option<u32>,
 
-    for _ in 0..3 {
-        let client = s3::Client::new(&config);
-        let response = match client
-            .create_multipart_upload()
-            .bucket(bucket)
-            .key(key)
-            .send()
-            .await
-        {
-            Ok(x) => x,
-            Err(e) => {
-                if verbose > 1 {
-                    eprintln!("{:?}", e);
-                }
-                if let s3::error::SdkError::ServiceError(response) = &e {
-                    if response.raw().status().as_u16() == StatusCode::MOVED_PERMANENTLY {
-                        if let Some(x) = response.raw().headers().get("x-amz-bucket-region") {
-                            config = config
-                                .into_builder()
-                                .region(Some(s3::config::Region::new(x.to_string())))
-                                .build();
-                            if verbose > 0 {
-                                eprintln!("Redirected to {}", x);
-                            }
-                            continue;
-                        }
-                    }
-                }
-                return Err(e.into());
-            }
-        };
-        let upload_id = match response.upload_id {
-            None => anyhow::bail!("Could not get upload_id"),
-            Some(x) => x,
-        };
-        if verbose > 1 {
-            eprintln!("Starting upload, upload_id = {upload_id}")
-        }
-        return Ok((config, upload_id));
-    }
-    anyhow::bail!("Stopped following redirects after 3 hops")
-}
+    /// Retries of the AWS SDK within each attempt at a part or single PUT
+    ///
+    /// Off by default, so that --max-retries alone decides how often a part is sent. With standard
+    /// or adaptive, each of the 1 + --max-retries attempts makes up to 1 + --sdk-retries requests.
+    /// Creating, completing and aborting uploads always use the SDK's standard retries, as s3put
+    /// does not retry them itself.
+    #[arg(long, value_enum, default_value = "off")]
+    sdk_retry_mode: SdkRetryMode,
 
-async fn upload(
-    args: &Args,
-    bucket: String,
-    key: String,
-    mut input: Box<dyn std::io::Read + Send + Sync>,
-    num_tokens: usize,
-    config: &aws_config::SdkConfig,
-    upload_id: String,
-) -> anyhow::Result<()> {
-    // add initial tokens
-    let (token_sender, token_receiver) = channel::bounded(num_tokens);
-    for _ in 0..num_tokens {
-        if token_sender.send(Ok(None)).is_err() {
-            anyhow::bail!("Failed to initialize threads");
-        }
-    }
+    /// Retries of the AWS SDK with --sdk-retry-mode standard or adaptive
+    #[arg(long, default_value = "2")]
+    sdk_retries: u32,
 
-    let mut part_results = Vec::new();
-    let mut wait_for_part = || {
-        match token_receiver.recv() {
-            Err(e) => anyhow::bail!("Failed communicate with threads: {e}"),
-            Ok(Err(e)) => anyhow::bail!("Failed to upload part: {e}"),
-            Ok(Ok(Some(x))) => part_results.push(x),
-            Ok(Ok(None)) => (),
-        }
-        Ok(())
-    };
-    for part_number in 1.. {
-        let mut buffer = vec![0_u8; args.block_size];
-        let mut pos = 0;
-        let mut end_of_file = false;
-        while pos < buffer.len() && !end_of_file {
-            let num_read = input.read(&mut buffer[pos..])?;
-            end_of_file = num_read == 0;
-            pos += num_read;
-        }
-        buffer.resize(pos, 0_u8);
+    /// Shell command to run after a successful upload
+    ///
+    /// The environment variables S3PUT_BUCKET, S3PUT_KEY, S3PUT_ETAG and S3PUT_BYTES
+    /// describe the uploaded object, and S3PUT_VERSION_ID in a bucket with versioning.
+    #[arg(long)]
+    on_success: Option<String>,
 
-        if buffer.is_empty() {
-            break;
-        }
+    /// Print a presigned GET URL for each uploaded object to stdout
+    ///
+    /// With `--output aws-json`, the URL is added to the JSON as `PresignedUrl` instead.
+    #[arg(long, conflicts_with = "sse_c_key_file")]
+    print_presigned_url: bool,
 
-        wait_for_part()?;
+    /// How long the URL of --print-presigned-url is valid, at most 7 days, e.g. 1h
+    ///
+    /// Presigning with temporary credentials limits the URL to their lifetime.
+    #[arg(
+        long,
+        value_parser = parse_duration,
+        default_value = "1h",
+        requires = "print_presigned_url"
+    )]
+    presign_expires: Duration,
 
-        let config = config.clone();
-        let max_retries = args.max_retries;
-        let bucket = bucket.to_string();
-        let key = key.to_string();
-        let upload_id = upload_id.to_string();
-        let token_sender = token_sender.clone();
-        tokio::spawn(async move {
-            let client = s3::Client::new(&config);
-            let mut retry_count = 0;
-            let result = loop {
-                match client
-                    .upload_part()
-                    .body(buffer.clone().into())
-                    .bucket(&bucket)
-                    .key(&key)
-                    .upload_id(&upload_id)
-                    .part_number(part_number)
-                    .send()
-                    .await
-                {
-                    Ok(response) => {
-                        break Ok(Some(
-                            CompletedPart::builder()
-                                .e_tag(response.e_tag.unwrap_or("".to_string()))
-                                .part_number(part_number)
-                                .build(),
-                        ))
-                    }
-                    Err(e) => {
-                        retry_count += 1;
-                        if retry_count > max_retries {
-                            break Err(e);
-                        }
-                        eprintln!("Failed to upload chunk: {}, retrying", e);
-                        tokio::time::sleep(Duration::from_secs(2_u64.pow(retry_count))).await;
-                    }
-                }
-            };
-            let _ = token_sender.send(result);
-        });
+    /// Exit with the exit code of the --on-success command if it fails
+    #[arg(long, requires = "on_success")]
+    strict_hook: bool,
 
-        if end_of_file {
-            break;
-        }
-    }
+    /// Credential profile to use, either once for all destinations or once per destination in the
+    /// order they are given
+    #[arg(long)]
+    profile: Vec<String>,
 
-    // drain remaining results
-    for _ in 0..num_tokens {
-        wait_for_part()?;
-    }
+    /// Read credentials from this file instead of the default locations, for all destinations
+    ///
+    /// The file has the format of `~/.aws/credentials`. Other settings, like the region, still
+    /// come from the environment and --profile.
+    #[arg(long, value_name = "PATH")]
+    credentials_file: Option<PathBuf>,
 
-    // finalize upload
+    /// Profile of the --credentials-file to use
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_value = "default",
+        requires = "credentials_file"
+    )]
+    credentials_profile: String,
 
-    part_results.sort_by_key(|x| x.part_number);
-    let client = s3::Client::new(config);
-    client
-        .complete_multipart_upload()
-        .bucket(bucket)
-        .key(key)
-        .upload_id(upload_id)
-        .multipart_upload(
-            CompletedMultipartUpload::builder()
-                .set_parts(Some(part_results))
-                .build(),
-        )
-        .send()
-        .await?;
+    /// Only take credentials from this source, instead of the first one of the default chain
+    /// that has any
+    ///
+    /// Fails before uploading if the source has no credentials.
+    #[arg(long, value_enum, conflicts_with = "credentials_file")]
+    credential_source: Option<CredentialSource>,
 
-    Ok(())
-}
+    /// Upload with an MFA authenticated session, obtained from STS with this MFA device
+    ///
+    /// The session is requested with the credentials of the first --profile and used for all
+    /// destinations. Without --mfa-token the code is prompted for if stdin is a terminal.
+    #[arg(long, value_name = "ARN")]
+    mfa_serial: Option<String>,
 
-async fn run(args: &Args) -> anyhow::Result<()> {
-    if args.block_size < 3 * 1024 * 1024 {
-        anyhow::bail!("Part size too small, 5MB is the minimum");
-    }
+    /// Current code of the --mfa-serial device
+    #[arg(long, value_name = "CODE", requires = "mfa_serial")]
+    mfa_token: Option<String>,
 
-    let (bucket, key) = match args.s3_path.strip_prefix("s3://") {
-        None => anyhow::bail!("S3 path has to start with 's3://'"),
-        Some(x) => match x.split_once('/') {
-            None => anyhow::bail!("S3 path should be 's3://bucket/key'"),
-            Some((bucket, key)) => (bucket.to_string(), key.to_string()),
-        },
-    };
+    /// Store the object under the hex SHA-256 of its content, using the key of each S3 path as
+    /// prefix
+    ///
+    /// The hash is only known once the input ends, so multipart uploads go to a temporary key
+    /// and are then copied server-side to the final key, which briefly needs twice the storage.
+    /// Single PUT uploads hash the input up front instead.
+    #[arg(long)]
+    content_addressed: bool,
 
-    let input: Box<dyn std::io::Read + Send + Sync> = if let Some(file) = &args.input {
-        Box::new(match std::fs::File::open(file) {
-            Err(e) => {
-                eprintln!("Failed to open input file: {}", e);
-                std::process::exit(1);
-            }
-            Ok(x) => x,
-        })
-    } else {
-        Box::new(std::io::stdin())
-    };
+    /// Write the SHA-256 of the input to this file, with a line per uploaded key
+    ///
+    /// With the default format, `sha256sum -c` can check a downloaded copy stored under the key.
+    #[arg(long, value_name = "PATH", conflicts_with = "manifest")]
+    write_checksum: Option<PathBuf>,
 
-    let num_tokens = 2 * args.threads;
+    /// Write the ETag of the uploaded object to this file, without quotes, a line per destination
+    ///
+    /// The file is removed when the upload starts and only written if it succeeds.
+    #[arg(long, value_name = "PATH", conflicts_with = "manifest")]
+    etag_file: Option<PathBuf>,
 
-    // start multi-part upload
-    let (config, upload_id) = start_upload(&bucket, &key, args.verbose).await?;
+    /// Line format of --write-checksum
+    #[arg(long, value_enum, default_value = "gnu", requires = "write_checksum")]
+    checksum_format: ChecksumFormat,
 
-    if let Err(e) = upload(
-        args,
-        bucket.clone(),
-        key.clone(),
-        input,
-        num_tokens,
-        &config,
-        upload_id.clone(),
-    )
-    .await
-    {
-        eprintln!("Aborting upload: {e}");
-        let client = s3::Client::new(&config);
-        client
-            .abort_multipart_upload()
-            .bucket(bucket)
-            .key(key)
-            .upload_id(upload_id)
-            .send()
-            .await?;
-        anyhow::bail!("Failed upload");
-    }
+    /// Move the object to this key once the upload is complete, so readers never see a partial
+    /// object at it
+    ///
+    /// The upload goes to the key of the S3 path, which is then copied server-side to this key and
+    /// deleted. Pass it once for all destinations or once per destination.
+    #[arg(long, value_name = "KEY", conflicts_with_all = ["content_addressed", "no_multipart"])]
+    finalize_as: Vec<String>,
 
-    Ok(())
-}
+    /// Only move the object to the --finalize-as key if the object there has this ETag, or with
+    /// `none` or `*` only if there is no object there
+    ///
+    /// Guards the move against concurrent writers. If the condition does not hold the upload
+    /// fails and the temporary object is kept. Pass it once for all destinations or once per
+    /// destination.
+    #[arg(long, value_name = "ETAG", requires = "finalize_as")]
+    finalize_if_match: Vec<String>,
 
-fn main() {
-    let args = Args::parse();
+    /// Write a JSON line per uploaded part to this file, with its timing, size, retries and ETag
+    ///
+    /// Use '-' to write to stderr.
+    #[arg(long)]
+    trace_out: Option<PathBuf>,
 
-    let rt = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(args.threads + 2) // we need 2 extra threads for blocking I/O
-        .enable_io()
-        .enable_time()
-        .build()
-        .unwrap();
+    /// Push statistics of each upload to a statsd server or a Prometheus Pushgateway
+    ///
+    /// `statsd://host:port` sends counters over UDP, `http://host:port` pushes to the Pushgateway
+    /// under the job `s3put`, unless the URL already has a `/metrics/job/...` path. Failing to
+    /// push only logs a warning.
+    #[arg(long, value_name = "URL", value_parser = parse_metrics_endpoint)]
+    metrics_endpoint: Option<MetricsEndpoint>,
+
+    /// POST a JSON object to this `http://` URL after each object is uploaded or failed
+    ///
+    /// The object has the bucket, key, etag, bytes, duration in seconds and a status of `success`
+    /// or `failed`, with an `error` message on failure. A failed upload reports each of its S3
+    /// paths. Failing to deliver is retried twice and then only logs a warning.
+    #[arg(long, value_name = "URL", value_parser = parse_webhook_url)]
+    webhook_url: Option<http::Uri>,
+
+    /// Secret to sign webhooks with, sent as the hex HMAC-SHA256 of the body in the header
+    /// `X-S3put-Signature: sha256=...`
+    #[arg(long, requires = "webhook_url")]
+    webhook_secret: Option<String>,
+
+    /// Format of the result printed to stdout for each uploaded object
+    #[arg(long, value_enum, default_value = "text")]
+    output: OutputFormat,
+
+    /// Upload with a single PUT instead of a multipart upload
+    ///
+    /// For backends with broken multipart support. Input that is not a regular file is buffered
+    /// completely in memory.
+    #[arg(long)]
+    no_multipart: bool,
+
+    /// Fail instead of uploading with a single PUT if the backend does not implement multipart
+    /// uploads
+    #[arg(long)]
+    no_multipart_fallback: bool,
+
+    /// Hex SHA-256 of the input, for S3 to check a single PUT against
+    ///
+    /// Only for uploads with a single PUT, see --no-multipart and --multipart-threshold.
+    #[arg(long, value_name = "HEX", value_parser = parse_sha256, conflicts_with = "content_crc32")]
+    content_sha256: Option<String>,
+
+    /// Hex CRC32 of the input, for S3 to check a single PUT against
+    ///
+    /// Only for uploads with a single PUT, see --no-multipart and --multipart-threshold.
+    #[arg(long, value_name = "HEX", value_parser = parse_crc32)]
+    content_crc32: Option<String>,
+
+    /// Have S3 check and store a checksum of this algorithm, computed while uploading
+    ///
+    /// Each part is hashed once before it is sent, and S3 rejects a part whose bytes do not match.
+    /// The checksum S3 reports for the completed upload has to match the parts, and is printed
+    /// after the upload.
+    #[arg(
+        long,
+        visible_alias = "checksum",
+        value_enum,
+        conflicts_with_all = ["content_sha256", "content_crc32", "use_sidecar_checksum", "append"]
+    )]
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+
+    /// Kind of checksum of a multipart upload, by default full-object for crc64nvme and composite
+    /// otherwise
+    ///
+    /// A composite checksum is the checksum of the checksums of the parts, with the number of
+    /// parts as suffix. A full-object checksum is the one a single PUT of the same data gets, but
+    /// only supports the CRC algorithms.
+    #[arg(long, value_enum, requires = "checksum_algorithm")]
+    checksum_type: Option<ChecksumType>,
+
+    /// After completing a multipart upload, check that S3 reports the checksum expected from the
+    /// parts, or for a full-object checksum from the input
+    #[arg(long, requires = "checksum_algorithm")]
+    post_verify_checksum: bool,
+
+    /// Check the input against the checksum in INPUT.sha256 or INPUT.md5 next to it
+    ///
+    /// The sidecar has the hex digest as written by sha256sum or md5sum, optionally followed by
+    /// the file name, or in their BSD format. A single PUT passes it to S3, which rejects an
+    /// upload that does not match. A multipart upload hashes the input as it is uploaded and warns
+    /// on a mismatch. Without a sidecar, the upload goes on with a warning.
+    #[arg(
+        long,
+        requires = "input",
+        conflicts_with_all = ["content_sha256", "content_crc32"]
+    )]
+    use_sidecar_checksum: bool,
+
+    /// Fail instead of warning if the sidecar of --use-sidecar-checksum is missing or does not
+    /// match, aborting a multipart upload before it is completed
+    #[arg(long, requires = "use_sidecar_checksum")]
+    strict_sidecar: bool,
+
+    /// Upload input files smaller than this with a single PUT, larger ones with a multipart
+    /// upload of --block-size parts
+    ///
+    /// Like `multipart_threshold` of the AWS CLI. Without it every upload is a multipart upload,
+    /// unless --no-multipart is given.
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        conflicts_with_all = [
+            "no_multipart",
+            "finalize_as",
+            "verify_sample",
+            "sparse",
+            "max_bandwidth_per_thread",
+        ]
+    )]
+    multipart_threshold: Option<usize>,
+
+    /// Do not read the holes of a sparse input file from disk, upload zeros for them directly
+    ///
+    /// Only supported on Linux, where holes are found with SEEK_HOLE/SEEK_DATA. Elsewhere, or
+    /// for file systems without hole support, the whole file is read as usual. S3 still stores
+    /// the zeros.
+    #[arg(long, requires = "input", conflicts_with = "no_multipart")]
+    sparse: bool,
+
+    /// Upload a tar archive of the input directory, streamed without a tar file on disk
+    ///
+    /// Entries are below the name of the directory and keep their modes and modification times,
+    /// symlinks are archived as links. The archive cannot be read again, so it cannot be resumed
+    /// or verified.
+    #[arg(
+        long,
+        requires = "input",
+        conflicts_with_all = [
+            "no_multipart",
+            "sparse",
+            "mmap",
+            "follow",
+            "state_dir",
+            "verify_sample",
+            "skip_if_same",
+        ]
+    )]
+    tar: bool,
+
+    /// Memory-map the input file and upload parts straight from the mapping, without reading
+    /// them into buffers first
+    ///
+    /// The input has to be a regular file that is not modified during the upload. It is checked
+    /// to not have shrunk before each part, but truncating it while a part is uploaded can still
+    /// crash s3put.
+    #[arg(
+        long,
+        requires = "input",
+        conflicts_with_all = ["no_multipart", "sparse", "follow"]
+    )]
+    mmap: bool,
+
+    /// After the upload, download this many random parts and compare them with the input file
+    ///
+    /// Catches corruption that the ETag does not reveal. Needs a regular file as input.
+    #[arg(long, value_name = "N", conflicts_with = "no_multipart")]
+    verify_sample: Option<usize>,
+
+    /// Retries of a failed --verify-sample or --post-verify-checksum, for backends whose reads
+    /// can lag behind a completed upload
+    ///
+    /// Waits 0.5s before the first retry, twice as long before each further one up to 32s.
+    /// Content that still differs after all retries fails as a mismatch, other errors as an
+    /// unreadable object.
+    #[arg(long, value_name = "N", default_value = "3")]
+    verify_retries: u32,
+
+    /// Before completing, check that the server lists every uploaded part, retrying the listing
+    /// until it does
+    ///
+    /// For S3 compatible backends where a just uploaded part may not be visible yet.
+    #[arg(long)]
+    check_parts: bool,
+
+    /// Skip the upload if every destination already has an object with the ETag this upload would
+    /// produce
+    ///
+    /// Needs a regular file as input and the same --block-size as the existing object was uploaded
+    /// with. Objects encrypted with SSE-KMS or SSE-C have ETags that never match.
+    #[arg(long, conflicts_with = "content_addressed")]
+    skip_if_same: bool,
+
+    /// Ask on the terminal before overwriting an existing object, or before uploading more than
+    /// --confirm-above
+    ///
+    /// Fails instead of asking if stdin is not a terminal, or is the input.
+    #[arg(long)]
+    interactive: bool,
+
+    /// Size of the input above which --interactive asks before uploading
+    #[arg(long, value_name = "SIZE", default_value = "10GB", value_parser = parse_size)]
+    confirm_above: usize,
+
+    /// Answer yes to all questions of --interactive
+    #[arg(long, requires = "interactive")]
+    yes: bool,
+
+    /// Encrypt the object with the customer-provided AES-256 key in this file (SSE-C)
+    ///
+    /// The file contains the raw 32 byte key, e.g. from `openssl rand 32`. The same key is needed
+    /// to read the object back.
+    #[arg(long, value_name = "PATH")]
+    sse_c_key_file: Option<PathBuf>,
+
+    /// Canned ACL of the uploaded object, e.g. bucket-owner-full-control
+    ///
+    /// Buckets with Object Ownership set to bucket owner enforced reject any ACL.
+    #[arg(long, value_parser = parse_acl)]
+    acl: Option<s3::types::ObjectCannedAcl>,
+
+    /// Storage class of the uploaded object, e.g. STANDARD_IA or REDUCED_REDUNDANCY
+    #[arg(long, value_parser = parse_storage_class)]
+    storage_class: Option<s3::types::StorageClass>,
+
+    /// Pick the storage class of each object by the first matching --storage-rule
+    ///
+    /// Without any --storage-rule, objects below 128KB stay STANDARD, input files unchanged for
+    /// more than 90 days become GLACIER_IR and for more than 30 days STANDARD_IA. Objects that
+    /// match no rule get the bucket's default class.
+    #[arg(long, conflicts_with = "storage_class")]
+    auto_storage_class: bool,
+
+    /// Rule for --auto-storage-class as CONDITION:CLASS, e.g. 'size>1GB:GLACIER' or
+    /// 'age>30d:STANDARD_IA', can be repeated
+    ///
+    /// Conditions compare the size of the input, or the time since the input file was modified,
+    /// with size>, size<, age> or age<.
+    #[arg(long, value_parser = parse_storage_rule, requires = "auto_storage_class")]
+    storage_rule: Vec<StorageRule>,
+
+    /// Object Lock retention mode of the uploaded object, GOVERNANCE or COMPLIANCE
+    ///
+    /// Needs a bucket with Object Lock enabled and --object-lock-retain-until.
+    #[arg(long, value_parser = parse_object_lock_mode, requires = "object_lock_retain_until")]
+    object_lock_mode: Option<s3::types::ObjectLockMode>,
+
+    /// Date until which the object is retained, e.g. 2030-01-31T00:00:00Z
+    #[arg(long, value_parser = parse_date_time, requires = "object_lock_mode")]
+    object_lock_retain_until: Option<s3::primitives::DateTime>,
+
+    /// Place an Object Lock legal hold on the uploaded object
+    #[arg(long)]
+    legal_hold: bool,
+
+    /// Encrypt the uploaded object with SSE-KMS, with the AWS managed key unless
+    /// --sse-kms-key-id is given
+    #[arg(long, conflicts_with = "sse_c_key_file")]
+    sse_kms: bool,
+
+    /// ID, ARN or alias of the KMS key for --sse-kms
+    #[arg(long, value_name = "KEY_ID", requires = "sse_kms")]
+    sse_kms_key_id: Option<String>,
+
+    /// Encryption context for --sse-kms as a JSON object of strings, e.g. '{"project":"x"}'
+    #[arg(
+        long,
+        value_name = "JSON",
+        value_parser = parse_encryption_context,
+        requires = "sse_kms"
+    )]
+    sse_kms_encryption_context: Option<String>,
+
+    /// Use an S3 Bucket Key for --sse-kms, which reduces the requests to KMS
+    #[arg(long, requires = "sse_kms")]
+    bucket_key_enabled: bool,
+
+    /// Set user metadata on the uploaded object, e.g. 'build=1234', can be repeated
+    #[arg(long = "metadata", value_name = "KEY=VALUE", value_parser = parse_metadata)]
+    metadata: Vec<(String, String)>,
+
+    /// Read user metadata from a file, either a JSON object of strings or `KEY=VALUE` lines
+    ///
+    /// Entries of --metadata replace entries of the file with the same key.
+    #[arg(long, value_name = "PATH")]
+    metadata_file: Option<PathBuf>,
+
+    /// Store the modification time of the input file as user metadata, so it can be restored
+    ///
+    /// Sets `mtime` (the header `x-amz-meta-mtime`) to the seconds since the Unix epoch. Entries
+    /// of --metadata and --metadata-file with the same key take precedence.
+    #[arg(long, requires = "input")]
+    preserve_mtime: bool,
+
+    /// With --preserve-mtime also store the permissions of the input file, as `mode` in octal,
+    /// e.g. '644'
+    #[arg(long, requires = "preserve_mtime")]
+    preserve_mode: bool,
+
+    /// Abort the upload if reading from stdin or a pipe stalls for this long, e.g. 30s or 5m
+    ///
+    /// Regular files are never subject to the timeout.
+    #[arg(long, value_parser = parse_duration)]
+    read_timeout: Option<Duration>,
+
+    /// Give up if the parts are not uploaded within this time since the start, e.g. 45m
+    ///
+    /// No new parts are sent after the deadline, the multipart upload is aborted, or kept with
+    /// --state-dir, and s3put exits with code 124. The deadline covers all files of --manifest.
+    #[arg(long, value_parser = parse_duration)]
+    deadline: Option<Duration>,
+
+    /// Abort an upload whose rate stays below this over --min-throughput-window, e.g. 1MB
+    ///
+    /// The rate counts the bytes of completed parts, like the progress, so a slow input stalls it
+    /// as well. Unlike --deadline it only fails the current upload, with exit code 1.
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    min_throughput: Option<usize>,
+
+    /// Period over which the rate of --min-throughput is measured, e.g. 5m
+    ///
+    /// No upload is aborted before it has run this long.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        default_value = "1m",
+        value_parser = parse_duration,
+        requires = "min_throughput"
+    )]
+    min_throughput_window: Duration,
+
+    /// Keep reading the input file as it grows, like `tail -f`, until SIGHUP is received or
+    /// --idle-timeout passes without new data
+    ///
+    /// Data is only uploaded once a whole --block-size part is available, except for the last
+    /// part. Without --idle-timeout, the upload is only finished by SIGHUP, which is not available
+    /// on Windows.
+    #[arg(
+        long,
+        requires = "input",
+        conflicts_with_all = [
+            "no_multipart",
+            "multipart_threshold",
+            "sparse",
+            "verify_sample",
+            "skip_if_same",
+        ]
+    )]
+    follow: bool,
+
+    /// Finish a --follow upload once the input file has not grown for this long, e.g. 5m
+    #[arg(long, value_parser = parse_duration, requires = "follow")]
+    idle_timeout: Option<Duration>,
+
+    /// What to do with a multipart upload on SIGINT or SIGTERM
+    ///
+    /// Both stop reading input and let the parts in flight finish. A second signal exits
+    /// immediately.
+    #[arg(long, value_enum, default_value = "abort")]
+    on_signal: SignalAction,
+
+    /// What a part that fails for one of several destinations does to the others
+    #[arg(long, value_enum, default_value = "abort-all")]
+    fanout_failure: FanoutFailure,
+
+    /// What to do if the input is empty
+    #[arg(long, value_enum, default_value = "object")]
+    allow_empty: EmptyInput,
+
+    /// What to do if the key already has unfinished multipart uploads, e.g. from a crashed run
+    #[arg(
+        long,
+        value_enum,
+        default_value = "new",
+        conflicts_with_all = ["state_dir", "part_sizes", "part_size_ramp", "content_addressed"]
+    )]
+    on_existing_upload: ExistingUpload,
+
+    /// Continue this multipart upload to the S3 path instead of starting a new one
+    ///
+    /// The parts the server already has are read from the input but not uploaded again. With a
+    /// regular file as input, only parts whose ETag matches the file are kept; other input, e.g.
+    /// stdin, has to be the same as in the interrupted run.
+    #[arg(
+        long,
+        value_name = "UPLOAD_ID",
+        conflicts_with_all = [
+            "manifest", "state_dir", "on_existing_upload", "part_sizes", "part_size_ramp",
+            "content_addressed", "append", "follow", "no_multipart", "split_into",
+            "random_suffix", "key_template"
+        ]
+    )]
+    resume: Option<String>,
+
+    /// Keep a failed multipart upload instead of aborting it, and print its upload ID for --resume
+    ///
+    /// A completed object with the wrong checksum is kept as well, instead of deleted.
+    #[arg(long, conflicts_with = "state_dir")]
+    keep_on_failure: bool,
+
+    /// Record multipart uploads in this directory, so that running the same upload again resumes
+    /// it instead of starting over
+    ///
+    /// A failed or interrupted upload is then kept instead of aborted. Running again with the same
+    /// input file and S3 paths only uploads the parts the server does not have yet, as long as
+    /// the file and --block-size did not change. The record is removed once the upload is
+    /// complete.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["follow", "no_multipart"])]
+    state_dir: Option<PathBuf>,
+
+    /// Append the input to the existing object at the S3 path, replacing it with the result
+    ///
+    /// The existing object is copied server-side into the first parts of a multipart upload,
+    /// followed by the input. Objects below 5MB, the minimum size of a part, are downloaded and
+    /// uploaded again in front of the input instead. A missing object is created. Needs a single
+    /// S3 path, and the object must not change during the upload. Its metadata is not kept, the
+    /// result gets the options of this upload.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "manifest",
+            "no_multipart",
+            "multipart_threshold",
+            "part_sizes",
+            "part_size_ramp",
+            "state_dir",
+            "on_existing_upload",
+            "content_addressed",
+            "skip_if_same",
+            "write_checksum",
+            "verify_sample",
+            "mmap",
+            "sparse",
+            "finalize_as",
+        ]
+    )]
+    append: bool,
+
+    /// Copy stdin to a temporary file in this directory first, and upload that file
+    ///
+    /// Needs as much free space as the input is large, but the upload can then use everything
+    /// that needs a regular file, e.g. --verify-sample. The file is removed afterwards, also if the
+    /// upload fails.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["input", "manifest"])]
+    spool_dir: Option<PathBuf>,
+
+    /// Upload the input as separate objects of at most this size, e.g. 5GB
+    ///
+    /// Each S3 path gets the objects KEY.part0001, KEY.part0002 and so on, each a multipart upload
+    /// of its own, and then KEY.manifest, a JSON list of the objects and their sizes. The manifest
+    /// is written last, so readers can treat the set as complete once it exists.
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        conflicts_with_all = [
+            "manifest", "tar", "follow", "state_dir", "append", "verify_sample", "skip_if_same",
+            "content_addressed", "random_suffix", "tee_on_upload", "spool_dir",
+            "use_sidecar_checksum", "interactive", "finalize_as", "mmap", "sparse",
+            "expected_size"
+        ]
+    )]
+    split_into: Option<usize>,
+
+    /// Copy stdin to this file while uploading it, and retry from the copy if the upload fails
+    ///
+    /// After a failure the rest of stdin is still copied, then the file is uploaded once more.
+    /// If writing the file fails, e.g. on a full disk, the upload goes on without a copy to
+    /// retry from. The file is removed after a successful upload.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["input", "manifest", "spool_dir"]
+    )]
+    tee_on_upload: Option<PathBuf>,
+
+    /// Keep the --tee-on-upload file after a successful upload
+    #[arg(long, requires = "tee_on_upload")]
+    keep_tee: bool,
+
+    /// Add a header to every S3 request, e.g. 'x-tenant-id: 42', can be repeated
+    ///
+    /// For gateways in front of S3 compatible storage. Headers are added before signing and
+    /// replace headers the SDK sets itself.
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// Append a JSON line for every attempt at an S3 request to this file, for auditing
+    ///
+    /// Each line has the `time`, `operation`, `bucket`, `key`, `part_number`, the SDK `attempt`,
+    /// the HTTP `status`, `request_id`, `extended_request_id`, the `duration` in seconds, and the
+    /// `error` if there was no response. Retries are lines of their own.
+    #[arg(long, value_name = "PATH")]
+    api_log: Option<PathBuf>,
+
+    /// Identifier appended to the User-Agent of every request, as `app/<SUFFIX>`
+    ///
+    /// Only letters, digits and !#$%&'*+-.^_`|~ are allowed.
+    #[arg(long, value_name = "SUFFIX", value_parser = parse_app_name)]
+    user_agent_suffix: Option<aws_config::AppName>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ChecksumFormat {
+    /// `<hash>  <key>`, as written by `sha256sum`
+    Gnu,
+    /// `SHA256 (<key>) = <hash>`, as written by `sha256sum --tag` and BSD `sha256`
+    Bsd,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ManifestOrder {
+    /// By local path
+    Name,
+    /// Smallest file first, e.g. to fail fast
+    Size,
+    /// Most recently modified file first
+    Mtime,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Provider {
+    Aws,
+    Wasabi,
+    /// Backblaze B2
+    B2,
+    Minio,
+    /// Cloudflare R2
+    R2,
+}
+
+impl Provider {
+    /// Region to use if none is configured
+    fn default_region(self) -> Option<&'static str> {
+        match self {
+            Self::Aws => Some("us-east-2"),
+            Self::Wasabi | Self::Minio => Some("us-east-1"),
+            Self::B2 => None,
+            Self::R2 => Some("auto"),
+        }
+    }
+
+    /// Endpoint to use if none is configured
+    fn endpoint(self, region: &str) -> anyhow::Result<Option<String>> {
+        match self {
+            Self::Aws => Ok(None),
+            Self::Wasabi => Ok(Some(format!("https://s3.{region}.wasabisys.com"))),
+            Self::B2 => Ok(Some(format!("https://s3.{region}.backblazeb2.com"))),
+            Self::Minio => anyhow::bail!("--provider minio needs AWS_ENDPOINT_URL of the server"),
+            Self::R2 => anyhow::bail!(
+                "--provider r2 needs AWS_ENDPOINT_URL=https://<account id>.r2.cloudflarestorage.com"
+            ),
+        }
+    }
+
+    fn payload_signing(self) -> PayloadSigning {
+        match self {
+            Self::Aws => PayloadSigning::Signed,
+            // many S3-compatible services reject aws-chunked encoding and checksum trailers
+            _ => PayloadSigning::Unsigned,
+        }
+    }
+}
+
+/// Provider from --provider, set once before any config is loaded
+static PROVIDER: std::sync::OnceLock<Provider> = std::sync::OnceLock::new();
+
+fn provider() -> Provider {
+    PROVIDER.get().copied().unwrap_or(Provider::Aws)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum PayloadSigning {
+    /// The SDK's default, signs the SHA-256 of the body
+    Signed,
+    /// `UNSIGNED-PAYLOAD` without any aws-chunked encoding, for backends that do not support it
+    Unsigned,
+    /// aws-chunked encoding with the checksum in a trailer
+    Streaming,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ChecksumAlgorithm {
+    Crc32,
+    Crc32c,
+    Crc64nvme,
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Crc32 => "crc32",
+            Self::Crc32c => "crc32c",
+            Self::Crc64nvme => "crc64nvme",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    fn sdk(self) -> s3::types::ChecksumAlgorithm {
+        match self {
+            Self::Crc32 => s3::types::ChecksumAlgorithm::Crc32,
+            Self::Crc32c => s3::types::ChecksumAlgorithm::Crc32C,
+            Self::Crc64nvme => s3::types::ChecksumAlgorithm::Crc64Nvme,
+            Self::Sha1 => s3::types::ChecksumAlgorithm::Sha1,
+            Self::Sha256 => s3::types::ChecksumAlgorithm::Sha256,
+        }
+    }
+
+    fn hasher(self) -> Box<dyn aws_smithy_checksums::http::HttpChecksum> {
+        use aws_smithy_checksums::ChecksumAlgorithm as Algorithm;
+
+        match self {
+            Self::Crc32 => Algorithm::Crc32,
+            Self::Crc32c => Algorithm::Crc32c,
+            Self::Crc64nvme => Algorithm::Crc64Nvme,
+            Self::Sha1 => Algorithm::Sha1,
+            Self::Sha256 => Algorithm::Sha256,
+        }
+        .into_impl()
+    }
+
+    /// Base64 checksum of the data, as S3 expects it in requests
+    fn digest(self, data: &[u8]) -> String {
+        use base64::Engine;
+
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        base64::engine::general_purpose::STANDARD.encode(hasher.finalize())
+    }
+
+    /// Returns the checksum of this algorithm from a response, as `algorithm:base64`
+    fn of(self, checksums: [&Option<String>; 5]) -> Option<String> {
+        let index = self as usize;
+        let value = checksums[index].as_ref()?;
+        Some(format!("{}:{value}", self.name()))
+    }
+}
+
+/// Sets a checksum of `Option<(ChecksumAlgorithm, String)>` on a request or part builder
+macro_rules! with_checksum {
+    ($builder:expr, $checksum:expr) => {
+        match $checksum {
+            None => $builder,
+            Some((ChecksumAlgorithm::Crc32, x)) => $builder.checksum_crc32(x),
+            Some((ChecksumAlgorithm::Crc32c, x)) => $builder.checksum_crc32_c(x),
+            Some((ChecksumAlgorithm::Crc64nvme, x)) => $builder.checksum_crc64_nvme(x),
+            Some((ChecksumAlgorithm::Sha1, x)) => $builder.checksum_sha1(x),
+            Some((ChecksumAlgorithm::Sha256, x)) => $builder.checksum_sha256(x),
+        }
+    };
+}
+
+/// Checksums of a response, in the order of `ChecksumAlgorithm`
+macro_rules! checksums {
+    ($x:expr) => {
+        [
+            &$x.checksum_crc32,
+            &$x.checksum_crc32_c,
+            &$x.checksum_crc64_nvme,
+            &$x.checksum_sha1,
+            &$x.checksum_sha256,
+        ]
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ChecksumType {
+    /// Checksum of the part checksums
+    Composite,
+    /// Checksum of the whole object
+    FullObject,
+}
+
+/// Checksum type of multipart uploads with --checksum-algorithm
+fn checksum_type(args: &Args) -> anyhow::Result<Option<s3::types::ChecksumType>> {
+    let Some(algorithm) = args.checksum_algorithm else {
+        return Ok(None);
+    };
+    let crc = matches!(
+        algorithm,
+        ChecksumAlgorithm::Crc32 | ChecksumAlgorithm::Crc32c | ChecksumAlgorithm::Crc64nvme
+    );
+    let checksum_type = match args.checksum_type {
+        Some(x) => x,
+        None if algorithm == ChecksumAlgorithm::Crc64nvme => ChecksumType::FullObject,
+        None => ChecksumType::Composite,
+    };
+    Ok(Some(match checksum_type {
+        ChecksumType::FullObject if !crc => anyhow::bail!(
+            "--checksum-type full-object needs a CRC algorithm, not {}",
+            algorithm.name()
+        ),
+        ChecksumType::FullObject => s3::types::ChecksumType::FullObject,
+        ChecksumType::Composite if algorithm == ChecksumAlgorithm::Crc64nvme => {
+            anyhow::bail!(
+                "--checksum-algorithm crc64nvme only supports --checksum-type full-object"
+            )
+        }
+        ChecksumType::Composite => s3::types::ChecksumType::Composite,
+    }))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SdkRetryMode {
+    /// No retries by the SDK
+    Off,
+    /// Exponential backoff on transient errors and throttling
+    Standard,
+    /// Standard retries, plus client-side rate limiting when throttled
+    Adaptive,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SignalAction {
+    /// Abort the multipart upload, removing all uploaded parts
+    Abort,
+    /// Keep the multipart upload and print its upload ID, so it can be completed or aborted later
+    Keep,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum FanoutFailure {
+    /// Abort the uploads to all destinations, so that either all or none get the object
+    AbortAll,
+    /// Abort only the upload to the failed destination and complete the others, e.g. for a best
+    /// effort backup
+    Continue,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+enum HttpVersion {
+    /// HTTP/1.1, with one request at a time on each connection
+    #[value(name = "1.1")]
+    Http1,
+    /// HTTP/2 only, negotiated with TLS or with prior knowledge over plain HTTP
+    #[value(name = "2")]
+    Http2,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum IoStrategy {
+    /// Read at most --read-ahead parts ahead, so each part is sent as soon as it is read and
+    /// little input is held in memory
+    Latency,
+    /// Read at least as many parts ahead as are in flight, so a finished part is replaced right
+    /// away even if the input is slow or bursty, at the cost of up to twice the memory
+    ///
+    /// Helps most on high-latency links, where many parts are in flight. Part sizes stay the
+    /// same, so ETags do not change.
+    Throughput,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum EmptyInput {
+    /// Upload a zero-byte object
+    Object,
+    /// Upload nothing and exit successfully
+    Skip,
+    /// Upload nothing and fail
+    Error,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ExistingUpload {
+    /// Start another upload without looking for existing ones
+    New,
+    /// Continue the most recent upload, keeping the parts whose ETag matches the input file
+    Resume,
+    /// Abort all existing uploads before starting a new one
+    AbortRestart,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// Nothing is printed to stdout, except for --print-presigned-url
+    Text,
+    /// The JSON printed by `aws s3api complete-multipart-upload`, an array of them for --manifest
+    AwsJson,
+    /// An array with a result per file and S3 path when s3put ends, including failed and skipped
+    /// ones
+    ///
+    /// Each result has the input `path` (null for stdin), `bucket`, `key`, `etag`, `version_id`,
+    /// `bytes`, `parts`, `retries`, the `duration` in seconds, the `status` 'uploaded', 'skipped'
+    /// or 'failed', and the `error` of a failure.
+    Json,
+}
+
+/// Result of uploading a file to an S3 path, for --output json
+#[derive(serde::Serialize)]
+struct FileResult {
+    path: Option<PathBuf>,
+    bucket: String,
+    key: String,
+    etag: Option<String>,
+    version_id: Option<String>,
+    bytes: usize,
+    parts: usize,
+    retries: u32,
+    duration: f64,
+    status: FileStatus,
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum FileStatus {
+    Uploaded,
+    Skipped,
+    Failed,
+}
+
+impl FileResult {
+    fn new(args: &Args, bucket: &str, key: &str, status: FileStatus) -> Self {
+        Self {
+            path: args.input.clone(),
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            etag: None,
+            version_id: None,
+            bytes: 0,
+            parts: 0,
+            retries: 0,
+            duration: 0.0,
+            status,
+            error: None,
+        }
+    }
+}
+
+/// Results of --output json, printed when s3put ends, `None` unless uploading
+static FILE_RESULTS: std::sync::Mutex<Option<Vec<FileResult>>> = std::sync::Mutex::new(None);
+
+fn record_result(args: &Args, result: FileResult) {
+    if args.output != OutputFormat::Json {
+        return;
+    }
+    if let Some(results) = FILE_RESULTS.lock().unwrap().as_mut() {
+        results.push(result);
+    }
+}
+
+/// Number of results recorded so far, for --output json
+fn recorded_results() -> usize {
+    FILE_RESULTS.lock().unwrap().as_ref().map_or(0, Vec::len)
+}
+
+/// Records a failed upload to a single destination, for --output json
+fn record_failure(
+    args: &Args,
+    bucket: &str,
+    key: &str,
+    start: std::time::Instant,
+    e: &anyhow::Error,
+) {
+    record_result(
+        args,
+        FileResult {
+            duration: start.elapsed().as_secs_f64(),
+            error: Some(e.to_string()),
+            ..FileResult::new(args, bucket, key, FileStatus::Failed)
+        },
+    );
+}
+
+/// Records a failed upload to all S3 paths that have no result yet, for --output json
+///
+/// Results from `recorded` on with the same input are the ones of this upload, others can be
+/// those of files of a manifest uploaded at the same time.
+fn record_failures(
+    args: &Args,
+    s3_paths: &[String],
+    recorded: usize,
+    start: std::time::Instant,
+    e: &anyhow::Error,
+) {
+    let results = FILE_RESULTS.lock().unwrap();
+    let new = results.as_ref().and_then(|x| x.get(recorded..));
+    if new.is_some_and(|x| x.iter().any(|x| x.path == args.input)) {
+        return;
+    }
+    drop(results);
+    for s3_path in s3_paths {
+        if let Ok((bucket, key)) = parse_s3_path(s3_path) {
+            record_failure(args, &bucket, &key, start, e);
+        }
+    }
+}
+
+fn record_skipped(args: &Args, bucket: &str, key: &str) {
+    record_result(
+        args,
+        FileResult::new(args, bucket, key, FileStatus::Skipped),
+    );
+}
+
+fn print_file_results() -> anyhow::Result<()> {
+    if let Some(results) = FILE_RESULTS.lock().unwrap().as_ref() {
+        println!("{}", pretty_json(results)?);
+    }
+    Ok(())
+}
+
+struct UploadResult {
+    etag: String,
+    bytes: usize,
+    /// URL of the object, as reported by the completion of a multipart upload
+    location: Option<String>,
+    /// Version of the object in a bucket with versioning
+    version_id: Option<String>,
+    /// Checksum of --checksum-algorithm, as `algorithm:base64`
+    checksum: Option<String>,
+    parts: usize,
+    retries: u32,
+}
+
+/// All parts of the input, uploaded to every destination
+struct UploadedParts {
+    bytes: usize,
+    num_parts: usize,
+    retries: u32,
+    /// Hex SHA-256 of the input, for --content-addressed and --write-checksum
+    digest: Option<String>,
+    /// Hex MD5 of the input, for an MD5 sidecar of --use-sidecar-checksum
+    md5: Option<String>,
+    /// Full-object checksum of the input, for --post-verify-checksum
+    checksum: Option<String>,
+    /// Completed parts of each destination
+    parts: Vec<Vec<CompletedPart>>,
+    /// Why each destination dropped out of the upload with --fanout-failure continue, if it did
+    dropped: Vec<Option<anyhow::Error>>,
+    /// Time from the first attempt to the completion of each part upload
+    part_durations: Vec<Duration>,
+    /// Reading stopped early because of a signal
+    interrupted: bool,
+}
+
+/// Grows the kernel buffer of a pipe connected to stdin
+///
+/// Reads from a pipe return at most what the pipe buffer holds (64KB by default), so filling a
+/// large part needs many small reads. A larger pipe buffer lets the producer run ahead and lets
+/// each read return more data.
+#[cfg(target_os = "linux")]
+fn grow_stdin_pipe() {
+    use std::os::fd::AsRawFd;
+
+    const PIPE_SIZE: libc::c_int = 1024 * 1024;
+    let fd = std::io::stdin().as_raw_fd();
+    // fails with EBADF if stdin is not a pipe, or EPERM if above the pipe-max-size limit
+    let result = unsafe { libc::fcntl(fd, libc::F_SETPIPE_SZ, PIPE_SIZE) };
+    if result < 0 {
+        let e = std::io::Error::last_os_error();
+        tracing::debug!("Could not grow stdin pipe buffer: {e}");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn grow_stdin_pipe() {}
+
+/// Reads a file, filling its holes with zeros instead of reading them from disk
+#[cfg(target_os = "linux")]
+struct SparseFile {
+    file: std::fs::File,
+    len: u64,
+    pos: u64,
+    /// End of the hole or data region `pos` is in
+    region_end: u64,
+    in_hole: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl SparseFile {
+    /// Returns the result of `lseek`, or `None` if there is no data after `offset`
+    fn lseek(&self, offset: u64, whence: libc::c_int) -> std::io::Result<Option<u64>> {
+        use std::os::fd::AsRawFd;
+
+        let result = unsafe { libc::lseek(self.file.as_raw_fd(), offset as libc::off_t, whence) };
+        if result < 0 {
+            let e = std::io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENXIO) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        Ok(Some(result as u64))
+    }
+
+    /// Finds the hole or data region starting at `pos`
+    fn next_region(&mut self) -> std::io::Result<()> {
+        use std::io::Seek;
+
+        let data = match self.lseek(self.pos, libc::SEEK_DATA) {
+            Ok(x) => x.unwrap_or(self.len).min(self.len),
+            Err(e) => {
+                tracing::debug!("Could not find holes in input file, reading it completely: {e}");
+                self.pos
+            }
+        };
+        if data > self.pos {
+            tracing::debug!(
+                "Skipping hole of {} at offset {}",
+                format_size((data - self.pos) as usize),
+                self.pos
+            );
+            self.in_hole = true;
+            self.region_end = data;
+        } else {
+            self.in_hole = false;
+            self.region_end = match self.lseek(self.pos, libc::SEEK_HOLE) {
+                Ok(x) => x.unwrap_or(self.len).min(self.len),
+                Err(_) => self.len,
+            };
+        }
+        self.file.seek(std::io::SeekFrom::Start(self.pos))?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl std::io::Read for SparseFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        if self.pos >= self.region_end {
+            self.next_region()?;
+        }
+        let len = (buf.len() as u64).min(self.region_end - self.pos) as usize;
+        let num_read = match self.in_hole {
+            true => {
+                buf[..len].fill(0);
+                len
+            }
+            false => self.file.read(&mut buf[..len])?,
+        };
+        self.pos += num_read as u64;
+        Ok(num_read)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sparse_file(file: std::fs::File, len: u64) -> Box<dyn std::io::Read + Send + Sync> {
+    Box::new(SparseFile {
+        file,
+        len,
+        pos: 0,
+        region_end: 0,
+        in_hole: false,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sparse_file(file: std::fs::File, _len: u64) -> Box<dyn std::io::Read + Send + Sync> {
+    tracing::warn!("--sparse is only supported on Linux, reading the input file completely");
+    Box::new(file)
+}
+
+/// How often a followed file is checked for new data
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Reads a file that is still being written, waiting for more data at its end
+struct FollowFile {
+    file: std::fs::File,
+    idle_timeout: Option<Duration>,
+    /// Set to stop waiting, the end of the file is then the end of the input
+    finish: Arc<AtomicBool>,
+    last_data: std::time::Instant,
+}
+
+impl std::io::Read for FollowFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            // check the flag before reading, so data written before the signal is not lost
+            let finish = self.finish.load(Ordering::Relaxed);
+            let num_read = self.file.read(buf)?;
+            if num_read > 0 || buf.is_empty() {
+                self.last_data = std::time::Instant::now();
+                return Ok(num_read);
+            }
+            if finish {
+                return Ok(0);
+            }
+            if let Some(timeout) = self.idle_timeout {
+                if self.last_data.elapsed() >= timeout {
+                    tracing::info!(
+                        "No new input for {}, finishing upload",
+                        format_duration(timeout)
+                    );
+                    return Ok(0);
+                }
+            }
+            std::thread::sleep(FOLLOW_INTERVAL);
+        }
+    }
+}
+
+/// Returns a flag that is set once SIGHUP is received, to finish a --follow upload
+fn watch_hangup() -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::hangup()) {
+            Err(e) => tracing::warn!("Failed to install SIGHUP handler: {e}"),
+            Ok(mut hangup) => {
+                let flag = flag.clone();
+                tokio::spawn(async move {
+                    if hangup.recv().await.is_some() {
+                        tracing::info!("Got SIGHUP, finishing upload at the end of the input");
+                        flag.store(true, Ordering::Relaxed);
+                    }
+                });
+            }
+        }
+    }
+    flag
+}
+
+/// Headers from --header, set once before any client is created
+static EXTRA_HEADERS: std::sync::OnceLock<Vec<(String, String)>> = std::sync::OnceLock::new();
+
+/// Headers set by the SDK, which --header would silently replace
+const SDK_HEADERS: [&str; 7] = [
+    "authorization",
+    "host",
+    "content-length",
+    "content-md5",
+    "x-amz-date",
+    "x-amz-content-sha256",
+    "x-amz-security-token",
+];
+
+/// Adds the --header headers to each request
+#[derive(Debug)]
+struct ExtraHeaders(&'static [(String, String)]);
+
+impl s3::config::Intercept for ExtraHeaders {
+    fn name(&self) -> &'static str {
+        "ExtraHeaders"
+    }
+
+    fn modify_before_signing(
+        &self,
+        context: &mut s3::config::interceptors::BeforeTransmitInterceptorContextMut<'_>,
+        _: &s3::config::RuntimeComponents,
+        _: &mut s3::config::ConfigBag,
+    ) -> Result<(), s3::error::BoxError> {
+        for (name, value) in self.0 {
+            context
+                .request_mut()
+                .headers_mut()
+                .insert(name.clone(), value.clone());
+        }
+        Ok(())
+    }
+}
+
+/// File of --api-log, set once before any client is created
+static API_LOG: std::sync::OnceLock<std::sync::Mutex<std::fs::File>> = std::sync::OnceLock::new();
+
+/// Bucket, key and part number of a request, for --api-log
+#[derive(Clone, Debug, Default)]
+struct ApiTarget {
+    bucket: Option<String>,
+    key: Option<String>,
+    part_number: Option<i32>,
+}
+
+impl aws_smithy_types::config_bag::Storable for ApiTarget {
+    type Storer = aws_smithy_types::config_bag::StoreReplace<Self>;
+}
+
+/// When the current attempt at a request was sent, for --api-log
+#[derive(Clone, Debug)]
+struct AttemptStart(std::time::Instant);
+
+impl aws_smithy_types::config_bag::Storable for AttemptStart {
+    type Storer = aws_smithy_types::config_bag::StoreReplace<Self>;
+}
+
+/// Returns the ApiTarget of inputs of these operations, which all have a bucket and the listed
+/// fields
+macro_rules! api_target {
+    ($input:ident: $($operation:ident::$type:ident { $($field:ident),* }),* $(,)?) => {$(
+        if let Some(x) = $input.downcast_ref::<s3::operation::$operation::$type>() {
+            #[allow(unused_mut)]
+            let mut target = ApiTarget {
+                bucket: x.bucket().map(str::to_string),
+                ..Default::default()
+            };
+            $(api_target!(@$field target x);)*
+            return target;
+        }
+    )*};
+    (@key $target:ident $x:ident) => {
+        $target.key = $x.key().map(str::to_string);
+    };
+    (@part_number $target:ident $x:ident) => {
+        $target.part_number = $x.part_number();
+    };
+}
+
+fn api_target(input: &aws_smithy_runtime_api::client::interceptors::context::Input) -> ApiTarget {
+    api_target!(input:
+        put_object::PutObjectInput { key },
+        create_multipart_upload::CreateMultipartUploadInput { key },
+        upload_part::UploadPartInput { key, part_number },
+        upload_part_copy::UploadPartCopyInput { key, part_number },
+        complete_multipart_upload::CompleteMultipartUploadInput { key },
+        abort_multipart_upload::AbortMultipartUploadInput { key },
+        list_parts::ListPartsInput { key },
+        head_object::HeadObjectInput { key, part_number },
+        get_object::GetObjectInput { key, part_number },
+        copy_object::CopyObjectInput { key },
+        delete_object::DeleteObjectInput { key },
+        head_bucket::HeadBucketInput {},
+        list_multipart_uploads::ListMultipartUploadsInput {},
+    );
+    ApiTarget::default()
+}
+
+/// Writes a line to the --api-log for each attempt at a request
+#[derive(Debug)]
+struct ApiLog(&'static std::sync::Mutex<std::fs::File>);
+
+impl s3::config::Intercept for ApiLog {
+    fn name(&self) -> &'static str {
+        "ApiLog"
+    }
+
+    fn read_before_execution(
+        &self,
+        context: &s3::config::interceptors::BeforeSerializationInterceptorContextRef<'_>,
+        cfg: &mut s3::config::ConfigBag,
+    ) -> Result<(), s3::error::BoxError> {
+        cfg.interceptor_state()
+            .store_put(api_target(context.input()));
+        Ok(())
+    }
+
+    fn read_before_transmit(
+        &self,
+        _: &s3::config::interceptors::BeforeTransmitInterceptorContextRef<'_>,
+        _: &s3::config::RuntimeComponents,
+        cfg: &mut s3::config::ConfigBag,
+    ) -> Result<(), s3::error::BoxError> {
+        cfg.interceptor_state()
+            .store_put(AttemptStart(std::time::Instant::now()));
+        Ok(())
+    }
+
+    fn read_after_attempt(
+        &self,
+        context: &s3::config::interceptors::FinalizerInterceptorContextRef<'_>,
+        _: &s3::config::RuntimeComponents,
+        cfg: &mut s3::config::ConfigBag,
+    ) -> Result<(), s3::error::BoxError> {
+        use aws_smithy_runtime_api::client::orchestrator::Metadata;
+        use aws_smithy_runtime_api::client::retries::RequestAttempts;
+        use std::io::Write;
+
+        let target = cfg.load::<ApiTarget>().cloned().unwrap_or_default();
+        let response = context.response();
+        let header = |name| response.and_then(|x| x.headers().get(name));
+        let error = match (response, context.output_or_error()) {
+            (None, Some(Err(e))) => {
+                Some(aws_smithy_types::error::display::DisplayErrorContext(e).to_string())
+            }
+            _ => None,
+        };
+        let line = serde_json::json!({
+            "time": s3::primitives::DateTime::from(std::time::SystemTime::now())
+                .fmt(s3::primitives::DateTimeFormat::DateTime)
+                .ok(),
+            "operation": cfg.load::<Metadata>().map(|x| x.name()),
+            "bucket": target.bucket,
+            "key": target.key,
+            "part_number": target.part_number,
+            "attempt": cfg.load::<RequestAttempts>().map(|x| x.attempts()),
+            "status": response.map(|x| x.status().as_u16()),
+            "request_id": header("x-amz-request-id"),
+            "extended_request_id": header("x-amz-id-2"),
+            "duration": cfg
+                .load::<AttemptStart>()
+                .map(|x| x.0.elapsed().as_secs_f64()),
+            "error": error,
+        });
+        let mut file = self.0.lock().unwrap();
+        if let Err(e) = writeln!(file, "{line}") {
+            tracing::warn!("Failed to write to --api-log: {e}");
+        }
+        Ok(())
+    }
+}
+
+fn s3_client(config: &aws_config::SdkConfig) -> s3::Client {
+    let mut builder = s3::config::Builder::from(config);
+    if let Some(headers) = EXTRA_HEADERS.get().filter(|x| !x.is_empty()) {
+        builder = builder.interceptor(ExtraHeaders(headers));
+    }
+    if let Some(file) = API_LOG.get() {
+        builder = builder.interceptor(ApiLog(file));
+    }
+    if provider() == Provider::Minio {
+        builder = builder.force_path_style(true);
+    }
+    s3::Client::from_conf(builder.build())
+}
+
+/// Limit from --max-connections, set once before the first upload
+static CONNECTIONS: std::sync::OnceLock<tokio::sync::Semaphore> = std::sync::OnceLock::new();
+
+/// SDK retries of parts and single PUTs from --sdk-retry-mode, set once before the first upload
+static SDK_RETRIES: std::sync::OnceLock<s3::config::retry::RetryConfig> =
+    std::sync::OnceLock::new();
+
+/// Config to override for the requests s3put retries itself
+fn retry_override() -> s3::config::Builder {
+    let retry_config = SDK_RETRIES.get().cloned();
+    s3::config::Builder::new()
+        .retry_config(retry_config.unwrap_or_else(s3::config::retry::RetryConfig::disabled))
+}
+
+/// Slot of --max-connections and lease of --global-concurrency-file, both freed on drop
+struct Connection {
+    _permit: Option<tokio::sync::SemaphorePermit<'static>>,
+    _lease: Option<Lease>,
+}
+
+/// Parts in flight shared by all files of a --manifest or --recursive, set once before the first
+static PART_POOL: std::sync::OnceLock<tokio::sync::Semaphore> = std::sync::OnceLock::new();
+
+/// Completions of --max-completions, set once before the first file of a --manifest or --recursive
+static COMPLETIONS: std::sync::OnceLock<tokio::sync::Semaphore> = std::sync::OnceLock::new();
+
+/// Waits for a free connection if --max-connections or --global-concurrency-file is set
+async fn connection() -> Connection {
+    // the semaphore is never closed
+    let permit = match CONNECTIONS.get() {
+        Some(semaphore) => semaphore.acquire().await.ok(),
+        None => None,
+    };
+    let lease = match GLOBAL_LEASES.get() {
+        Some(leases) => leases.acquire().await,
+        None => None,
+    };
+    Connection {
+        _permit: permit,
+        _lease: lease,
+    }
+}
+
+/// Lease file of --global-concurrency-file, set once before the first upload
+static GLOBAL_LEASES: std::sync::OnceLock<GlobalLeases> = std::sync::OnceLock::new();
+
+/// Leases not renewed for this long are left over from a crashed process
+const LEASE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval to check for a free lease while all are taken
+const LEASE_POLL: Duration = Duration::from_millis(100);
+
+/// Semaphore shared by processes through a file
+///
+/// The file lists a `pid id expiry` line for every lease taken, and is only read and written while
+/// holding an exclusive lock on it. Each process renews its leases while it runs.
+struct GlobalLeases {
+    /// The file lock only excludes other processes, the mutex the threads of this one
+    file: std::sync::Mutex<std::fs::File>,
+    limit: usize,
+    next_id: std::sync::atomic::AtomicU64,
+}
+
+struct GlobalLease {
+    pid: u32,
+    id: u64,
+    expiry: u64,
+}
+
+/// Lease taken from GLOBAL_LEASES, returned on drop
+struct Lease {
+    id: u64,
+}
+
+impl GlobalLeases {
+    fn open(path: &std::path::Path, limit: usize) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open {}: {e}", path.display()))?;
+        Ok(Self {
+            file: std::sync::Mutex::new(file),
+            limit,
+            next_id: Default::default(),
+        })
+    }
+
+    fn expiry() -> u64 {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+        now.unwrap_or_default().as_secs() + LEASE_TIMEOUT.as_secs()
+    }
+
+    /// Changes the unexpired leases in the file while holding its lock
+    fn update<T>(&self, f: impl FnOnce(&mut Vec<GlobalLease>) -> T) -> std::io::Result<T> {
+        use std::io::{Seek, Write};
+        let file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.lock()?;
+        let result = (|| {
+            let mut file = &*file;
+            let mut content = String::new();
+            file.seek(std::io::SeekFrom::Start(0))?;
+            file.read_to_string(&mut content)?;
+            let now = Self::expiry() - LEASE_TIMEOUT.as_secs();
+            let mut leases: Vec<_> = (content.lines())
+                .filter_map(|line| {
+                    let mut fields = line.split(' ').map(str::parse::<u64>);
+                    let (Some(Ok(pid)), Some(Ok(id)), Some(Ok(expiry))) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        return None;
+                    };
+                    let pid = pid as u32;
+                    (expiry > now).then_some(GlobalLease { pid, id, expiry })
+                })
+                .collect();
+            let result = f(&mut leases);
+            let content: String = (leases.iter())
+                .map(|x| format!("{} {} {}\n", x.pid, x.id, x.expiry))
+                .collect();
+            file.set_len(0)?;
+            file.seek(std::io::SeekFrom::Start(0))?;
+            file.write_all(content.as_bytes())?;
+            Ok(result)
+        })();
+        file.unlock()?;
+        result
+    }
+
+    fn try_acquire(&self) -> std::io::Result<Option<Lease>> {
+        self.update(|leases| {
+            if leases.len() >= self.limit {
+                return None;
+            }
+            let pid = std::process::id();
+            let id = (self.next_id).fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let expiry = Self::expiry();
+            leases.push(GlobalLease { pid, id, expiry });
+            Some(Lease { id })
+        })
+    }
+
+    /// Waits for a free lease, uploads without one if the file cannot be used
+    async fn acquire(&'static self) -> Option<Lease> {
+        loop {
+            match tokio::task::spawn_blocking(|| self.try_acquire()).await {
+                Ok(Ok(Some(lease))) => return Some(lease),
+                Ok(Ok(None)) => tokio::time::sleep(LEASE_POLL).await,
+                Ok(Err(e)) => {
+                    tracing::warn!("Failed to take a lease from --global-concurrency-file: {e}");
+                    return None;
+                }
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Extends the expiry of the leases of this process
+    fn renew(&self) -> std::io::Result<()> {
+        let pid = std::process::id();
+        self.update(|leases| {
+            for lease in leases.iter_mut().filter(|x| x.pid == pid) {
+                lease.expiry = Self::expiry();
+            }
+        })
+    }
+
+    /// Removes the leases of this process with the given ID, or all of them
+    fn release(&self, id: Option<u64>) {
+        let pid = std::process::id();
+        let released =
+            self.update(|x| x.retain(|x| x.pid != pid || id.is_some_and(|id| x.id != id)));
+        if let Err(e) = released {
+            tracing::warn!("Failed to return a lease to --global-concurrency-file: {e}");
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let Some(leases) = GLOBAL_LEASES.get() else {
+            return;
+        };
+        let id = self.id;
+        // main() removes the leases still held at exit, in case the runtime is gone by then
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(move || leases.release(Some(id)))),
+            Err(_) => leases.release(Some(id)),
+        }
+    }
+}
+
+/// Point in time and duration of --deadline, set once before the first upload
+static DEADLINE: std::sync::OnceLock<(tokio::time::Instant, Duration)> = std::sync::OnceLock::new();
+
+/// Exit code when --deadline passes, the same as `timeout` uses
+const DEADLINE_EXIT_CODE: i32 = 124;
+
+#[derive(Debug)]
+struct DeadlineExceeded(Duration);
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Deadline of {}s exceeded", self.0.as_secs_f64())
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// The upload rate stayed below --min-throughput for --min-throughput-window
+#[derive(Debug)]
+struct ThroughputTooLow {
+    /// Bytes per second over the window
+    rate: f64,
+    minimum: usize,
+    window: Duration,
+}
+
+impl std::fmt::Display for ThroughputTooLow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Throughput below minimum: {}/s over the last {}, expected at least {}/s",
+            format_size(self.rate as usize),
+            format_duration(self.window),
+            format_size(self.minimum)
+        )
+    }
+}
+
+impl std::error::Error for ThroughputTooLow {}
+
+/// The upload stopped on SIGINT or SIGTERM
+#[derive(Debug)]
+struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Fails once --deadline has passed
+fn check_deadline() -> Result<(), DeadlineExceeded> {
+    match DEADLINE.get() {
+        Some((deadline, duration)) if tokio::time::Instant::now() >= *deadline => {
+            Err(DeadlineExceeded(*duration))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Runs the future until it finishes or --deadline passes, dropping it in the latter case
+///
+/// This only works at await points, code that blocks the thread has to check the deadline itself.
+async fn before_deadline<T>(
+    future: impl std::future::Future<Output = T>,
+) -> Result<T, DeadlineExceeded> {
+    match DEADLINE.get() {
+        None => Ok(future.await),
+        Some((deadline, duration)) => tokio::time::timeout_at(*deadline, future)
+            .await
+            .map_err(|_| DeadlineExceeded(*duration)),
+    }
+}
+
+/// Directory buckets of S3 Express One Zone are named `bucket--azid--x-s3`
+fn is_directory_bucket(bucket: &str) -> bool {
+    bucket.ends_with("--x-s3")
+}
+
+/// Where credentials come from, see --credential-source
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CredentialSource {
+    /// AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN
+    Env,
+    /// Keys, roles or processes of the --profile in `~/.aws/config` and `~/.aws/credentials`
+    Profile,
+    /// The EC2 instance metadata service
+    Imds,
+    /// The ECS or EKS Pod Identity container endpoint
+    Ecs,
+    /// The SSO session of the --profile, as set up by `aws configure sso`
+    Sso,
+    /// AWS_WEB_IDENTITY_TOKEN_FILE and AWS_ROLE_ARN, e.g. of an EKS service account
+    WebIdentity,
+}
+
+impl CredentialSource {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Env => "env",
+            Self::Profile => "profile",
+            Self::Imds => "imds",
+            Self::Ecs => "ecs",
+            Self::Sso => "sso",
+            Self::WebIdentity => "web-identity",
+        }
+    }
+
+    fn provider(self, profile: Option<&str>) -> s3::config::SharedCredentialsProvider {
+        use aws_config::{ecs, environment, imds, profile, web_identity_token};
+        use s3::config::SharedCredentialsProvider;
+
+        match self {
+            Self::Env => SharedCredentialsProvider::new(
+                environment::EnvironmentVariableCredentialsProvider::new(),
+            ),
+            Self::Profile | Self::Sso => {
+                let mut builder = profile::ProfileFileCredentialsProvider::builder();
+                if let Some(x) = profile {
+                    builder = builder.profile_name(x);
+                }
+                SharedCredentialsProvider::new(builder.build())
+            }
+            Self::Imds => SharedCredentialsProvider::new(
+                imds::credentials::ImdsCredentialsProvider::builder().build(),
+            ),
+            Self::Ecs => {
+                SharedCredentialsProvider::new(ecs::EcsCredentialsProvider::builder().build())
+            }
+            Self::WebIdentity => SharedCredentialsProvider::new(
+                web_identity_token::WebIdentityTokenCredentialsProvider::builder().build(),
+            ),
+        }
+    }
+}
+
+/// Source from --credential-source, set once before any config is loaded
+static CREDENTIAL_SOURCE: std::sync::OnceLock<Option<CredentialSource>> =
+    std::sync::OnceLock::new();
+
+/// Checks that the profile has SSO settings in the AWS config file, for --credential-source sso
+///
+/// Otherwise the profile provider would take whatever else the profile has.
+fn check_sso_profile(profile: Option<&str>) -> anyhow::Result<()> {
+    let profile = match profile {
+        Some(x) => x.to_string(),
+        None => std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".into()),
+    };
+    let path = match std::env::var_os("AWS_CONFIG_FILE") {
+        Some(x) => PathBuf::from(x),
+        None => match std::env::var_os("HOME") {
+            Some(x) => PathBuf::from(x).join(".aws/config"),
+            None => anyhow::bail!("Cannot find the AWS config file, HOME is not set"),
+        },
+    };
+    let content = match std::fs::read_to_string(&path) {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Failed to read AWS config file {}: {e}", path.display()),
+    };
+    let header = match profile.as_str() {
+        "default" => "[default]".to_string(),
+        x => format!("[profile {x}]"),
+    };
+    let mut lines = content.lines().map(str::trim);
+    let found = lines.by_ref().any(|x| x == header);
+    let has_sso = found
+        && lines.take_while(|x| !x.starts_with('[')).any(|x| {
+            let key = x.split('=').next().unwrap_or_default().trim();
+            key == "sso_start_url" || key == "sso_session"
+        });
+    if !has_sso {
+        anyhow::bail!(
+            "Profile '{profile}' in {} has no SSO settings, set it up with `aws configure sso`",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Credentials file and profile from --credentials-file, set once before any config is loaded
+static CREDENTIALS_FILE: std::sync::OnceLock<Option<(PathBuf, String)>> =
+    std::sync::OnceLock::new();
+
+/// Checks that the --credentials-file can be read and has the profile
+fn check_credentials_file(path: &std::path::Path, profile: &str) -> anyhow::Result<()> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Failed to read credentials file {}: {e}", path.display()),
+    };
+    let header = format!("[{profile}]");
+    if !content.lines().any(|x| x.trim() == header) {
+        anyhow::bail!(
+            "Credentials file {} has no profile '{profile}'",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Session credentials from --mfa-serial, set once before any config is loaded
+static MFA_CREDENTIALS: std::sync::OnceLock<s3::config::Credentials> = std::sync::OnceLock::new();
+
+/// Returns the code for the MFA device, from --mfa-token or prompted for on the terminal
+fn mfa_token(args: &Args, serial: &str) -> anyhow::Result<String> {
+    use std::io::IsTerminal;
+
+    if let Some(token) = &args.mfa_token {
+        return Ok(token.clone());
+    }
+    // stdin is the input if there is no input file or URL
+    let stdin_input = args.input.is_none() && args.input_url.is_none();
+    if stdin_input || !std::io::stdin().is_terminal() {
+        anyhow::bail!("--mfa-serial needs --mfa-token if stdin is not a terminal");
+    }
+    log_write(format!("MFA code for {serial}: ").into_bytes());
+    flush_log();
+    let mut token = String::new();
+    std::io::stdin().read_line(&mut token)?;
+    Ok(token.trim().to_string())
+}
+
+/// Requests session credentials authenticated with the MFA device
+async fn mfa_session(
+    profile: Option<&str>,
+    serial: &str,
+    token: &str,
+) -> anyhow::Result<s3::config::Credentials> {
+    let mut config = config_loader(profile).load().await;
+    if config.region().is_none() {
+        config = with_region(config, "us-east-1".into());
+    }
+    let response = match aws_sdk_sts::Client::new(&config)
+        .get_session_token()
+        .serial_number(serial)
+        .token_code(token)
+        .send()
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Failed to get an MFA session for {serial}: {e}"),
+    };
+    let Some(credentials) = response.credentials else {
+        anyhow::bail!("Failed to get an MFA session for {serial}: no credentials returned");
+    };
+    Ok(s3::config::Credentials::new(
+        credentials.access_key_id,
+        credentials.secret_access_key,
+        Some(credentials.session_token),
+        std::time::SystemTime::try_from(credentials.expiration).ok(),
+        "mfa-session",
+    ))
+}
+
+/// HTTP client with --tcp-keepalive, --http-version and --http2-initial-window, set once before
+/// any config is loaded if one of them is given
+static HTTP_CLIENT: std::sync::OnceLock<s3::config::SharedHttpClient> = std::sync::OnceLock::new();
+
+/// Builds the client of HTTP_CLIENT, on hyper like the default client of the SDK
+fn http_client(args: &Args) -> anyhow::Result<s3::config::SharedHttpClient> {
+    let version = args.http_version.unwrap_or(HttpVersion::Http1);
+    if args.http2_initial_window.is_some() && version != HttpVersion::Http2 {
+        anyhow::bail!("--http2-initial-window needs --http-version 2");
+    }
+    let window = match args.http2_initial_window.map(u32::try_from) {
+        None => None,
+        Some(Ok(x)) if x <= i32::MAX as u32 => Some(x),
+        Some(_) => anyhow::bail!("--http2-initial-window has to be less than 2GB"),
+    };
+
+    let mut roots = rustls::RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            for cert in certs {
+                let _ = roots.add(&rustls::Certificate(cert.0));
+            }
+        }
+        Err(e) => anyhow::bail!("Failed to load CA certificates: {e}"),
+    }
+    if roots.is_empty() {
+        anyhow::bail!("No CA certificates found");
+    }
+    let tls = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let mut http = hyper::client::HttpConnector::new();
+    http.enforce_http(false);
+    http.set_nodelay(true);
+    http.set_keepalive(args.tcp_keepalive);
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http();
+    let mut builder = hyper::Client::builder();
+    let https = match version {
+        HttpVersion::Http1 => https.enable_http1().wrap_connector(http),
+        HttpVersion::Http2 => {
+            builder.http2_only(true);
+            if let Some(window) = window {
+                builder.http2_initial_stream_window_size(window);
+                // the connection holds the windows of all requests on it
+                builder.http2_initial_connection_window_size(window.saturating_mul(4));
+            }
+            https.enable_http2().wrap_connector(http)
+        }
+    };
+    tracing::debug!(
+        ?version,
+        tcp_keepalive = ?args.tcp_keepalive,
+        http2_initial_window = window,
+        "Using custom HTTP settings"
+    );
+    Ok(aws_smithy_http_client::hyper_014::HyperClientBuilder::new()
+        .hyper_builder(builder)
+        .build(https))
+}
+
+/// Loader for the profile, reading credentials from the --credentials-file if given
+fn config_loader(profile: Option<&str>) -> aws_config::ConfigLoader {
+    use aws_runtime::env_config::file::{EnvConfigFileKind, EnvConfigFiles};
+
+    let mut loader = aws_config::from_env();
+    if let Some(client) = HTTP_CLIENT.get() {
+        loader = loader.http_client(client.clone());
+    }
+    if let Some(profile) = profile {
+        loader = loader.profile_name(profile);
+    }
+    if let Some((path, profile)) = CREDENTIALS_FILE.get().and_then(Option::as_ref) {
+        let files = EnvConfigFiles::builder()
+            .with_file(EnvConfigFileKind::Credentials, path)
+            .build();
+        let provider = aws_config::profile::ProfileFileCredentialsProvider::builder()
+            .profile_files(files)
+            .profile_name(profile)
+            .build();
+        loader = loader.credentials_provider(provider);
+    }
+    if let Some(source) = CREDENTIAL_SOURCE.get().copied().flatten() {
+        loader = loader.credentials_provider(source.provider(profile));
+    }
+    loader
+}
+
+async fn load_config(bucket: &str, profile: Option<&str>) -> anyhow::Result<aws_config::SdkConfig> {
+    let mut loader = config_loader(profile);
+    if let Some(credentials) = MFA_CREDENTIALS.get() {
+        loader = loader.credentials_provider(credentials.clone());
+    }
+    let config = loader.load().await;
+    let region = config.region().cloned();
+    // directory buckets do not redirect to their region, so it has to be known up front
+    if is_directory_bucket(bucket) && region.is_none() {
+        anyhow::bail!(
+            "Directory bucket '{bucket}' needs a configured region, \
+            set AWS_REGION to the region of its availability zone"
+        );
+    }
+    let region = match (region, provider().default_region()) {
+        (Some(x), _) => x,
+        (None, Some(x)) => s3::config::Region::new(x),
+        (None, None) => anyhow::bail!("This --provider needs AWS_REGION to be set"),
+    };
+    let endpoint = match config.endpoint_url() {
+        Some(_) => None,
+        None => provider().endpoint(region.as_ref())?,
+    };
+    let mut builder = config.into_builder().region(region);
+    if let Some(x) = endpoint {
+        builder = builder.endpoint_url(x);
+    }
+    let config = resolve_region(bucket, profile, builder.build()).await;
+    check_region(bucket, config.region().map(|x| x.as_ref()))?;
+    Ok(config)
+}
+
+/// Makes sure credentials can be resolved, so a misconfigured destination fails before any data
+/// is transferred
+async fn check_credentials(config: &aws_config::SdkConfig, bucket: &str) -> anyhow::Result<()> {
+    use s3::config::ProvideCredentials;
+
+    match config.credentials_provider() {
+        None => anyhow::bail!("No credentials configured for bucket '{bucket}'"),
+        Some(provider) => {
+            if let Err(e) = provider.provide_credentials().await {
+                if let Some(source) = CREDENTIAL_SOURCE.get().copied().flatten() {
+                    anyhow::bail!(
+                        "No credentials from --credential-source {} for bucket '{bucket}': {e}",
+                        source.name()
+                    );
+                }
+                anyhow::bail!("Failed to resolve credentials for bucket '{bucket}': {e}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the region to retry a request in, if it was rejected because the bucket lives in a
+/// different region
+fn redirected_region<E>(bucket: &str, e: &s3::error::SdkError<E>) -> Option<String> {
+    match e {
+        s3::error::SdkError::ServiceError(response)
+            if response.raw().status().as_u16() == StatusCode::MOVED_PERMANENTLY =>
+        {
+            error_region(bucket, e)
+        }
+        _ => None,
+    }
+}
+
+/// Region of the bucket named by an error response
+///
+/// S3 sends it with redirects, and for HeadBucket also when access to the bucket is denied.
+fn error_region<E>(bucket: &str, e: &s3::error::SdkError<E>) -> Option<String> {
+    if is_directory_bucket(bucket) || provider() != Provider::Aws {
+        return None;
+    }
+    let s3::error::SdkError::ServiceError(response) = e else {
+        return None;
+    };
+    let region = response.raw().headers().get("x-amz-bucket-region")?;
+    Some(region.to_string())
+}
+
+fn with_region(config: aws_config::SdkConfig, region: String) -> aws_config::SdkConfig {
+    config
+        .into_builder()
+        .region(Some(s3::config::Region::new(region)))
+        .build()
+}
+
+/// Profile, endpoint and bucket a region was looked up for
+type RegionKey = (Option<String>, Option<String>, String);
+
+/// Regions of the buckets looked up so far, so batch uploads look up each bucket once
+///
+/// `None` if the lookup failed, which is not retried with the same profile either.
+static BUCKET_REGIONS: std::sync::Mutex<std::collections::BTreeMap<RegionKey, Option<String>>> =
+    std::sync::Mutex::new(std::collections::BTreeMap::new());
+
+/// Looks up the region of the bucket before the first upload, for services that do not redirect
+/// `create_multipart_upload` to it
+///
+/// Failures are not fatal, e.g. credentials that may upload but not list the bucket.
+async fn resolve_region(
+    bucket: &str,
+    profile: Option<&str>,
+    config: aws_config::SdkConfig,
+) -> aws_config::SdkConfig {
+    if is_directory_bucket(bucket) || provider() != Provider::Aws {
+        return config;
+    }
+    let key = (
+        profile.map(str::to_string),
+        config.endpoint_url().map(str::to_string),
+        bucket.to_string(),
+    );
+    let cached = BUCKET_REGIONS.lock().unwrap().get(&key).cloned();
+    let region = match cached {
+        Some(x) => x,
+        None => {
+            let region = match s3_client(&config).head_bucket().bucket(bucket).send().await {
+                Ok(response) => response.bucket_region,
+                Err(e) => {
+                    // e.g. without s3:ListBucket, later requests would go to the wrong region
+                    let region = error_region(bucket, &e);
+                    if region.is_none() {
+                        tracing::debug!(bucket, "Could not look up bucket region: {e:?}");
+                    }
+                    region
+                }
+            };
+            if let Some(x) = region
+                .as_deref()
+                .filter(|x| config.region().map(|x| x.as_ref()) != Some(*x))
+            {
+                tracing::info!(bucket, region = x, "Bucket is in {x}");
+            }
+            BUCKET_REGIONS.lock().unwrap().insert(key, region.clone());
+            region
+        }
+    };
+    match region {
+        Some(x) if config.region().map(|x| x.as_ref()) != Some(x.as_str()) => {
+            with_region(config, x)
+        }
+        _ => config,
+    }
+}
+
+/// Region of --expect-region, set once before the first upload
+static EXPECTED_REGION: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+/// Fails if the bucket is not in the region of --expect-region
+fn check_region(bucket: &str, region: Option<&str>) -> anyhow::Result<()> {
+    match EXPECTED_REGION.get() {
+        Some(expected) if region != Some(expected.as_str()) => anyhow::bail!(
+            "Bucket '{bucket}' is in {}, but --expect-region is {expected}",
+            region.unwrap_or("an unknown region")
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Records the region a request was redirected to, for all profiles using the same endpoint
+fn remember_region(bucket: &str, config: &aws_config::SdkConfig, region: &str) {
+    let endpoint = config.endpoint_url();
+    let mut regions = BUCKET_REGIONS.lock().unwrap();
+    for ((_, e, b), cached) in regions.iter_mut() {
+        if b == bucket && e.as_deref() == endpoint {
+            *cached = Some(region.to_string());
+        }
+    }
+}
+
+async fn start_upload(
+    bucket: &str,
+    key: &str,
+    mut config: aws_config::SdkConfig,
+    sse_customer_key: Option<&SseCustomerKey>,
+    options: &ObjectOptions,
+) -> anyhow::Result<(aws_config::SdkConfig, String)> {
+    for _ in 0..3 {
+        let client = s3_client(&config);
+        let response = match client
+            .create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .with_sse_c(sse_customer_key)
+            .with_object_options(options)
+            .set_checksum_algorithm(options.checksum_algorithm.map(ChecksumAlgorithm::sdk))
+            .set_checksum_type(options.checksum_type.clone())
+            .send()
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                tracing::debug!(bucket, key, "Request failed: {e:?}");
+                if let Some(x) = redirected_region(bucket, &e) {
+                    tracing::info!(bucket, region = x, "Redirected to {x}");
+                    check_region(bucket, Some(&x))?;
+                    remember_region(bucket, &config, &x);
+                    config = with_region(config, x);
+                    continue;
+                }
+                if is_acl_not_supported(&e) {
+                    return Err(acl_not_supported(bucket));
+                }
+                if is_access_denied(&e) && MFA_CREDENTIALS.get().is_none() {
+                    anyhow::bail!(
+                        "Access denied to start upload to bucket '{bucket}', if its policy \
+                        requires MFA pass --mfa-serial: {e}{}",
+                        request_ids(&e)
+                    );
+                }
+                if options.uses_object_lock() && is_invalid_request(&e) {
+                    anyhow::bail!(
+                        "Failed to start upload with object lock settings, the bucket '{bucket}' \
+                        needs Object Lock enabled: {e}{}",
+                        request_ids(&e)
+                    );
+                }
+                if is_not_implemented(&e) {
+                    return Err(MultipartUnsupported(bucket.to_string()).into());
+                }
+                if is_directory_bucket(bucket) {
+                    anyhow::bail!(
+                        "Failed to start upload to directory bucket '{bucket}', \
+                        check that AWS_REGION matches the region of its availability zone: {e}{}",
+                        request_ids(&e)
+                    );
+                }
+                anyhow::bail!("{e}{}", request_ids(&e));
+            }
+        };
+        let upload_id = match response.upload_id {
+            None => anyhow::bail!("Could not get upload_id"),
+            Some(x) => x,
+        };
+        tracing::debug!(bucket, key, upload_id, "Starting upload");
+        return Ok((config, upload_id));
+    }
+    anyhow::bail!("Stopped following redirects after 3 hops")
+}
+
+/// Algorithm of customer-provided encryption keys, the only one S3 supports
+const SSE_C_ALGORITHM: &str = "AES256";
+
+/// A customer-provided encryption key, as sent with each request
+#[derive(Clone)]
+struct SseCustomerKey {
+    /// Base64 of the key
+    key: String,
+    /// Base64 of the MD5 of the key
+    key_md5: String,
+}
+
+impl SseCustomerKey {
+    fn read(path: &std::path::Path) -> anyhow::Result<Self> {
+        use base64::Engine;
+        use md5::Md5;
+
+        let key = match std::fs::read(path) {
+            Ok(x) => x,
+            Err(e) => anyhow::bail!("Failed to read SSE-C key file: {e}"),
+        };
+        if key.len() != 32 {
+            anyhow::bail!(
+                "SSE-C key file has to contain a raw 256 bit key of 32 bytes, got {} bytes",
+                key.len()
+            );
+        }
+        let base64 = base64::engine::general_purpose::STANDARD;
+        Ok(Self {
+            key: base64.encode(&key),
+            key_md5: base64.encode(Md5::digest(&key)),
+        })
+    }
+}
+
+/// Request builders that take a customer-provided encryption key
+trait WithSseC {
+    fn with_sse_c(self, key: Option<&SseCustomerKey>) -> Self;
+}
+
+/// Request builders that create an object
+trait WithObjectOptions {
+    fn with_object_options(self, options: &ObjectOptions) -> Self;
+}
+
+macro_rules! impl_request_options {
+    ($($builder:ident in $operation:ident),* $(,)?) => {$(
+        impl WithSseC for s3::operation::$operation::builders::$builder {
+            fn with_sse_c(self, key: Option<&SseCustomerKey>) -> Self {
+                self.set_sse_customer_algorithm(key.map(|_| SSE_C_ALGORITHM.into()))
+                    .set_sse_customer_key(key.map(|x| x.key.clone()))
+                    .set_sse_customer_key_md5(key.map(|x| x.key_md5.clone()))
+            }
+        }
+    )*};
+    (creating $($builder:ident in $operation:ident),* $(,)?) => {
+        impl_request_options!($($builder in $operation),*);
+        $(
+            impl WithObjectOptions for s3::operation::$operation::builders::$builder {
+                fn with_object_options(self, options: &ObjectOptions) -> Self {
+                    self.set_acl(options.acl.clone())
+                        .set_storage_class(options.storage_class.clone())
+                        .set_object_lock_mode(options.object_lock_mode.clone())
+                        .set_object_lock_retain_until_date(options.object_lock_retain_until)
+                        .set_object_lock_legal_hold_status(options.object_lock_legal_hold.clone())
+                        .set_metadata(options.metadata.clone())
+                        .set_server_side_encryption(options.server_side_encryption.clone())
+                        .set_ssekms_key_id(options.ssekms_key_id.clone())
+                        .set_ssekms_encryption_context(options.ssekms_encryption_context.clone())
+                        .set_bucket_key_enabled(options.bucket_key_enabled)
+                }
+            }
+        )*
+    };
+}
+
+impl_request_options!(
+    creating CreateMultipartUploadFluentBuilder in create_multipart_upload,
+    PutObjectFluentBuilder in put_object,
+    CopyObjectFluentBuilder in copy_object,
+);
+impl_request_options!(
+    UploadPartFluentBuilder in upload_part,
+    UploadPartCopyFluentBuilder in upload_part_copy,
+    CompleteMultipartUploadFluentBuilder in complete_multipart_upload,
+    ListPartsFluentBuilder in list_parts,
+    GetObjectFluentBuilder in get_object,
+    HeadObjectFluentBuilder in head_object,
+);
+
+/// Settings of the uploaded object, given when it is created
+#[derive(Clone, Default)]
+struct ObjectOptions {
+    acl: Option<s3::types::ObjectCannedAcl>,
+    storage_class: Option<s3::types::StorageClass>,
+    object_lock_mode: Option<s3::types::ObjectLockMode>,
+    object_lock_retain_until: Option<s3::primitives::DateTime>,
+    object_lock_legal_hold: Option<s3::types::ObjectLockLegalHoldStatus>,
+    metadata: Option<std::collections::HashMap<String, String>>,
+    server_side_encryption: Option<s3::types::ServerSideEncryption>,
+    ssekms_key_id: Option<String>,
+    /// Base64 of the JSON encryption context
+    ssekms_encryption_context: Option<String>,
+    bucket_key_enabled: Option<bool>,
+    /// Base64 checksums of the content for a single PUT, which other requests do not take
+    checksum_sha256: Option<String>,
+    checksum_crc32: Option<String>,
+    content_md5: Option<String>,
+    /// Checksum of --use-sidecar-checksum, to check a multipart upload against
+    sidecar: Option<Sidecar>,
+    /// See --checksum-algorithm, not set on the copies of an upload
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    checksum_type: Option<s3::types::ChecksumType>,
+}
+
+/// Largest size of the user metadata of an object S3 accepts, counting keys and values
+const MAX_METADATA_SIZE: usize = 2 * 1024;
+
+impl ObjectOptions {
+    fn new(args: &Args) -> anyhow::Result<Self> {
+        let mut metadata = std::collections::HashMap::new();
+        metadata.extend(preserved_metadata(args)?);
+        if let Some(path) = &args.metadata_file {
+            metadata.extend(read_metadata_file(path)?);
+        }
+        metadata.extend(args.metadata.iter().cloned());
+        let size: usize = metadata.iter().map(|(k, v)| k.len() + v.len()).sum();
+        if size > MAX_METADATA_SIZE {
+            tracing::warn!(
+                "User metadata has {}, more than the {} S3 accepts",
+                format_size(size),
+                format_size(MAX_METADATA_SIZE)
+            );
+        }
+        let storage_class = match args.auto_storage_class {
+            true => auto_storage_class(args)?,
+            false => args.storage_class.clone(),
+        };
+        let sidecar = read_sidecar(args)?;
+        let sidecar_base64 = |algorithm| {
+            let sidecar = sidecar.as_ref().filter(|x| x.algorithm == algorithm);
+            sidecar.map(|x| x.base64.clone())
+        };
+        Ok(Self {
+            acl: args.acl.clone(),
+            storage_class,
+            object_lock_mode: args.object_lock_mode.clone(),
+            object_lock_retain_until: args.object_lock_retain_until,
+            object_lock_legal_hold: args
+                .legal_hold
+                .then_some(s3::types::ObjectLockLegalHoldStatus::On),
+            metadata: (!metadata.is_empty()).then_some(metadata),
+            server_side_encryption: args
+                .sse_kms
+                .then_some(s3::types::ServerSideEncryption::AwsKms),
+            ssekms_key_id: args.sse_kms_key_id.clone(),
+            ssekms_encryption_context: args.sse_kms_encryption_context.clone(),
+            bucket_key_enabled: args.bucket_key_enabled.then_some(true),
+            checksum_sha256: args
+                .content_sha256
+                .clone()
+                .or(sidecar_base64(SidecarAlgorithm::Sha256)),
+            checksum_crc32: args.content_crc32.clone(),
+            content_md5: sidecar_base64(SidecarAlgorithm::Md5),
+            sidecar,
+            checksum_algorithm: args.checksum_algorithm,
+            checksum_type: checksum_type(args)?,
+        })
+    }
+
+    fn uses_object_lock(&self) -> bool {
+        self.object_lock_mode.is_some() || self.object_lock_legal_hold.is_some()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SidecarAlgorithm {
+    Sha256,
+    Md5,
+}
+
+impl SidecarAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA-256",
+            Self::Md5 => "MD5",
+        }
+    }
+}
+
+/// Checksum read from a file next to the input, for --use-sidecar-checksum
+#[derive(Clone)]
+struct Sidecar {
+    path: PathBuf,
+    algorithm: SidecarAlgorithm,
+    hex: String,
+    base64: String,
+}
+
+/// Finds the checksum file next to the input, preferring SHA-256
+fn sidecar_path(input: &std::path::Path) -> Option<(PathBuf, SidecarAlgorithm)> {
+    for (extension, algorithm) in [
+        ("sha256", SidecarAlgorithm::Sha256),
+        ("md5", SidecarAlgorithm::Md5),
+    ] {
+        let mut path = input.as_os_str().to_owned();
+        path.push(".");
+        path.push(extension);
+        let path = PathBuf::from(path);
+        if path.is_file() {
+            return Some((path, algorithm));
+        }
+    }
+    None
+}
+
+fn read_sidecar(args: &Args) -> anyhow::Result<Option<Sidecar>> {
+    let Some(input) = args.input.as_ref().filter(|_| args.use_sidecar_checksum) else {
+        return Ok(None);
+    };
+    let Some((path, algorithm)) = sidecar_path(input) else {
+        let message = format!(
+            "No {0}.sha256 or {0}.md5 to check the input",
+            input.display()
+        );
+        if args.strict_sidecar {
+            anyhow::bail!("{message}");
+        }
+        tracing::warn!("{message}, uploading without");
+        return Ok(None);
+    };
+    let content = match std::fs::read_to_string(&path) {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Failed to read {}: {e}", path.display()),
+    };
+    let line = content.lines().next().unwrap_or_default();
+    // BSD format: `SHA256 (file) = digest`
+    let bsd_tag = match algorithm {
+        SidecarAlgorithm::Sha256 => "SHA256 (",
+        SidecarAlgorithm::Md5 => "MD5 (",
+    };
+    let hex = match line.rsplit_once(") = ") {
+        Some((tag, hex)) if tag.starts_with(bsd_tag) => hex,
+        Some(_) => anyhow::bail!("{} is not a {} checksum", path.display(), algorithm.name()),
+        None => line.split_whitespace().next().unwrap_or_default(),
+    };
+    let hex = hex.trim().to_ascii_lowercase();
+    let size = match algorithm {
+        SidecarAlgorithm::Sha256 => 32,
+        SidecarAlgorithm::Md5 => 16,
+    };
+    let base64 = match parse_hex_digest(&hex, size, algorithm.name()) {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Invalid checksum in {}: {e}", path.display()),
+    };
+    tracing::info!("Checking the input against {}", path.display());
+    Ok(Some(Sidecar {
+        path,
+        algorithm,
+        hex,
+        base64,
+    }))
+}
+
+/// Compares the hashes of an uploaded input with its sidecar, failing only with --strict-sidecar
+fn check_sidecar(args: &Args, sidecar: &Sidecar, uploaded: &UploadedParts) -> anyhow::Result<()> {
+    let computed = match sidecar.algorithm {
+        SidecarAlgorithm::Sha256 => uploaded.digest.as_deref(),
+        SidecarAlgorithm::Md5 => uploaded.md5.as_deref(),
+    };
+    if computed == Some(sidecar.hex.as_str()) {
+        tracing::info!(
+            "{} of the input matches {}",
+            sidecar.algorithm.name(),
+            sidecar.path.display()
+        );
+        return Ok(());
+    }
+    let message = format!(
+        "{} of the input is {}, but {} has {}",
+        sidecar.algorithm.name(),
+        computed.unwrap_or_default(),
+        sidecar.path.display(),
+        sidecar.hex
+    );
+    if args.strict_sidecar {
+        anyhow::bail!("{message}");
+    }
+    tracing::warn!("{message}");
+    Ok(())
+}
+
+/// Applies the rules of --auto-storage-class to the input
+fn auto_storage_class(args: &Args) -> anyhow::Result<Option<s3::types::StorageClass>> {
+    let rules = match args.storage_rule.is_empty() {
+        true => DEFAULT_STORAGE_RULES
+            .iter()
+            .map(|x| parse_storage_rule(x))
+            .collect::<anyhow::Result<_>>()?,
+        false => args.storage_rule.clone(),
+    };
+    let metadata = args.input.as_ref().and_then(|x| std::fs::metadata(x).ok());
+    let size = match &metadata {
+        Some(x) if x.is_file() => Some(x.len()),
+        _ => args.expected_size.map(|x| x as u64),
+    };
+    let age = metadata
+        .and_then(|x| x.modified().ok())
+        .and_then(|x| x.elapsed().ok());
+    let input = match &args.input {
+        Some(x) => x.display().to_string(),
+        None => args.input_url.clone().unwrap_or("stdin".to_string()),
+    };
+    for rule in rules {
+        let matches = match rule.condition {
+            StorageCondition::SizeAbove(x) => size.is_some_and(|size| size > x),
+            StorageCondition::SizeBelow(x) => size.is_some_and(|size| size < x),
+            StorageCondition::AgeAbove(x) => age.is_some_and(|age| age > x),
+            StorageCondition::AgeBelow(x) => age.is_some_and(|age| age < x),
+        };
+        if matches {
+            tracing::info!(
+                "Storage class {} for {input}, by rule '{}'",
+                rule.class,
+                rule.rule
+            );
+            return Ok(Some(rule.class));
+        }
+    }
+    tracing::info!("No storage rule matches {input}, using the default storage class");
+    Ok(None)
+}
+
+/// Returns the user metadata for --preserve-mtime and --preserve-mode
+fn preserved_metadata(args: &Args) -> anyhow::Result<Vec<(String, String)>> {
+    let Some(input) = args.input.as_ref().filter(|_| args.preserve_mtime) else {
+        return Ok(Vec::new());
+    };
+    let metadata = match std::fs::metadata(input) {
+        Ok(x) if x.is_file() => x,
+        Ok(_) => {
+            tracing::warn!(
+                "{} is not a regular file, not preserving its mtime",
+                input.display()
+            );
+            return Ok(Vec::new());
+        }
+        Err(e) => anyhow::bail!("Failed to read the mtime of {}: {e}", input.display()),
+    };
+    let mtime = match metadata
+        .modified()
+        .map(|x| x.duration_since(std::time::UNIX_EPOCH))
+    {
+        Ok(Ok(x)) => x.as_secs(),
+        Ok(Err(_)) => anyhow::bail!("The mtime of {} is before 1970", input.display()),
+        Err(e) => anyhow::bail!("Failed to read the mtime of {}: {e}", input.display()),
+    };
+    let mut entries = vec![("mtime".to_string(), mtime.to_string())];
+    if args.preserve_mode {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = metadata.permissions().mode() & 0o7777;
+            entries.push(("mode".to_string(), format!("{mode:o}")));
+        }
+        #[cfg(not(unix))]
+        tracing::warn!("--preserve-mode is only supported on Unix");
+    }
+    Ok(entries)
+}
+
+/// Reads metadata from a JSON object of strings, or from `KEY=VALUE` lines
+fn read_metadata_file(path: &std::path::Path) -> anyhow::Result<Vec<(String, String)>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Failed to read metadata file: {e}"),
+    };
+    if content.trim_start().starts_with('{') {
+        let object: serde_json::Map<String, serde_json::Value> =
+            match serde_json::from_str(&content) {
+                Ok(x) => x,
+                Err(e) => anyhow::bail!("Failed to parse metadata file: {e}"),
+            };
+        return object
+            .iter()
+            .map(|(key, value)| match value.as_str() {
+                Some(value) => check_metadata(key, value),
+                None => anyhow::bail!("Metadata value of '{key}' should be a string"),
+            })
+            .collect();
+    }
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(parse_metadata)
+        .collect()
+}
+
+/// Whether a request failed because the bucket has ACLs disabled via Object Ownership
+fn is_acl_not_supported<E: s3::error::ProvideErrorMetadata>(e: &s3::error::SdkError<E>) -> bool {
+    e.as_service_error().and_then(|x| x.code()) == Some("AccessControlListNotSupported")
+}
+
+/// Whether a request was rejected for lacking permissions
+fn is_access_denied<E: s3::error::ProvideErrorMetadata>(e: &s3::error::SdkError<E>) -> bool {
+    e.as_service_error().and_then(|x| x.code()) == Some("AccessDenied")
+}
+
+/// Whether the backend does not implement the operation at all
+fn is_not_implemented<E: s3::error::ProvideErrorMetadata>(e: &s3::error::SdkError<E>) -> bool {
+    let status = e.raw_response().map(|x| x.status().as_u16());
+    e.as_service_error().and_then(|x| x.code()) == Some("NotImplemented") || status == Some(501)
+}
+
+/// Starting a multipart upload failed because the backend does not implement them
+#[derive(Debug)]
+struct MultipartUnsupported(String);
+
+impl std::fmt::Display for MultipartUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Bucket '{}' does not support multipart uploads, upload with --no-multipart",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for MultipartUnsupported {}
+
+/// Whether a request was rejected as invalid, e.g. object lock settings on a bucket without it
+fn is_invalid_request<E: s3::error::ProvideErrorMetadata>(e: &s3::error::SdkError<E>) -> bool {
+    e.as_service_error().and_then(|x| x.code()) == Some("InvalidRequest")
+}
+
+/// Whether a conditional request was rejected because its condition does not hold
+fn is_precondition_failed<E>(e: &s3::error::SdkError<E>) -> bool {
+    match e {
+        s3::error::SdkError::ServiceError(x) => {
+            x.raw().status().as_u16() == StatusCode::PRECONDITION_FAILED
+        }
+        _ => false,
+    }
+}
+
+/// The request IDs of a failed request, which AWS support asks for, formatted to be appended to
+/// its error message
+fn request_ids<E>(e: &s3::error::SdkError<E>) -> String {
+    use s3::operation::{RequestId, RequestIdExt};
+    match (e.request_id(), e.extended_request_id()) {
+        (None, None) => String::new(),
+        (id, id_2) => format!(
+            " (x-amz-request-id: {}, x-amz-id-2: {})",
+            id.unwrap_or("-"),
+            id_2.unwrap_or("-")
+        ),
+    }
+}
+
+/// Like [`request_ids`], but only at verbose level 2, for messages about requests that are
+/// retried
+fn retry_request_ids<E>(e: &s3::error::SdkError<E>) -> String {
+    if tracing::enabled!(tracing::Level::DEBUG) {
+        request_ids(e)
+    } else {
+        String::new()
+    }
+}
+
+/// How long the server asked to wait before retrying, from the `Retry-After` header in seconds
+///
+/// Zero if there is no such header, or it has an HTTP date instead.
+fn retry_after<E>(e: &s3::error::SdkError<E>) -> Duration {
+    let value = e
+        .raw_response()
+        .and_then(|x| x.headers().get("retry-after"));
+    let seconds = value.and_then(|x| u64::from_str(x.trim()).ok());
+    Duration::from_secs(seconds.unwrap_or_default())
+}
+
+fn precondition_failed(bucket: &str, key: &str) -> anyhow::Error {
+    anyhow::anyhow!("The object at s3://{bucket}/{key} does not match --finalize-if-match")
+}
+
+fn acl_not_supported(bucket: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Bucket '{bucket}' does not accept ACLs, its Object Ownership is set to \
+        bucket owner enforced, upload without --acl"
+    )
+}
+
+/// A multipart upload in progress to a single S3 object
+#[derive(Clone)]
+struct Destination {
+    bucket: String,
+    key: String,
+    /// Profile the config was loaded from, to reload expired credentials
+    profile: Option<String>,
+    /// Current config and how often its credentials have been reloaded
+    config: Arc<std::sync::RwLock<(u64, aws_config::SdkConfig)>>,
+    upload_id: String,
+    sse_customer_key: Option<SseCustomerKey>,
+    /// Parts of a resumed upload that an earlier run uploaded, see --state-dir
+    completed_parts: Vec<CompletedPart>,
+}
+
+impl Destination {
+    fn config(&self) -> aws_config::SdkConfig {
+        self.config.read().unwrap().1.clone()
+    }
+
+    /// Replaces the credentials of the config, unless another part upload already did so since
+    /// `generation`
+    async fn reload_credentials(&self, generation: u64) -> anyhow::Result<()> {
+        let loaded = load_config(&self.bucket, self.profile.as_deref()).await?;
+        check_credentials(&loaded, &self.bucket).await?;
+        let mut config = self.config.write().unwrap();
+        if config.0 == generation {
+            let builder = config.1.clone().into_builder();
+            *config = (
+                generation + 1,
+                builder
+                    .credentials_provider(loaded.credentials_provider().unwrap())
+                    .build(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// What kind of problem made an upload fail, to tell apart e.g. bad credentials and flaky networks
+#[derive(Debug, Clone, Copy)]
+enum FailureReason {
+    Auth,
+    Network,
+    Throttling,
+    SourceRead,
+    SizeViolation,
+    Other,
+}
+
+impl FailureReason {
+    fn of<E: s3::error::ProvideErrorMetadata>(e: &s3::error::SdkError<E>) -> Self {
+        use s3::error::SdkError;
+        match e {
+            SdkError::DispatchFailure(_)
+            | SdkError::TimeoutError(_)
+            | SdkError::ResponseError(_) => return FailureReason::Network,
+            _ => (),
+        }
+        let code = e.as_service_error().and_then(|x| x.code());
+        let status = e.raw_response().map(|x| x.status().as_u16());
+        match (code, status) {
+            (
+                Some(
+                    "AccessDenied"
+                    | "InvalidAccessKeyId"
+                    | "SignatureDoesNotMatch"
+                    | "ExpiredToken"
+                    | "InvalidToken"
+                    | "ExpiredTokenException"
+                    | "TokenRefreshRequired",
+                ),
+                _,
+            )
+            | (_, Some(401 | 403)) => FailureReason::Auth,
+            (
+                Some("SlowDown" | "Throttling" | "ThrottlingException" | "RequestLimitExceeded"),
+                _,
+            )
+            | (_, Some(429 | 503)) => FailureReason::Throttling,
+            (Some("EntityTooLarge" | "EntityTooSmall" | "InvalidPart" | "InvalidPartOrder"), _) => {
+                FailureReason::SizeViolation
+            }
+            _ => FailureReason::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FailureReason::Auth => "auth",
+            FailureReason::Network => "network",
+            FailureReason::Throttling => "throttling",
+            FailureReason::SourceRead => "source-read",
+            FailureReason::SizeViolation => "size-violation",
+            FailureReason::Other => "other",
+        })
+    }
+}
+
+/// An upload error that knows its reason, and the part it happened at
+#[derive(Debug)]
+struct UploadFailure {
+    reason: FailureReason,
+    part_number: Option<i32>,
+    message: String,
+}
+
+impl std::fmt::Display for UploadFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for UploadFailure {}
+
+fn upload_failure(reason: FailureReason, part_number: i32, message: String) -> anyhow::Error {
+    UploadFailure {
+        reason,
+        part_number: Some(part_number),
+        message,
+    }
+    .into()
+}
+
+/// Whether a request failed because the session token of temporary credentials expired
+fn is_expired_credentials<E: s3::error::ProvideErrorMetadata>(e: &s3::error::SdkError<E>) -> bool {
+    matches!(
+        e.as_service_error().and_then(|x| x.code()),
+        Some("ExpiredToken" | "ExpiredTokenException" | "TokenRefreshRequired")
+    )
+}
+
+/// How often a single part upload may reload expired credentials, on top of its retries
+const MAX_CREDENTIAL_RELOADS: u32 = 3;
+
+/// Whether the connection failed, e.g. was reset while sending the body, rather than the request
+///
+/// S3 never saw a complete request then, so sending it again on a new connection is safe
+/// regardless of what the request was.
+fn is_connection_error<E>(e: &s3::error::SdkError<E>) -> bool {
+    match e {
+        s3::error::SdkError::DispatchFailure(x) => x.is_io(),
+        s3::error::SdkError::ResponseError(_) => true,
+        _ => false,
+    }
+}
+
+/// How often a single part upload may retry after a lost connection, on top of its retries
+const MAX_CONNECTION_RETRIES: u32 = 5;
+
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+fn parse_s3_path(path: &str) -> anyhow::Result<(String, String)> {
+    match path.strip_prefix("s3://") {
+        None => anyhow::bail!("S3 path has to start with 's3://'"),
+        Some(x) => match x.split_once('/') {
+            None => anyhow::bail!("S3 path should be 's3://bucket/key'"),
+            Some((bucket, key)) => Ok((bucket.to_string(), key.to_string())),
+        },
+    }
+}
+
+/// Prefixes and suffixes S3 reserves, directory buckets ending in `--x-s3` are allowed though
+const RESERVED_BUCKET_PREFIXES: [&str; 3] = ["xn--", "sthree-", "amzn-s3-demo-"];
+const RESERVED_BUCKET_SUFFIXES: [&str; 4] = ["-s3alias", "--ol-s3", ".mrap", "--table-s3"];
+
+/// Checks a bucket name against the S3 naming rules, unless --skip-bucket-validation is given
+fn check_bucket_name(args: &Args, bucket: &str) -> anyhow::Result<()> {
+    if args.skip_bucket_validation {
+        return Ok(());
+    }
+    let rule = if !(3..=63).contains(&bucket.len()) {
+        Some("has to be 3 to 63 characters long")
+    } else if !bucket
+        .bytes()
+        .all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == b'.' || x == b'-')
+    {
+        Some("may only contain lowercase letters, digits, dots and hyphens")
+    } else if !bucket.starts_with(|x: char| x.is_ascii_alphanumeric())
+        || !bucket.ends_with(|x: char| x.is_ascii_alphanumeric())
+    {
+        Some("has to begin and end with a letter or digit")
+    } else if bucket.contains("..") {
+        Some("may not contain two dots in a row")
+    } else if bucket.parse::<std::net::Ipv4Addr>().is_ok() {
+        Some("may not be formatted like an IP address")
+    } else if RESERVED_BUCKET_PREFIXES
+        .iter()
+        .any(|x| bucket.starts_with(x))
+        || RESERVED_BUCKET_SUFFIXES.iter().any(|x| bucket.ends_with(x))
+    {
+        Some("uses a prefix or suffix that S3 reserves")
+    } else {
+        None
+    };
+    match rule {
+        Some(rule) => anyhow::bail!(
+            "Bucket name '{bucket}' {rule}, pass --skip-bucket-validation if the backend accepts it"
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Returns the name of this host
+#[cfg(target_os = "linux")]
+fn hostname() -> Option<String> {
+    let mut buffer = [0_u8; 256];
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buffer.iter().position(|x| *x == 0)?;
+    String::from_utf8(buffer[..len].to_vec()).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+}
+
+/// A random token of 16 hex digits for --random-suffix, from the random low half of a UUID
+fn random_token() -> String {
+    let (_, low) = uuid::Uuid::new_v4().as_u64_pair();
+    format!("{low:016x}")
+}
+
+/// Inserts `-token` before the extension of the last path segment of the key
+///
+/// Everything from the first dot of the file name on counts as extension, to keep e.g.
+/// '.tar.gz' intact, except for a leading dot.
+fn add_key_suffix(key: &str, token: &str) -> String {
+    let name_start = key.rfind('/').map_or(0, |x| x + 1);
+    let position = match key[name_start..]
+        .char_indices()
+        .skip(1)
+        .find(|(_, x)| *x == '.')
+    {
+        Some((x, _)) => name_start + x,
+        None => key.len(),
+    };
+    format!("{}-{token}{}", &key[..position], &key[position..])
+}
+
+/// Values of the placeholders of --key-template, or why they are not available
+fn key_placeholders(
+    input: Option<&std::path::Path>,
+) -> Vec<(&'static str, Result<String, String>)> {
+    let now = s3::primitives::DateTime::from(std::time::SystemTime::now())
+        .fmt(s3::primitives::DateTimeFormat::DateTime)
+        .unwrap_or_default();
+    let time = |range: std::ops::Range<usize>| match now.get(range) {
+        Some(x) => Ok(x.to_string()),
+        None => Err("the current time could not be formatted".to_string()),
+    };
+    let basename = match input.and_then(|x| x.file_name()) {
+        Some(x) => Ok(x.to_string_lossy().into_owned()),
+        None => Err("it needs an input file".to_string()),
+    };
+    vec![
+        ("year", time(0..4)),
+        ("month", time(5..7)),
+        ("day", time(8..10)),
+        ("hour", time(11..13)),
+        ("uuid", Ok(uuid::Uuid::new_v4().to_string())),
+        (
+            "hostname",
+            hostname().ok_or("the host name is not known".to_string()),
+        ),
+        ("basename", basename),
+    ]
+}
+
+/// Replaces each `{name}` in the template with the value of the placeholder
+fn expand_placeholders(
+    template: &str,
+    placeholders: &[(&str, Result<String, String>)],
+) -> anyhow::Result<String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded += &rest[..start];
+        let end = match rest[start..].find('}') {
+            None => anyhow::bail!("Unclosed placeholder in '{template}'"),
+            Some(x) => start + x,
+        };
+        let name = &rest[start + 1..end];
+        match placeholders.iter().find(|(x, _)| *x == name) {
+            None => {
+                let known: Vec<_> = placeholders
+                    .iter()
+                    .map(|(x, _)| format!("{{{x}}}"))
+                    .collect();
+                anyhow::bail!(
+                    "Unknown placeholder '{{{name}}}' in '{template}', known are {}",
+                    known.join(", ")
+                );
+            }
+            Some((_, Err(e))) => anyhow::bail!("Cannot expand '{{{name}}}' in '{template}': {e}"),
+            Some((_, Ok(value))) => expanded += value,
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded += rest;
+    Ok(expanded)
+}
+
+/// How failed uploads are retried
+#[derive(Clone)]
+struct RetryPolicy {
+    /// Retries of a single part or object
+    max_retries: u32,
+    /// Retries left for the whole upload, shared by all parts
+    budget: Option<Arc<AtomicU32>>,
+}
+
+impl RetryPolicy {
+    fn new(args: &Args) -> Self {
+        Self {
+            max_retries: args.max_retries,
+            budget: args.retry_budget.map(|x| Arc::new(AtomicU32::new(x))),
+        }
+    }
+
+    /// Takes one retry from the shared budget, returns false if it is used up
+    fn take_from_budget(&self) -> bool {
+        match &self.budget {
+            None => true,
+            Some(budget) => budget
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| x.checked_sub(1))
+                .is_ok(),
+        }
+    }
+}
+
+/// A part uploaded to one destination
+struct PartUpload {
+    part: CompletedPart,
+    size: usize,
+    retries: u32,
+    start: std::time::SystemTime,
+    end: std::time::SystemTime,
+}
+
+/// Upload rate as an exponentially weighted moving average, to estimate the remaining time
+struct Throughput {
+    last: std::time::Instant,
+    /// Bytes per second
+    rate: Option<f64>,
+}
+
+impl Throughput {
+    /// Time over which older samples lose most of their weight, longer is smoother but reacts
+    /// slower to changes
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    fn new() -> Self {
+        Self {
+            last: std::time::Instant::now(),
+            rate: None,
+        }
+    }
+
+    fn update(&mut self, bytes: usize) {
+        let now = std::time::Instant::now();
+        let elapsed = (now - self.last).as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+        self.last = now;
+        let sample = bytes as f64 / elapsed;
+        // weight by elapsed time, so bursts of parts completing together do not dominate
+        let weight = 1.0 - (-elapsed / Self::WINDOW.as_secs_f64()).exp();
+        self.rate = Some(match self.rate {
+            None => sample,
+            Some(rate) => rate + weight * (sample - rate),
+        });
+    }
+
+    fn eta(&self, remaining: usize) -> Option<Duration> {
+        match self.rate {
+            Some(rate) if rate > 0.0 => Some(Duration::from_secs_f64(remaining as f64 / rate)),
+            _ => None,
+        }
+    }
+}
+
+/// Progress of the upload shared with the --heartbeat task
+struct Progress {
+    completed_bytes: usize,
+    completed_parts: usize,
+    /// Bytes per second
+    rate: Option<f64>,
+}
+
+/// Default of --heartbeat when stderr is not a terminal
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(60);
+
+/// Interval of --progress
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Task that is aborted when dropped, so that it ends with the function that started it
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Starts printing the progress every --heartbeat interval, if enabled
+fn start_heartbeat(
+    args: &Args,
+    progress: Arc<std::sync::Mutex<Progress>>,
+    total_size: Option<usize>,
+) -> Option<AbortOnDrop> {
+    let interval = match args.heartbeat {
+        Some(x) => x,
+        None if args.progress => PROGRESS_INTERVAL,
+        None if !args.quiet && !std::io::IsTerminal::is_terminal(&std::io::stderr()) => {
+            DEFAULT_HEARTBEAT
+        }
+        None => return None,
+    };
+    if interval.is_zero() {
+        return None;
+    }
+    let start = std::time::Instant::now();
+    Some(AbortOnDrop(tokio::spawn(async move {
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            ticks.tick().await;
+            let (completed, parts, rate) = {
+                let progress = progress.lock().unwrap();
+                (
+                    progress.completed_bytes,
+                    progress.completed_parts,
+                    progress.rate,
+                )
+            };
+            let done = match total_size {
+                Some(total) if total > 0 => format!(
+                    "{} of {} ({:.1}%)",
+                    format_size(completed),
+                    format_size(total),
+                    100.0 * completed as f64 / total as f64
+                ),
+                _ => format_size(completed),
+            };
+            let rate = rate.unwrap_or_default();
+            let eta = match total_size {
+                Some(total) if rate > 0.0 => {
+                    let remaining = total.saturating_sub(completed) as f64 / rate;
+                    format!(
+                        ", {} left",
+                        format_duration(Duration::from_secs_f64(remaining))
+                    )
+                }
+                _ => String::new(),
+            };
+            status!(
+                "uploaded {done} in {parts} parts after {}, {}/s{eta}",
+                format_duration(start.elapsed()),
+                format_size(rate as usize)
+            );
+        }
+    })))
+}
+
+/// How often the --min-throughput watchdog samples the progress
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Starts failing the upload once its rate falls below --min-throughput, if set
+///
+/// The error is sent like a failed part, so it wakes the upload loop while it waits for parts.
+fn start_watchdog(
+    args: &Args,
+    progress: Arc<std::sync::Mutex<Progress>>,
+    sender: channel::Sender<PartResult>,
+) -> Option<AbortOnDrop> {
+    let minimum = args.min_throughput?;
+    let window = args.min_throughput_window;
+    Some(AbortOnDrop(tokio::spawn(async move {
+        // completed bytes over time, starting with the newest sample at least a window old
+        let mut samples = std::collections::VecDeque::new();
+        let mut ticks = tokio::time::interval(WATCHDOG_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let now = tokio::time::Instant::now();
+            let completed = progress.lock().unwrap().completed_bytes;
+            samples.push_back((now, completed));
+            while samples.get(1).is_some_and(|(x, _)| now - *x >= window) {
+                samples.pop_front();
+            }
+            let (since, before) = samples[0];
+            if now - since < window {
+                continue;
+            }
+            let rate = completed.saturating_sub(before) as f64 / (now - since).as_secs_f64();
+            if rate >= minimum as f64 {
+                continue;
+            }
+            let e = ThroughputTooLow {
+                rate,
+                minimum,
+                window,
+            };
+            // a full channel means the loop is busy, it is tried again on the next tick
+            if !matches!(
+                sender.try_send(Err(e.into())),
+                Err(channel::TrySendError::Full(_))
+            ) {
+                break;
+            }
+        }
+    })))
+}
+
+/// Line of the --trace-out file
+#[derive(serde::Serialize)]
+struct PartEvent<'a> {
+    bucket: &'a str,
+    key: &'a str,
+    part_number: i32,
+    size: usize,
+    retries: u32,
+    etag: &'a str,
+    /// Seconds since the Unix epoch
+    start: f64,
+    end: f64,
+    /// Estimated seconds until the whole input is uploaded, if its size is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta_seconds: Option<f64>,
+}
+
+fn unix_seconds(x: std::time::SystemTime) -> f64 {
+    x.duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs_f64())
+        .unwrap_or_default()
+}
+
+fn write_part_event(
+    trace: &mut dyn std::io::Write,
+    destination: &Destination,
+    upload: &PartUpload,
+    eta: Option<Duration>,
+) -> anyhow::Result<()> {
+    let event = PartEvent {
+        bucket: &destination.bucket,
+        key: &destination.key,
+        part_number: upload.part.part_number.unwrap_or_default(),
+        size: upload.size,
+        retries: upload.retries,
+        etag: upload.part.e_tag.as_deref().unwrap_or_default(),
+        start: unix_seconds(upload.start),
+        end: unix_seconds(upload.end),
+        eta_seconds: eta.map(|x| x.as_secs_f64()),
+    };
+    serde_json::to_writer(&mut *trace, &event)?;
+    writeln!(trace)?;
+    Ok(())
+}
+
+/// Request body that sends its data no faster than a given rate
+struct ThrottledBody {
+    data: bytes::Bytes,
+    /// Bytes per second
+    rate: usize,
+    sent: usize,
+    start: Option<tokio::time::Instant>,
+    sleep: Option<std::pin::Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl ThrottledBody {
+    fn new(data: bytes::Bytes, rate: usize) -> Self {
+        Self {
+            data,
+            rate: rate.max(1),
+            sent: 0,
+            start: None,
+            sleep: None,
+        }
+    }
+}
+
+impl http_body::Body for ThrottledBody {
+    type Data = bytes::Bytes;
+    type Error = std::convert::Infallible;
+
+    fn poll_frame(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        use std::future::Future;
+        use std::task::Poll;
+
+        if self.data.is_empty() {
+            return Poll::Ready(None);
+        }
+        if let Some(sleep) = &mut self.sleep {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.sleep = None;
+        }
+        // send a chunk once the bytes sent so far are within the rate
+        let start = *self.start.get_or_insert_with(tokio::time::Instant::now);
+        let due = start + Duration::from_secs_f64(self.sent as f64 / self.rate as f64);
+        if due > tokio::time::Instant::now() {
+            let mut sleep = Box::pin(tokio::time::sleep_until(due));
+            if sleep.as_mut().poll(cx).is_pending() {
+                self.sleep = Some(sleep);
+                return Poll::Pending;
+            }
+        }
+        let chunk_size = (self.rate / 10)
+            .clamp(1024, 256 * 1024)
+            .min(self.data.len());
+        let chunk = self.data.split_to(chunk_size);
+        self.sent += chunk.len();
+        Poll::Ready(Some(Ok(http_body::Frame::data(chunk))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        http_body::SizeHint::with_exact(self.data.len() as u64)
+    }
+}
+
+/// How part bodies are sent, from --max-bandwidth-per-thread, --payload-signing and
+/// --timeout-per-mb
+#[derive(Clone, Copy)]
+struct BodyOptions {
+    max_bandwidth: Option<usize>,
+    payload_signing: PayloadSigning,
+    /// Base and time per MB of the timeout of each attempt
+    timeout: Option<(Duration, Duration)>,
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+}
+
+impl BodyOptions {
+    fn new(args: &Args) -> Self {
+        Self {
+            max_bandwidth: args.max_bandwidth_per_thread,
+            payload_signing: args
+                .payload_signing
+                .unwrap_or(args.provider.payload_signing()),
+            timeout: args.timeout_per_mb.map(|x| (args.timeout_base, x)),
+            checksum_algorithm: args.checksum_algorithm,
+        }
+    }
+
+    /// Timeout of an attempt to upload a part of `size` bytes
+    fn timeout(&self, size: usize) -> Option<Duration> {
+        let (base, per_mb) = self.timeout?;
+        Some(base + per_mb.mul_f64(size as f64 / (1024 * 1024) as f64))
+    }
+}
+
+/// Body of a part upload, limited to `max_bandwidth` bytes per second if set
+fn part_body(buffer: &bytes::Bytes, options: BodyOptions) -> s3::primitives::ByteStream {
+    let data = buffer.clone();
+    // the SDK only uses aws-chunked encoding for bodies that are not in memory
+    let rate = match (options.max_bandwidth, options.payload_signing) {
+        (Some(rate), _) => rate,
+        (None, PayloadSigning::Streaming) => usize::MAX,
+        (None, _) => return data.into(),
+    };
+    s3::primitives::ByteStream::new(s3::primitives::SdkBody::retryable(move || {
+        s3::primitives::SdkBody::from_body_1_x(ThrottledBody::new(data.clone(), rate))
+    }))
+}
+
+async fn upload_part(
+    destination: &Destination,
+    buffer: &bytes::Bytes,
+    part_number: i32,
+    retry_policy: &RetryPolicy,
+    body_options: BodyOptions,
+) -> anyhow::Result<PartUpload> {
+    let start = std::time::SystemTime::now();
+    let mut retry_count = 0;
+    let mut credential_reloads = 0;
+    let mut connection_retries = 0;
+    // hashed once, so retries do not hash the part again, and set explicitly, so that also
+    // unsigned and streamed bodies carry it without a trailer
+    let checksum = (body_options.checksum_algorithm).map(|x| (x, x.digest(buffer)));
+    let timeout = body_options.timeout(buffer.len());
+    if let Some(timeout) = timeout {
+        tracing::debug!(
+            part_number,
+            "Timeout of {}s per attempt",
+            timeout.as_secs_f64()
+        );
+    }
+    let overrides = || {
+        let overrides = retry_override();
+        match timeout {
+            Some(timeout) => overrides.timeout_config(
+                s3::config::timeout::TimeoutConfig::builder()
+                    .operation_attempt_timeout(timeout)
+                    .build(),
+            ),
+            None => overrides,
+        }
+    };
+    loop {
+        let (generation, config) = destination.config.read().unwrap().clone();
+        let client = s3_client(&config);
+        let _connection = connection().await;
+        let request = client
+            .upload_part()
+            .body(part_body(buffer, body_options))
+            // some S3-compatible gateways insist on an explicit length
+            .content_length(buffer.len() as i64)
+            .bucket(&destination.bucket)
+            .key(&destination.key)
+            .upload_id(&destination.upload_id)
+            .part_number(part_number)
+            .with_sse_c(destination.sse_customer_key.as_ref())
+            .set_checksum_algorithm(body_options.checksum_algorithm.map(ChecksumAlgorithm::sdk));
+        let request = with_checksum!(request, checksum.clone());
+        let response = match body_options.payload_signing {
+            PayloadSigning::Unsigned => {
+                // the SDK would send its own checksum in a trailer, and so with aws-chunked
+                // encoding, the one set above is a plain header
+                let checksums = overrides().request_checksum_calculation(
+                    s3::config::RequestChecksumCalculation::WhenRequired,
+                );
+                request
+                    .customize()
+                    .disable_payload_signing()
+                    .config_override(checksums)
+                    .send()
+                    .await
+            }
+            _ => {
+                request
+                    .customize()
+                    .config_override(overrides())
+                    .send()
+                    .await
+            }
+        };
+        match response {
+            Ok(response) => {
+                let part = CompletedPart::builder()
+                    .e_tag(response.e_tag.unwrap_or("".to_string()))
+                    .part_number(part_number)
+                    .set_checksum_crc32(response.checksum_crc32)
+                    .set_checksum_crc32_c(response.checksum_crc32_c)
+                    .set_checksum_crc64_nvme(response.checksum_crc64_nvme)
+                    .set_checksum_sha1(response.checksum_sha1)
+                    .set_checksum_sha256(response.checksum_sha256);
+                // the same checksum as sent, for the completion to check the part against
+                let part = with_checksum!(part, checksum).build();
+                return Ok(PartUpload {
+                    part,
+                    size: buffer.len(),
+                    retries: retry_count + connection_retries,
+                    start,
+                    end: std::time::SystemTime::now(),
+                });
+            }
+            Err(e) => {
+                // expired credentials are not the part's fault, so they do not count as retry
+                if is_expired_credentials(&e) && credential_reloads < MAX_CREDENTIAL_RELOADS {
+                    credential_reloads += 1;
+                    tracing::warn!(
+                        part_number,
+                        upload_id = destination.upload_id,
+                        "Credentials expired, reloading them"
+                    );
+                    destination.reload_credentials(generation).await?;
+                    continue;
+                }
+                // lost connections are the network's fault, so they do not use up the retries
+                // and the budget meant for failing requests
+                if is_connection_error(&e) && connection_retries < MAX_CONNECTION_RETRIES {
+                    connection_retries += 1;
+                    let backoff = Duration::from_secs(connection_retries.into());
+                    tracing::warn!(
+                        part_number,
+                        upload_id = destination.upload_id,
+                        "Connection lost ({connection_retries}/{MAX_CONNECTION_RETRIES}), retrying \
+                        in {:.1}s: {e}",
+                        backoff.as_secs_f64()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    continue;
+                }
+                retry_count += 1;
+                if retry_count > retry_policy.max_retries {
+                    return Err(upload_failure(
+                        FailureReason::of(&e),
+                        part_number,
+                        format!(
+                            "Failed uploading part {part_number}: {destination}: {e}{}",
+                            request_ids(&e)
+                        ),
+                    ));
+                }
+                if !retry_policy.take_from_budget() {
+                    return Err(upload_failure(
+                        FailureReason::of(&e),
+                        part_number,
+                        format!(
+                            "Failed uploading part {part_number}: {destination}: \
+                            retry budget exhausted: {e}{}",
+                            request_ids(&e)
+                        ),
+                    ));
+                }
+                let backoff = Duration::from_secs(2_u64.pow(retry_count)).max(retry_after(&e));
+                tracing::warn!(
+                    part_number,
+                    upload_id = destination.upload_id,
+                    retry = retry_count,
+                    "Upload failed (attempt {retry_count}/{}), retrying in {:.1}s: {e}{}",
+                    retry_policy.max_retries + 1,
+                    backoff.as_secs_f64(),
+                    retry_request_ids(&e)
+                );
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+/// Reads until `buffer` is full or the input ends, returning the number of bytes read and whether
+/// the end of the input was reached
+fn read_part(input: &mut dyn std::io::Read, buffer: &mut [u8]) -> std::io::Result<(usize, bool)> {
+    let mut pos = 0;
+    let mut end_of_file = false;
+    while pos < buffer.len() && !end_of_file {
+        let num_read = input.read(&mut buffer[pos..])?;
+        end_of_file = num_read == 0;
+        pos += num_read;
+    }
+    Ok((pos, end_of_file))
+}
+
+/// Tar archive of a directory, written by a thread into a pipe
+struct TarStream {
+    pipe: std::io::PipeReader,
+    writer: Option<std::thread::JoinHandle<std::io::Result<()>>>,
+}
+
+impl TarStream {
+    fn new(dir: &std::path::Path) -> anyhow::Result<Self> {
+        let (pipe, writer) = std::io::pipe()?;
+        let name = match dir.canonicalize()?.file_name() {
+            Some(x) => PathBuf::from(x),
+            None => PathBuf::from("."),
+        };
+        let dir = dir.to_path_buf();
+        let writer = std::thread::spawn(move || {
+            let mut builder = tar::Builder::new(writer);
+            builder.follow_symlinks(false);
+            builder.append_dir_all(&name, &dir)?;
+            builder.into_inner()?;
+            Ok(())
+        });
+        Ok(Self {
+            pipe,
+            writer: Some(writer),
+        })
+    }
+}
+
+impl std::io::Read for TarStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let num_read = self.pipe.read(buf)?;
+        // the pipe also ends when archiving fails, which must not look like a complete archive
+        if num_read == 0 && !buf.is_empty() {
+            if let Some(writer) = self.writer.take() {
+                match writer.join() {
+                    Ok(result) => result?,
+                    Err(_) => return Err(std::io::Error::other("Archiving thread panicked")),
+                }
+            }
+        }
+        Ok(num_read)
+    }
+}
+
+/// Where the parts of a multipart upload are read from
+enum Input {
+    Reader(Box<dyn std::io::Read + Send + Sync>),
+    /// The input file mapped with --mmap, parts are slices of it
+    Mapped {
+        data: bytes::Bytes,
+        file: std::fs::File,
+        offset: usize,
+    },
+}
+
+impl Input {
+    fn map(file: std::fs::File) -> anyhow::Result<Self> {
+        // SAFETY: --mmap documents that the file may not change during the upload
+        let map = match unsafe { memmap2::Mmap::map(&file) } {
+            Ok(x) => x,
+            Err(e) => anyhow::bail!("Failed to memory-map input file: {e}"),
+        };
+        Ok(Self::Mapped {
+            data: bytes::Bytes::from_owner(map),
+            file,
+            offset: 0,
+        })
+    }
+
+    /// Reads from a reader in chunks of `size`, see --read-block-size
+    fn buffered(self, size: Option<usize>) -> Self {
+        match (self, size) {
+            (Self::Reader(inner), Some(size)) => Self::Reader(Box::new(ChunkReader {
+                inner,
+                chunk: vec![0_u8; size],
+                pos: 0,
+                len: 0,
+                end_of_file: false,
+            })),
+            (input, _) => input,
+        }
+    }
+
+    /// Records the time of every read that returns data in `last_read`, for --read-timeout
+    fn watched(self, last_read: Arc<std::sync::Mutex<std::time::Instant>>) -> Self {
+        match self {
+            Self::Reader(inner) => Self::Reader(Box::new(StallWatch { inner, last_read })),
+            input => input,
+        }
+    }
+
+    /// Puts data in front of a reader, for --append
+    fn prepend(self, data: bytes::Bytes) -> anyhow::Result<Self> {
+        use std::io::Read;
+
+        match self {
+            Self::Reader(input) => Ok(Self::Reader(Box::new(
+                std::io::Cursor::new(data).chain(input),
+            ))),
+            Self::Mapped { .. } => anyhow::bail!("Cannot put data in front of a mapped input"),
+        }
+    }
+
+    /// Reads a part of `size`, or the rest of the input if `None`, returning the data and whether
+    /// the end of the input was reached
+    ///
+    /// A reader that ends right at the end of a part can only tell with the next read, so that
+    /// part is not marked as the last one and the next part is empty. Callers must not upload an
+    /// empty part, unless it is the only one.
+    fn read_part(&mut self, size: Option<usize>) -> std::io::Result<(bytes::Bytes, bool)> {
+        match self {
+            Self::Reader(input) => match size {
+                Some(size) => {
+                    let mut buffer = vec![0_u8; size];
+                    let (pos, end_of_file) = read_part(input, &mut buffer)?;
+                    buffer.resize(pos, 0_u8);
+                    Ok((buffer.into(), end_of_file))
+                }
+                None => Ok((read_rest(input)?.into(), true)),
+            },
+            Self::Mapped { data, file, offset } => {
+                // reading a truncated part of the mapping would crash
+                if file.metadata()?.len() < data.len() as u64 {
+                    return Err(std::io::Error::other("Input file shrank during the upload"));
+                }
+                let end = match size {
+                    Some(size) => (*offset + size).min(data.len()),
+                    None if data.len() - *offset > MAX_PART_SIZE => {
+                        return Err(std::io::Error::other(
+                            "The rest of the input is larger than 5GB, the largest part S3 \
+                            accepts",
+                        ))
+                    }
+                    None => data.len(),
+                };
+                let part = data.slice(*offset..end);
+                *offset = end;
+                Ok((part, end == data.len()))
+            }
+        }
+    }
+}
+
+/// Reader that fills a whole chunk from its input at a time, and serves reads from it
+///
+/// Unlike a `BufReader`, a chunk is refilled until it is full or the input ends, so short reads
+/// of a pipe do not lead to short chunks, and large reads never bypass it.
+struct ChunkReader {
+    inner: Box<dyn std::io::Read + Send + Sync>,
+    chunk: Vec<u8>,
+    pos: usize,
+    len: usize,
+    end_of_file: bool,
+}
+
+impl std::io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.len && !self.end_of_file {
+            (self.len, self.end_of_file) = read_part(&mut self.inner, &mut self.chunk)?;
+            self.pos = 0;
+        }
+        let size = buf.len().min(self.len - self.pos);
+        buf[..size].copy_from_slice(&self.chunk[self.pos..self.pos + size]);
+        self.pos += size;
+        Ok(size)
+    }
+}
+
+/// Reader that records when it last received data
+struct StallWatch {
+    inner: Box<dyn std::io::Read + Send + Sync>,
+    last_read: Arc<std::sync::Mutex<std::time::Instant>>,
+}
+
+impl std::io::Read for StallWatch {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read > 0 {
+            *self.last_read.lock().unwrap() = std::time::Instant::now();
+        }
+        Ok(read)
+    }
+}
+
+/// Reads the rest of the input into a single part
+fn read_rest(input: &mut dyn std::io::Read) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    input
+        .take(MAX_PART_SIZE as u64 + 1)
+        .read_to_end(&mut buffer)?;
+    if buffer.len() > MAX_PART_SIZE {
+        return Err(std::io::Error::other(
+            "The rest of the input is larger than 5GB, the largest part S3 accepts",
+        ));
+    }
+    Ok(buffer)
+}
+
+/// Parts uploaded by a task to every destination, `None` for the initial tokens
+type PartResult = anyhow::Result<Option<Vec<Option<PartUpload>>>>;
+
+/// A token of the part pool, returned with the result of its task when dropped
+///
+/// Returning it on drop means a task that is cancelled, e.g. when the runtime shuts down, still
+/// gives its token back instead of leaving the upload waiting for it forever.
+struct Token {
+    sender: channel::Sender<PartResult>,
+    result: Option<PartResult>,
+}
+
+impl Token {
+    fn finish(mut self, result: PartResult) {
+        self.result = Some(result);
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        let result = self
+            .result
+            .take()
+            .unwrap_or_else(|| Err(anyhow::anyhow!("Part upload was cancelled")));
+        let _ = self.sender.send(result);
+    }
+}
+
+/// Uploads all parts of the input to every destination
+async fn upload(
+    args: &Args,
+    destinations: Arc<Vec<Destination>>,
+    mut input: Input,
+    read_timeout: Option<Duration>,
+    num_tokens: usize,
+    total_size: Option<usize>,
+    mut trace: Option<Box<dyn std::io::Write>>,
+) -> anyhow::Result<UploadedParts> {
+    // add initial tokens, with --slow-start only a few that grow as parts complete
+    let (token_sender, token_receiver) = channel::bounded::<PartResult>(num_tokens);
+    let tokens = std::cell::Cell::new(args.slow_start.unwrap_or(num_tokens).clamp(1, num_tokens));
+    for _ in 0..tokens.get() {
+        if token_sender.send(Ok(None)).is_err() {
+            anyhow::bail!("Failed to initialize threads");
+        }
+    }
+
+    // parts of a resumed upload that every destination has are only read, not uploaded again
+    let resumed = destinations
+        .iter()
+        .map(|x| {
+            let parts = x.completed_parts.iter().filter_map(|x| x.part_number);
+            parts.collect::<std::collections::BTreeSet<_>>()
+        })
+        .reduce(|a, b| &a & &b)
+        .unwrap_or_default();
+    let mut part_results: Vec<Vec<_>> = destinations
+        .iter()
+        .map(|x| {
+            let parts = x.completed_parts.iter();
+            let parts = parts.filter(|x| x.part_number.is_some_and(|x| resumed.contains(&x)));
+            parts.cloned().collect()
+        })
+        .collect();
+    let mut bytes = 0;
+    let mut num_parts = 0;
+    let mut retries = 0;
+    let mut part_durations = Vec::new();
+    let dropped: Arc<std::sync::Mutex<Vec<Option<anyhow::Error>>>> = Arc::new(
+        std::sync::Mutex::new(destinations.iter().map(|_| None).collect()),
+    );
+    let sidecar = args.input.as_deref().filter(|_| args.use_sidecar_checksum);
+    let sidecar = sidecar
+        .and_then(sidecar_path)
+        .map(|(_, algorithm)| algorithm);
+    let mut hasher = (args.content_addressed
+        || args.write_checksum.is_some()
+        || sidecar == Some(SidecarAlgorithm::Sha256))
+    .then(Sha256::new);
+    let mut md5 = (sidecar == Some(SidecarAlgorithm::Md5)).then(md5::Md5::new);
+    let full_object = matches!(
+        checksum_type(args),
+        Ok(Some(s3::types::ChecksumType::FullObject))
+    );
+    let mut checksum = args
+        .checksum_algorithm
+        .filter(|_| args.post_verify_checksum && full_object)
+        .map(ChecksumAlgorithm::hasher);
+    // with --append the upload starts with the parts copied from the existing object
+    let first_part = match args.append {
+        true => resumed.len() as i32 + 1,
+        false => 1,
+    };
+    let mut completed_bytes = match total_size {
+        Some(_) if args.append => 0,
+        Some(total) => resumed
+            .iter()
+            .map(|x| {
+                (total.saturating_sub((*x as usize - 1) * args.block_size)).min(args.block_size)
+            })
+            .sum(),
+        None => 0,
+    };
+    let mut interrupted = false;
+    let mut signal = watch_signals();
+    let retry_policy = RetryPolicy::new(args);
+    let mut throughput = Throughput::new();
+    let mut completed_parts = resumed.len();
+    let progress = Arc::new(std::sync::Mutex::new(Progress {
+        completed_bytes,
+        completed_parts,
+        rate: None,
+    }));
+    let _heartbeat = start_heartbeat(args, progress.clone(), total_size);
+    let _watchdog = start_watchdog(args, progress.clone(), token_sender.clone());
+    // waits for a free token, returning the number of the part that completed if any
+    let mut wait_for_part = || {
+        let received = match DEADLINE.get() {
+            None => token_receiver.recv().map_err(anyhow::Error::from),
+            Some((deadline, duration)) => match token_receiver.recv_deadline(deadline.into_std()) {
+                Err(channel::RecvTimeoutError::Timeout) => Err(DeadlineExceeded(*duration).into()),
+                x => x.map_err(anyhow::Error::from),
+            },
+        };
+        match received {
+            Err(e) if e.is::<DeadlineExceeded>() => Err(e),
+            Err(e) => anyhow::bail!("Failed communicate with threads: {e}"),
+            Ok(Err(e)) => Err(e),
+            Ok(Ok(Some(parts))) => {
+                // destinations dropped with --fanout-failure continue have no upload
+                let first = parts.iter().flatten().next();
+                let size = first.map_or(0, |x| x.size);
+                let part_number = first.and_then(|x| x.part.part_number);
+                completed_bytes += size;
+                completed_parts += 1;
+                // each completed part adds a token, doubling the parts in flight per round trip
+                if tokens.get() < num_tokens && token_sender.send(Ok(None)).is_ok() {
+                    tokens.set(tokens.get() + 1);
+                    tracing::debug!("Ramping up to {} parts in flight", tokens.get());
+                }
+                throughput.update(size);
+                *progress.lock().unwrap() = Progress {
+                    completed_bytes,
+                    completed_parts,
+                    rate: throughput.rate,
+                };
+                let eta =
+                    total_size.and_then(|x| throughput.eta(x.saturating_sub(completed_bytes)));
+                match total_size {
+                    Some(total) if total > 0 => tracing::info!(
+                        "Uploaded {} of {} ({:.1}%){}",
+                        format_size(completed_bytes),
+                        format_size(total),
+                        100.0 * completed_bytes as f64 / total as f64,
+                        eta.map(|x| format!(", {} left", format_duration(x)))
+                            .unwrap_or_default()
+                    ),
+                    _ => tracing::info!("Uploaded {}", format_size(completed_bytes)),
+                }
+                for ((results, upload), destination) in
+                    part_results.iter_mut().zip(parts).zip(destinations.iter())
+                {
+                    let Some(upload) = upload else {
+                        continue;
+                    };
+                    if let Some(writer) = &mut trace {
+                        if let Err(e) = write_part_event(writer, destination, &upload, eta) {
+                            tracing::warn!("Failed to write trace, disabling it: {e}");
+                            trace = None;
+                        }
+                    }
+                    retries += upload.retries;
+                    part_durations
+                        .push(upload.end.duration_since(upload.start).unwrap_or_default());
+                    results.push(upload.part);
+                }
+                Ok(part_number)
+            }
+            Ok(Ok(None)) => Ok(None),
+        }
+    };
+    // parts started but not completed, and tokens taken while waiting for them with --ordered
+    let mut in_flight = std::collections::BTreeSet::new();
+    let mut spare_tokens = 0;
+    // read on the blocking pool, so a stalled input can be timed out, up to --read-ahead parts
+    // ahead of the uploads
+    let (part_sender, mut part_receiver) =
+        tokio::sync::mpsc::channel(read_ahead(args, num_tokens).max(1));
+    let last_read = Arc::new(std::sync::Mutex::new(std::time::Instant::now()));
+    input = input
+        .watched(last_read.clone())
+        .buffered(args.read_block_size);
+    let reader_args = args.clone();
+    let runtime = tokio::runtime::Handle::current();
+    let reader_last_read = last_read.clone();
+    tokio::task::spawn_blocking(move || {
+        for part_number in 1.. {
+            // reserve first, so that no more parts are held than the channel has room for
+            let Ok(permit) = runtime.block_on(part_sender.reserve()) else {
+                break;
+            };
+            // waiting for room in the channel is not a stall of the input
+            *reader_last_read.lock().unwrap() = std::time::Instant::now();
+            let result = input.read_part(part_size(&reader_args, part_number));
+            let last = !matches!(&result, Ok((buffer, false)) if !buffer.is_empty());
+            permit.send(result);
+            if last {
+                break;
+            }
+        }
+    });
+
+    for part_number in first_part.. {
+        check_deadline()?;
+        // take an upload slot before the part, so read parts only wait in the channel
+        if !resumed.contains(&part_number) {
+            if spare_tokens == 0 {
+                if let Some(x) = wait_for_part()? {
+                    in_flight.remove(&x);
+                }
+                spare_tokens += 1;
+            }
+            if let Some(window) = args.ordered {
+                while in_flight
+                    .first()
+                    .is_some_and(|x| (part_number - x) as usize >= window)
+                {
+                    if let Some(x) = wait_for_part()? {
+                        in_flight.remove(&x);
+                    }
+                    spare_tokens += 1;
+                }
+            }
+        }
+
+        // a slow input is fine as long as it keeps sending, only one without any data for
+        // --read-timeout has stalled
+        let read = async {
+            let Some(timeout) = read_timeout else {
+                return Ok(part_receiver.recv().await);
+            };
+            loop {
+                let wait = timeout.saturating_sub(last_read.lock().unwrap().elapsed());
+                if wait.is_zero() {
+                    return Err(upload_failure(
+                        FailureReason::SourceRead,
+                        part_number,
+                        format!("No input received for {}s", timeout.as_secs_f64()),
+                    ));
+                }
+                if let Ok(x) = tokio::time::timeout(wait, part_receiver.recv()).await {
+                    return Ok(x);
+                }
+            }
+        };
+        let result = tokio::select! {
+            x = read => match x {
+                Ok(Some(x)) => x,
+                Ok(None) => Err(std::io::Error::other("Reading the input stopped unexpectedly")),
+                Err(e) => {
+                    drain_parts(&token_receiver, tokens.get() - spare_tokens);
+                    return Err(e);
+                }
+            },
+            _ = signal.wait_for(|x| *x) => {
+                interrupted = true;
+                break;
+            }
+        };
+        let (buffer, end_of_file) = match result {
+            Ok(x) => x,
+            Err(e) => {
+                drain_parts(&token_receiver, tokens.get() - spare_tokens);
+                return Err(upload_failure(
+                    FailureReason::SourceRead,
+                    part_number,
+                    format!("Failed reading input at offset {bytes}: {e}"),
+                ));
+            }
+        };
+
+        // an empty part is the end of an input that ended right at the previous part, but S3 needs
+        // at least one part, so an empty object is a single empty part
+        if buffer.is_empty() && !(part_number == 1 && args.allow_empty == EmptyInput::Object) {
+            break;
+        }
+        if part_number as usize > args.max_parts {
+            drain_parts(&token_receiver, tokens.get() - spare_tokens);
+            return Err(upload_failure(
+                FailureReason::SizeViolation,
+                part_number,
+                format!(
+                    "Input needs part {part_number}, but {}, increase --block-size",
+                    parts_limit(args)
+                ),
+            ));
+        }
+        if (bytes + buffer.len()) as u64 > MAX_OBJECT_SIZE {
+            drain_parts(&token_receiver, tokens.get() - spare_tokens);
+            return Err(upload_failure(
+                FailureReason::SizeViolation,
+                part_number,
+                format!(
+                    "Input is larger than {}, the maximum size of an S3 object",
+                    format_size(MAX_OBJECT_SIZE as usize)
+                ),
+            ));
+        }
+        if total_size.is_some_and(|x| bytes <= x && bytes + buffer.len() > x) {
+            tracing::warn!("Input is larger than the expected size, continuing anyway");
+        }
+        bytes += buffer.len();
+        num_parts += 1;
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&buffer);
+        }
+        if let Some(md5) = &mut md5 {
+            md5.update(&buffer);
+        }
+        if let Some(checksum) = &mut checksum {
+            checksum.update(&buffer);
+        }
+        if resumed.contains(&part_number) {
+            if end_of_file {
+                break;
+            }
+            continue;
+        }
+        spare_tokens -= 1;
+        in_flight.insert(part_number);
+
+        let destinations = destinations.clone();
+        let dropped = dropped.clone();
+        let continue_on_failure = args.fanout_failure == FanoutFailure::Continue;
+        let retry_policy = retry_policy.clone();
+        let body_options = BodyOptions::new(args);
+        let token = Token {
+            sender: token_sender.clone(),
+            result: None,
+        };
+        // the semaphore is never closed
+        let pooled = match PART_POOL.get() {
+            Some(semaphore) => semaphore.acquire().await.ok(),
+            None => None,
+        };
+        let parts = async move {
+            let _pooled = pooled;
+            let uploads = destinations.iter().enumerate().map(|(i, x)| {
+                let skip = dropped.lock().unwrap()[i].is_some();
+                let upload = upload_part(x, &buffer, part_number, &retry_policy, body_options);
+                async move {
+                    match skip {
+                        true => Ok(None),
+                        false => upload.await.map(Some),
+                    }
+                }
+            });
+            // a part failing for any destination fails the whole upload, unless the destination
+            // is dropped and others remain with --fanout-failure continue
+            if !continue_on_failure {
+                return futures::future::try_join_all(uploads).await;
+            }
+            let results = futures::future::join_all(uploads).await;
+            let mut dropped = dropped.lock().unwrap();
+            let mut parts = Vec::new();
+            for ((result, dropped), destination) in results
+                .into_iter()
+                .zip(dropped.iter_mut())
+                .zip(destinations.iter())
+            {
+                match result {
+                    Ok(x) => parts.push(x),
+                    Err(e) => {
+                        if dropped.is_none() {
+                            tracing::error!("Dropping {destination}: {e}");
+                            *dropped = Some(e);
+                        }
+                        parts.push(None);
+                    }
+                }
+            }
+            match dropped.iter().all(Option::is_some) {
+                true => anyhow::bail!("every destination failed"),
+                false => Ok(parts),
+            }
+        };
+        spawn_part(token, part_number, parts);
+
+        if end_of_file {
+            break;
+        }
+    }
+
+    // drain remaining results
+    let mut drained = 0;
+    while drained < tokens.get() - spare_tokens {
+        wait_for_part()?;
+        drained += 1;
+    }
+
+    let dropped = std::mem::take(&mut *dropped.lock().unwrap());
+    Ok(UploadedParts {
+        bytes,
+        num_parts,
+        retries,
+        digest: hasher.map(|x| format!("{:x}", x.finalize())),
+        md5: md5.map(|x| format!("{:x}", x.finalize())),
+        checksum: checksum.map(|x| {
+            use base64::Engine;
+            base64::engine::general_purpose::STANDARD.encode(x.finalize())
+        }),
+        parts: part_results,
+        dropped,
+        part_durations,
+        interrupted,
+    })
+}
+
+/// Uploads a part on the runtime, returning its result with the token
+fn spawn_part(
+    token: Token,
+    part_number: i32,
+    parts: impl std::future::Future<Output = anyhow::Result<Vec<Option<PartUpload>>>> + Send + 'static,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        // a panic has to return the token as well, or waiting for it would block forever
+        let result = match std::panic::AssertUnwindSafe(parts).catch_unwind().await {
+            // typed failures already name the part
+            Ok(x) => x.map(Some).map_err(|e| match e.is::<UploadFailure>() {
+                true => e,
+                false => anyhow::anyhow!("Failed uploading part {part_number}: {e}"),
+            }),
+            Err(_) => Err(anyhow::anyhow!("Upload of part {part_number} panicked")),
+        };
+        token.finish(result);
+    })
+}
+
+/// Waits for the parts in flight after reading the input failed, so that aborting the upload is
+/// not raced by parts that are still being uploaded
+fn drain_parts(token_receiver: &channel::Receiver<PartResult>, num_tokens: usize) {
+    for _ in 0..num_tokens {
+        if token_receiver.recv().is_err() {
+            break;
+        }
+    }
+}
+
+/// Lists all parts the server has for an upload, following every page of the listing
+///
+/// A page holds at most 1000 parts, so large uploads need several requests.
+async fn list_parts(destination: &Destination) -> anyhow::Result<Vec<s3::types::Part>> {
+    let client = s3_client(&destination.config());
+    let mut parts = Vec::new();
+    let mut marker: Option<String> = None;
+    loop {
+        let response = client
+            .list_parts()
+            .bucket(&destination.bucket)
+            .key(&destination.key)
+            .upload_id(&destination.upload_id)
+            .set_part_number_marker(marker.clone())
+            .with_sse_c(destination.sse_customer_key.as_ref())
+            .send()
+            .await?;
+        parts.extend(response.parts().iter().cloned());
+        if !response.is_truncated.unwrap_or_default() {
+            break;
+        }
+        // a marker that does not advance would list the same page forever
+        match response.next_part_number_marker {
+            Some(next) if Some(&next) != marker.as_ref() => marker = Some(next),
+            _ => anyhow::bail!("{destination}: part listing is truncated without a next marker"),
+        }
+    }
+    parts.sort_by_key(|x| x.part_number);
+    Ok(parts)
+}
+
+/// Returns the part numbers of completed parts the server does not list yet, or lists with a
+/// different ETag
+async fn unlisted_parts(
+    destination: &Destination,
+    parts: &[CompletedPart],
+) -> anyhow::Result<Vec<i32>> {
+    let listed: std::collections::HashMap<_, _> = list_parts(destination)
+        .await?
+        .into_iter()
+        .filter_map(|x| Some((x.part_number?, x.e_tag)))
+        .collect();
+    Ok(parts
+        .iter()
+        .filter(|x| listed.get(&x.part_number.unwrap_or_default()) != Some(&x.e_tag))
+        .filter_map(|x| x.part_number)
+        .collect())
+}
+
+/// How often the part listing is retried until it shows all parts
+const MAX_LIST_ATTEMPTS: u32 = 5;
+
+/// Waits until the server lists all completed parts, for backends where a just uploaded part is
+/// not visible right away
+async fn wait_for_parts(destination: &Destination, parts: &[CompletedPart]) -> anyhow::Result<()> {
+    for attempt in 1..=MAX_LIST_ATTEMPTS {
+        let missing = unlisted_parts(destination, parts).await?;
+        if missing.is_empty() {
+            return Ok(());
+        }
+        if attempt == MAX_LIST_ATTEMPTS {
+            anyhow::bail!(
+                "{destination}: {} parts are still not listed by the server, e.g. part {}",
+                missing.len(),
+                missing[0]
+            );
+        }
+        let backoff = Duration::from_secs(2_u64.pow(attempt - 1));
+        tracing::warn!(
+            "{destination}: {} parts not listed yet, checking again in {:.1}s",
+            missing.len(),
+            backoff.as_secs_f64()
+        );
+        tokio::time::sleep(backoff).await;
+    }
+    Ok(())
+}
+
+async fn complete_upload(
+    args: &Args,
+    destination: &Destination,
+    mut parts: Vec<CompletedPart>,
+    bytes: usize,
+) -> anyhow::Result<UploadResult> {
+    if parts.is_empty() {
+        anyhow::bail!("Cannot complete an upload without parts, the input was empty");
+    }
+    if parts.len() > args.max_parts {
+        anyhow::bail!(
+            "Cannot complete an upload of {} parts, {}",
+            parts.len(),
+            parts_limit(args)
+        );
+    }
+    parts.sort_by_key(|x| x.part_number);
+    if args.check_parts {
+        wait_for_parts(destination, &parts).await?;
+    }
+    let num_parts = parts.len();
+    // the semaphore is never closed
+    let _completion = match COMPLETIONS.get() {
+        Some(semaphore) => semaphore.acquire().await.ok(),
+        None => None,
+    };
+    let client = s3_client(&destination.config());
+    let response = match client
+        .complete_multipart_upload()
+        .bucket(&destination.bucket)
+        .key(&destination.key)
+        .upload_id(&destination.upload_id)
+        .with_sse_c(destination.sse_customer_key.as_ref())
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build(),
+        )
+        .set_checksum_type(checksum_type(args)?)
+        .send()
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!(
+            "Failed to complete upload of {num_parts} parts: {e}{}",
+            request_ids(&e)
+        ),
+    };
+    // S3 answers with 200 before it has completed the upload, so a failure only shows in the
+    // body. The SDK turns an error body into an error, but a result without an ETag, as sent by
+    // some S3-compatible backends, does not mean the upload is complete either.
+    let etag = match response.e_tag {
+        Some(x) => x,
+        None => anyhow::bail!(
+            "Failed to complete upload of {num_parts} parts: the response has no ETag, the upload \
+            may not be complete"
+        ),
+    };
+
+    let checksum = (args.checksum_algorithm).and_then(|x| x.of(checksums!(response)));
+    Ok(UploadResult {
+        etag,
+        bytes,
+        location: response.location,
+        version_id: response.version_id,
+        checksum,
+        parts: num_parts,
+        retries: 0,
+    })
+}
+
+/// Returns the checksum S3 should report for the completed upload, as `algorithm:base64`, for
+/// --post-verify-checksum
+///
+/// A composite checksum is the checksum of the part checksums with the number of parts as
+/// suffix, a full-object checksum the one of the input.
+fn expected_checksum(
+    args: &Args,
+    parts: &[CompletedPart],
+    full_object: Option<&str>,
+) -> anyhow::Result<String> {
+    use base64::Engine;
+
+    let Some(algorithm) = args.checksum_algorithm else {
+        anyhow::bail!("No --checksum-algorithm to verify");
+    };
+    if let Some(x) = full_object {
+        return Ok(format!("{}:{x}", algorithm.name()));
+    }
+    let mut parts: Vec<_> = parts.iter().collect();
+    parts.sort_by_key(|x| x.part_number);
+    let mut hasher = algorithm.hasher();
+    for part in &parts {
+        let number = part.part_number.unwrap_or_default();
+        let Some(value) = algorithm.of(checksums!(part)) else {
+            anyhow::bail!("Part {number} has no {} checksum", algorithm.name());
+        };
+        let value = value.split_once(':').map_or("", |x| x.1);
+        match base64::engine::general_purpose::STANDARD.decode(value) {
+            Ok(x) => hasher.update(&x),
+            Err(e) => anyhow::bail!("Part {number} has an invalid checksum '{value}': {e}"),
+        }
+    }
+    let value = base64::engine::general_purpose::STANDARD.encode(hasher.finalize());
+    Ok(format!("{}:{value}-{}", algorithm.name(), parts.len()))
+}
+
+/// A verification found content different from the upload, rather than failing to read it
+#[derive(Debug)]
+struct Mismatch(String);
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// Runs a verification of the uploaded object up to --verify-retries more times until it passes
+///
+/// Reads right after the upload can see a missing or stale object on some S3-compatible backends.
+async fn verify_with_retries<F: std::future::Future<Output = anyhow::Result<()>>>(
+    args: &Args,
+    mut verify: impl FnMut() -> F,
+) -> anyhow::Result<()> {
+    let mut retries = 0;
+    loop {
+        let e = match verify().await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        if retries == args.verify_retries {
+            match (e.is::<Mismatch>(), retries) {
+                (_, 0) => return Err(e),
+                (true, _) => anyhow::bail!("{e}, also after {retries} retries"),
+                (false, _) => anyhow::bail!("Object not readable after {retries} retries: {e}"),
+            }
+        }
+        let backoff = Duration::from_millis(500 << retries.min(6));
+        retries += 1;
+        tracing::info!(
+            "Verification failed, the object may not be visible yet, retrying in {:.1}s: {e}",
+            backoff.as_secs_f64()
+        );
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// Checks that S3 reports the `expected` checksum for the object, see --post-verify-checksum
+async fn verify_checksum(destination: &Destination, expected: &str) -> anyhow::Result<()> {
+    let Some(algorithm) = expected.split_once(':').map(|x| x.0) else {
+        anyhow::bail!("Invalid expected checksum '{expected}'");
+    };
+    let response = match s3_client(&destination.config())
+        .head_object()
+        .bucket(&destination.bucket)
+        .key(&destination.key)
+        .with_sse_c(destination.sse_customer_key.as_ref())
+        .checksum_mode(s3::types::ChecksumMode::Enabled)
+        .send()
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Failed to read the checksum of {destination}: {e}"),
+    };
+    let actual = [
+        ChecksumAlgorithm::Crc32,
+        ChecksumAlgorithm::Crc32c,
+        ChecksumAlgorithm::Crc64nvme,
+        ChecksumAlgorithm::Sha1,
+        ChecksumAlgorithm::Sha256,
+    ]
+    .into_iter()
+    .filter(|x| x.name() == algorithm)
+    .find_map(|x| x.of(checksums!(response)));
+    match actual {
+        Some(x) if x == expected => Ok(()),
+        Some(x) => Err(Mismatch(format!(
+            "{destination} has checksum {x}, expected {expected}"
+        ))
+        .into()),
+        None => anyhow::bail!("{destination} has no {algorithm} checksum, expected {expected}"),
+    }
+}
+
+/// Deletes the completed object of an upload whose checksum does not match, unless
+/// --keep-on-failure, as it can no longer be aborted
+async fn delete_mismatched(args: &Args, destination: &Destination, result: &UploadResult) {
+    if args.keep_on_failure {
+        tracing::warn!("Keeping {destination} with the wrong checksum");
+        return;
+    }
+    let deleted = s3_client(&destination.config())
+        .delete_object()
+        .bucket(&destination.bucket)
+        .key(&destination.key)
+        .set_version_id(result.version_id.clone())
+        .send()
+        .await;
+    match deleted {
+        Ok(_) => tracing::error!("Deleted {destination} with the wrong checksum"),
+        Err(e) => tracing::error!("Failed to delete {destination} with the wrong checksum: {e}"),
+    }
+}
+
+async fn abort_upload(destination: &Destination) {
+    let client = s3_client(&destination.config());
+    if let Err(e) = client
+        .abort_multipart_upload()
+        .bucket(&destination.bucket)
+        .key(&destination.key)
+        .upload_id(&destination.upload_id)
+        .send()
+        .await
+    {
+        tracing::error!(
+            upload_id = destination.upload_id,
+            "Failed to abort upload to {destination}: {e}"
+        );
+    }
+}
+
+/// Lists the unfinished multipart uploads to exactly `key`, oldest first
+async fn existing_uploads(
+    bucket: &str,
+    key: &str,
+    config: &aws_config::SdkConfig,
+) -> anyhow::Result<Vec<s3::types::MultipartUpload>> {
+    let mut uploads = uploads_below(bucket, key, config).await?;
+    uploads.retain(|x| x.key() == Some(key));
+    Ok(uploads)
+}
+
+/// Lists the unfinished multipart uploads to keys starting with `prefix`, oldest first
+async fn uploads_below(
+    bucket: &str,
+    prefix: &str,
+    config: &aws_config::SdkConfig,
+) -> anyhow::Result<Vec<s3::types::MultipartUpload>> {
+    let client = s3_client(config);
+    let mut uploads = Vec::new();
+    let mut markers: (Option<String>, Option<String>) = (None, None);
+    loop {
+        let response = client
+            .list_multipart_uploads()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_key_marker(markers.0.clone())
+            .set_upload_id_marker(markers.1.clone())
+            .send()
+            .await?;
+        uploads.extend(response.uploads().iter().cloned());
+        if !response.is_truncated.unwrap_or_default() {
+            break;
+        }
+        let next = (response.next_key_marker, response.next_upload_id_marker);
+        if next == markers || next == (None, None) {
+            anyhow::bail!(
+                "s3://{bucket}/{prefix}: upload listing is truncated without a next marker"
+            );
+        }
+        markers = next;
+    }
+    uploads.sort_by_key(|x| x.initiated);
+    Ok(uploads)
+}
+
+/// Part of an existing upload to complete it with, keeping the checksums of --checksum-algorithm
+fn completed_part(part: &s3::types::Part) -> CompletedPart {
+    CompletedPart::builder()
+        .set_e_tag(part.e_tag.clone())
+        .set_part_number(part.part_number)
+        .set_checksum_crc32(part.checksum_crc32.clone())
+        .set_checksum_crc32_c(part.checksum_crc32_c.clone())
+        .set_checksum_crc64_nvme(part.checksum_crc64_nvme.clone())
+        .set_checksum_sha1(part.checksum_sha1.clone())
+        .set_checksum_sha256(part.checksum_sha256.clone())
+        .build()
+}
+
+/// Returns the parts of an existing upload that have the same content as the input file, by
+/// comparing their ETags with the MD5 of the same range of the file
+///
+/// The file is read on the blocking pool, as this can read all of it.
+async fn matching_parts(
+    input: &std::path::Path,
+    block_size: usize,
+    parts: &[s3::types::Part],
+) -> std::io::Result<Vec<CompletedPart>> {
+    use md5::Md5;
+    use std::io::Seek;
+
+    let (input, parts) = (input.to_path_buf(), parts.to_vec());
+    let matching = tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(input)?;
+        let mut buffer = vec![0_u8; block_size];
+        let mut matching = Vec::new();
+        for part in &parts {
+            let (Some(number), Some(etag)) = (part.part_number, part.e_tag()) else {
+                continue;
+            };
+            file.seek(std::io::SeekFrom::Start(
+                (number.max(1) as u64 - 1) * block_size as u64,
+            ))?;
+            let (size, _) = read_part(&mut file, &mut buffer)?;
+            let same_size = part.size.is_some_and(|x| x as usize == size);
+            if same_size && format!("{:x}", Md5::digest(&buffer[..size])) == etag.trim_matches('"')
+            {
+                matching.push(completed_part(part));
+            }
+        }
+        Ok(matching)
+    });
+    matching.await?
+}
+
+/// Handles the unfinished uploads to a destination according to --on-existing-upload, returning
+/// the upload to continue if any
+async fn handle_existing_uploads(
+    args: &Args,
+    bucket: &str,
+    key: &str,
+    config: &aws_config::SdkConfig,
+    profile: &Option<String>,
+    sse_customer_key: Option<&SseCustomerKey>,
+) -> anyhow::Result<Option<Destination>> {
+    if let Some(upload_id) = &args.resume {
+        let destination = Destination {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            profile: profile.clone(),
+            config: Arc::new(std::sync::RwLock::new((0, config.clone()))),
+            upload_id: upload_id.clone(),
+            sse_customer_key: sse_customer_key.cloned(),
+            completed_parts: Vec::new(),
+        };
+        return resume_upload(args, destination).await.map(Some);
+    }
+    if args.on_existing_upload == ExistingUpload::New {
+        return Ok(None);
+    }
+    let uploads = match existing_uploads(bucket, key, config).await {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::warn!("Cannot list existing uploads to s3://{bucket}/{key}: {e}");
+            return Ok(None);
+        }
+    };
+    let mut destinations = uploads.iter().filter_map(|x| {
+        Some(Destination {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            profile: profile.clone(),
+            config: Arc::new(std::sync::RwLock::new((0, config.clone()))),
+            upload_id: x.upload_id.clone()?,
+            sse_customer_key: sse_customer_key.cloned(),
+            completed_parts: Vec::new(),
+        })
+    });
+    if args.on_existing_upload == ExistingUpload::AbortRestart {
+        for destination in destinations {
+            tracing::info!(
+                upload_id = destination.upload_id,
+                "Aborting existing upload to {destination}"
+            );
+            abort_upload(&destination).await;
+        }
+        return Ok(None);
+    }
+
+    let Some(mut destination) = destinations.next_back() else {
+        tracing::info!("No existing upload to s3://{bucket}/{key}, starting a new one");
+        return Ok(None);
+    };
+    let input = match &args.input {
+        Some(x) if std::fs::metadata(x).is_ok_and(|x| x.is_file()) => x,
+        _ => anyhow::bail!("--on-existing-upload resume needs a regular file as input"),
+    };
+    let parts = match list_parts(&destination).await {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::warn!(
+                "Cannot resume upload {} to {destination}, starting a new one: {e}",
+                destination.upload_id
+            );
+            return Ok(None);
+        }
+    };
+    destination.completed_parts = match matching_parts(input, args.block_size, &parts).await {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Failed to read input file: {e}"),
+    };
+    tracing::info!(
+        upload_id = destination.upload_id,
+        "Resuming existing upload to {destination}, {} of its {} parts match the input",
+        destination.completed_parts.len(),
+        parts.len()
+    );
+    Ok(Some(destination))
+}
+
+/// Continues the upload of --resume, reusing the parts that match the input
+async fn resume_upload(args: &Args, mut destination: Destination) -> anyhow::Result<Destination> {
+    let parts = match list_parts(&destination).await {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!(
+            "Cannot resume upload {} to {destination}: {e}",
+            destination.upload_id
+        ),
+    };
+    let input = (args.input.as_deref()).filter(|x| std::fs::metadata(x).is_ok_and(|x| x.is_file()));
+    // with SSE-C and SSE-KMS, ETags are not the MD5 of the content
+    let encrypted = destination.sse_customer_key.is_some() || args.sse_kms;
+    match input {
+        Some(input) if !encrypted => {
+            destination.completed_parts = match matching_parts(input, args.block_size, &parts).await
+            {
+                Ok(x) => x,
+                Err(e) => anyhow::bail!("Failed to read input file: {e}"),
+            };
+        }
+        _ => {
+            // the last part of the input may be shorter, any other one means another part size
+            let full_parts = &parts[..parts.len().saturating_sub(1)];
+            if (full_parts.iter()).any(|x| x.size.is_some_and(|x| x as usize != args.block_size)) {
+                anyhow::bail!(
+                    "Upload {} to {destination} has parts of another size than --block-size",
+                    destination.upload_id
+                );
+            }
+            destination.completed_parts = parts.iter().map(completed_part).collect();
+        }
+    }
+    tracing::info!(
+        upload_id = destination.upload_id,
+        "Resuming upload to {destination}, reusing {} of its {} parts",
+        destination.completed_parts.len(),
+        parts.len()
+    );
+    Ok(destination)
+}
+
+/// Placeholders of --success-format
+fn success_placeholders(
+    args: &Args,
+    bucket: &str,
+    key: &str,
+    result: &UploadResult,
+    duration: Duration,
+) -> Vec<(&'static str, Result<String, String>)> {
+    let mut placeholders = vec![
+        ("bucket", Ok(bucket.to_string())),
+        ("key", Ok(key.to_string())),
+        ("etag", Ok(result.etag.trim_matches('"').to_string())),
+        (
+            "version_id",
+            Ok(result.version_id.clone().unwrap_or_default()),
+        ),
+        ("checksum", Ok(result.checksum.clone().unwrap_or_default())),
+        ("bytes", Ok(result.bytes.to_string())),
+        ("size", Ok(format_size(result.bytes))),
+        ("duration", Ok(format!("{:.1}", duration.as_secs_f64()))),
+    ];
+    placeholders.extend(key_placeholders(args.input.as_deref()));
+    placeholders
+}
+
+async fn run_hook(
+    command: &str,
+    bucket: &str,
+    key: &str,
+    result: &UploadResult,
+) -> std::io::Result<std::process::ExitStatus> {
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = tokio::process::Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = tokio::process::Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    cmd.arg(command)
+        .env("S3PUT_BUCKET", bucket)
+        .env("S3PUT_KEY", key)
+        .env("S3PUT_ETAG", result.etag.trim_matches('"'))
+        .env("S3PUT_BYTES", result.bytes.to_string());
+    if let Some(version_id) = &result.version_id {
+        cmd.env("S3PUT_VERSION_ID", version_id);
+    }
+    cmd.status().await
+}
+
+/// Formats a result like the AWS CLI does for `complete-multipart-upload`
+fn aws_json(
+    bucket: &str,
+    key: &str,
+    result: &UploadResult,
+    presigned_url: Option<&str>,
+) -> serde_json::Value {
+    let mut value = serde_json::Map::new();
+    if let Some(location) = &result.location {
+        value.insert("Location".into(), location.as_str().into());
+    }
+    value.insert("Bucket".into(), bucket.into());
+    value.insert("Key".into(), key.into());
+    value.insert("ETag".into(), result.etag.as_str().into());
+    if let Some(version_id) = &result.version_id {
+        value.insert("VersionId".into(), version_id.as_str().into());
+    }
+    if let Some(url) = presigned_url {
+        value.insert("PresignedUrl".into(), url.into());
+    }
+    value.into()
+}
+
+/// Results of --output aws-json while a --manifest is uploaded, printed as one array at the end
+static MANIFEST_RESULTS: std::sync::Mutex<Option<Vec<serde_json::Value>>> =
+    std::sync::Mutex::new(None);
+
+/// Prints a result of --output aws-json, or collects it for the array of a --manifest
+fn print_json(value: serde_json::Value) -> anyhow::Result<()> {
+    if let Some(results) = MANIFEST_RESULTS.lock().unwrap().as_mut() {
+        results.push(value);
+        return Ok(());
+    }
+    println!("{}", pretty_json(&value)?);
+    Ok(())
+}
+
+/// Pretty prints JSON with the indentation of the AWS CLI
+fn pretty_json(value: &impl serde::Serialize) -> anyhow::Result<String> {
+    let mut output = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut serializer = serde_json::Serializer::with_formatter(&mut output, formatter);
+    value.serialize(&mut serializer)?;
+    Ok(String::from_utf8(output)?)
+}
+
+/// Formats the reason of an aborted upload, in the shape of the `Error` of AWS responses
+fn aws_json_error(
+    reason: FailureReason,
+    part_number: Option<i32>,
+    message: &str,
+) -> serde_json::Value {
+    let mut error = serde_json::Map::new();
+    error.insert("Code".into(), reason.to_string().into());
+    if let Some(part_number) = part_number {
+        error.insert("PartNumber".into(), part_number.into());
+    }
+    error.insert("Message".into(), message.into());
+    serde_json::json!({ "Error": error })
+}
+
+/// Hex SHA-256 of a file
+fn hash_file(path: &std::path::Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Writes a line with the SHA-256 of the input for each uploaded key
+fn write_checksum(
+    path: &std::path::Path,
+    format: ChecksumFormat,
+    digest: &str,
+    keys: &[impl AsRef<str>],
+) -> anyhow::Result<()> {
+    let mut content = String::new();
+    for key in keys {
+        let key = key.as_ref();
+        match format {
+            ChecksumFormat::Gnu => content += &format!("{digest}  {key}\n"),
+            ChecksumFormat::Bsd => content += &format!("SHA256 ({key}) = {digest}\n"),
+        }
+    }
+    if let Err(e) = std::fs::write(path, content) {
+        anyhow::bail!("Failed to write checksum file: {e}");
+    }
+    Ok(())
+}
+
+/// Writes the ETags to the --etag-file, one line each
+fn write_etag_file(path: &std::path::Path, etags: &[String]) -> anyhow::Result<()> {
+    let content: String = etags
+        .iter()
+        .map(|x| format!("{}\n", x.trim_matches('"')))
+        .collect();
+    if let Err(e) = std::fs::write(path, content) {
+        anyhow::bail!("Failed to write ETag file: {e}");
+    }
+    Ok(())
+}
+
+/// Longest validity of a SigV4 presigned URL
+const MAX_PRESIGN_EXPIRES: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A GET URL for the object that is valid for `expires` without credentials
+async fn presigned_url(
+    config: &aws_config::SdkConfig,
+    bucket: &str,
+    key: &str,
+    expires: Duration,
+) -> anyhow::Result<String> {
+    let presigning = s3::presigning::PresigningConfig::expires_in(expires)?;
+    let client = s3_client(config);
+    let request = client.get_object().bucket(bucket).key(key);
+    Ok(request.presigned(presigning).await?.uri().to_string())
+}
+
+/// Prints the result of an object that was uploaded successfully, and runs the --on-success hook
+async fn on_success(
+    args: &Args,
+    config: &aws_config::SdkConfig,
+    bucket: &str,
+    key: &str,
+    result: &UploadResult,
+    start: std::time::Instant,
+) -> anyhow::Result<()> {
+    send_webhook(
+        args,
+        webhook_payload(bucket, key, Some(result), start, None),
+    )
+    .await;
+    let url = match args.print_presigned_url {
+        true => match presigned_url(config, bucket, key, args.presign_expires).await {
+            Ok(x) => Some(x),
+            Err(e) => anyhow::bail!("Failed to presign URL for s3://{bucket}/{key}: {e}"),
+        },
+        false => None,
+    };
+    match (args.output, &url) {
+        (OutputFormat::AwsJson, _) => print_json(aws_json(bucket, key, result, url.as_deref()))?,
+        (OutputFormat::Json, _) => record_result(
+            args,
+            FileResult {
+                etag: Some(result.etag.clone()),
+                version_id: result.version_id.clone(),
+                bytes: result.bytes,
+                parts: result.parts,
+                retries: result.retries,
+                duration: start.elapsed().as_secs_f64(),
+                ..FileResult::new(args, bucket, key, FileStatus::Uploaded)
+            },
+        ),
+        (OutputFormat::Text, Some(url)) => println!("{url}"),
+        (OutputFormat::Text, None) => (),
+    }
+    if !args.quiet {
+        let placeholders = success_placeholders(args, bucket, key, result, start.elapsed());
+        match &args.success_format {
+            Some(format) => status!("{}", expand_placeholders(format, &placeholders)?),
+            None => {
+                let version = match &result.version_id {
+                    Some(x) => format!(", version={x}"),
+                    None => String::new(),
+                };
+                let checksum = match &result.checksum {
+                    Some(x) => format!(", checksum={x}"),
+                    None => String::new(),
+                };
+                status!(
+                    "Uploaded s3://{bucket}/{key} ({}, etag={}{version}{checksum})",
+                    format_size(result.bytes),
+                    result.etag.trim_matches('"')
+                );
+            }
+        }
+    }
+    if let Some(command) = &args.on_success {
+        match run_hook(command, bucket, key, result).await {
+            Err(e) => {
+                if args.strict_hook {
+                    anyhow::bail!("Failed to run --on-success command: {e}");
+                }
+                tracing::warn!("Failed to run --on-success command: {e}");
+            }
+            Ok(status) if !status.success() => {
+                if args.strict_hook {
+                    exit(status.code().unwrap_or(1));
+                }
+                tracing::warn!("--on-success command failed: {status}");
+            }
+            Ok(_) => (),
+        }
+    }
+    Ok(())
+}
+
+async fn print_summary(
+    args: &Args,
+    bytes: usize,
+    num_parts: usize,
+    retries: u32,
+    start: std::time::Instant,
+) {
+    if !args.quiet {
+        let elapsed = start.elapsed().as_secs_f64();
+        status!(
+            "uploaded {} in {} parts, {elapsed:.1}s ({}/s), {} retries",
+            format_size(bytes),
+            num_parts,
+            format_size((bytes as f64 / elapsed) as usize),
+            retries
+        );
+    }
+    let metrics = Metrics {
+        bytes,
+        parts: num_parts,
+        retries,
+        duration: start.elapsed(),
+        failed: false,
+    };
+    push_metrics(args, metrics).await;
+}
+
+#[derive(Clone)]
+enum MetricsEndpoint {
+    Statsd(String),
+    Pushgateway(http::Uri),
+}
+
+fn parse_metrics_endpoint(x: &str) -> anyhow::Result<MetricsEndpoint> {
+    if let Some(address) = x.strip_prefix("statsd://") {
+        return Ok(MetricsEndpoint::Statsd(
+            address.trim_end_matches('/').to_string(),
+        ));
+    }
+    let uri = http::Uri::from_str(x)?;
+    if uri.scheme_str() != Some("http") || uri.host().is_none() {
+        anyhow::bail!("Metrics endpoint should be 'statsd://host:port' or 'http://host:port'");
+    }
+    Ok(MetricsEndpoint::Pushgateway(uri))
+}
+
+/// Statistics of one upload for --metrics-endpoint
+struct Metrics {
+    bytes: usize,
+    parts: usize,
+    retries: u32,
+    duration: Duration,
+    failed: bool,
+}
+
+impl Metrics {
+    fn failed(start: std::time::Instant) -> Self {
+        Self {
+            bytes: 0,
+            parts: 0,
+            retries: 0,
+            duration: start.elapsed(),
+            failed: true,
+        }
+    }
+}
+
+/// How long pushing metrics or a webhook may take, so an unreachable endpoint does not hold up
+/// the upload
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pushes the metrics to the --metrics-endpoint, if any, only warning on failure
+async fn push_metrics(args: &Args, metrics: Metrics) {
+    let Some(endpoint) = args.metrics_endpoint.clone() else {
+        return;
+    };
+    let pushed = blocking_io(move || match &endpoint {
+        MetricsEndpoint::Statsd(address) => push_statsd(address, &metrics),
+        MetricsEndpoint::Pushgateway(uri) => push_pushgateway(uri, &metrics),
+    });
+    if let Err(e) = pushed.await {
+        tracing::warn!("Failed to push metrics: {e}");
+    }
+}
+
+/// Runs the socket I/O of metrics and webhooks on the blocking pool, as it blocks, which must not
+/// hold up a worker thread of the part uploads
+async fn blocking_io(
+    io: impl FnOnce() -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<()> {
+    match tokio::task::spawn_blocking(io).await {
+        Ok(x) => x,
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn push_statsd(address: &str, metrics: &Metrics) -> anyhow::Result<()> {
+    let lines = [
+        format!("s3put.bytes:{}|c", metrics.bytes),
+        format!("s3put.parts:{}|c", metrics.parts),
+        format!("s3put.retries:{}|c", metrics.retries),
+        format!("s3put.failures:{}|c", u8::from(metrics.failed)),
+        format!("s3put.duration:{}|ms", metrics.duration.as_millis()),
+    ];
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    socket.send_to(lines.join("\n").as_bytes(), address)?;
+    Ok(())
+}
+
+fn push_pushgateway(uri: &http::Uri, metrics: &Metrics) -> anyhow::Result<()> {
+    let mut body = String::new();
+    for (name, kind, value) in [
+        ("s3put_bytes", "gauge", metrics.bytes.to_string()),
+        ("s3put_parts", "gauge", metrics.parts.to_string()),
+        ("s3put_retries", "gauge", metrics.retries.to_string()),
+        (
+            "s3put_failed",
+            "gauge",
+            u8::from(metrics.failed).to_string(),
+        ),
+        (
+            "s3put_duration_seconds",
+            "gauge",
+            metrics.duration.as_secs_f64().to_string(),
+        ),
+    ] {
+        body += &format!("# TYPE {name} {kind}\n{name} {value}\n");
+    }
+    let path = match uri.path() {
+        x if x.contains("/metrics/job/") => x.to_string(),
+        x => format!("{}/metrics/job/s3put", x.trim_end_matches('/')),
+    };
+    http_request(uri, "PUT", &path, &[("Content-Type", "text/plain")], &body)
+}
+
+/// Sends a request over plain HTTP/1.1, failing unless the response has a 2xx status
+fn http_request(
+    uri: &http::Uri,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> anyhow::Result<()> {
+    use std::io::Write;
+    use std::net::ToSocketAddrs;
+
+    let host = uri.host().unwrap_or_default();
+    let port = uri.port_u16().unwrap_or(80);
+    let address = match (host, port).to_socket_addrs()?.next() {
+        Some(x) => x,
+        None => anyhow::bail!("Cannot resolve {host}"),
+    };
+    let mut stream = std::net::TcpStream::connect_timeout(&address, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let mut request = format!("{method} {path} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+    for (name, value) in headers {
+        request += &format!("{name}: {value}\r\n");
+    }
+    request += &format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        anyhow::bail!(
+            "{host} responded with '{}'",
+            response.lines().next().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+fn parse_input_url(x: &str) -> anyhow::Result<String> {
+    if !x.starts_with("http://") && !x.starts_with("https://") {
+        anyhow::bail!("Input URL should start with 'http://' or 'https://'");
+    }
+    Ok(x.to_string())
+}
+
+/// Requests the --input-url, returning its body and its length if known
+fn open_url(url: &str) -> anyhow::Result<(Box<dyn std::io::Read + Send + Sync>, Option<usize>)> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(HTTP_TIMEOUT)
+        .build();
+    let response = match agent.get(url).call() {
+        Ok(x) => x,
+        Err(ureq::Error::Status(status, response)) => {
+            anyhow::bail!("{url} responded with {status} {}", response.status_text())
+        }
+        Err(e) => anyhow::bail!("Failed to request {url}: {e}"),
+    };
+    if response.get_url() != url {
+        tracing::info!("Redirected to {}", response.get_url());
+    }
+    let length = response
+        .header("content-length")
+        .and_then(|x| x.parse().ok());
+    Ok((response.into_reader(), length))
+}
+
+fn parse_webhook_url(x: &str) -> anyhow::Result<http::Uri> {
+    let uri = http::Uri::from_str(x)?;
+    if uri.scheme_str() != Some("http") || uri.host().is_none() {
+        anyhow::bail!("Webhook URL should be 'http://host[:port]/path'");
+    }
+    Ok(uri)
+}
+
+/// How often delivering a webhook is attempted
+const WEBHOOK_ATTEMPTS: u32 = 3;
+
+/// POSTs the payload to the --webhook-url, if any, only warning on failure
+async fn send_webhook(args: &Args, payload: serde_json::Value) {
+    use hmac::Mac;
+
+    let Some(uri) = &args.webhook_url else {
+        return;
+    };
+    let body = payload.to_string();
+    let signature = args.webhook_secret.as_ref().map(|secret| {
+        // HMAC accepts keys of any length
+        let mut mac = hmac::Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        format!("sha256={:x}", mac.finalize().into_bytes())
+    });
+    let mut headers = vec![("Content-Type", "application/json")];
+    if let Some(signature) = &signature {
+        headers.push(("X-S3put-Signature", signature));
+    }
+    let path = uri.path_and_query().map_or("/", |x| x.as_str());
+    let headers: Vec<_> = headers.iter().map(|(x, y)| (*x, y.to_string())).collect();
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let (uri, path, headers, body) =
+            (uri.clone(), path.to_string(), headers.clone(), body.clone());
+        let sent = blocking_io(move || {
+            let headers: Vec<_> = headers.iter().map(|(x, y)| (*x, y.as_str())).collect();
+            http_request(&uri, "POST", &path, &headers, &body)
+        });
+        match sent.await {
+            Ok(()) => return,
+            Err(e) if attempt < WEBHOOK_ATTEMPTS => {
+                tracing::info!("Failed to deliver webhook, retrying: {e}");
+                tokio::time::sleep(Duration::from_secs(attempt.into())).await;
+            }
+            Err(e) => tracing::warn!("Failed to deliver webhook: {e}"),
+        }
+    }
+}
+
+fn webhook_payload(
+    bucket: &str,
+    key: &str,
+    result: Option<&UploadResult>,
+    start: std::time::Instant,
+    error: Option<&anyhow::Error>,
+) -> serde_json::Value {
+    let mut value = serde_json::Map::new();
+    value.insert("bucket".into(), bucket.into());
+    value.insert("key".into(), key.into());
+    value.insert(
+        "etag".into(),
+        result.map(|x| x.etag.trim_matches('"')).into(),
+    );
+    value.insert("bytes".into(), result.map_or(0, |x| x.bytes).into());
+    value.insert("duration".into(), start.elapsed().as_secs_f64().into());
+    let status = match error {
+        None => "success",
+        Some(_) => "failed",
+    };
+    value.insert("status".into(), status.into());
+    if let Some(e) = error {
+        value.insert("error".into(), e.to_string().into());
+    }
+    value.into()
+}
+
+/// Sends a failed webhook for each of the S3 paths of an upload
+async fn webhook_failure(
+    args: &Args,
+    s3_paths: &[String],
+    start: std::time::Instant,
+    e: &anyhow::Error,
+) {
+    for s3_path in s3_paths {
+        if let Ok((bucket, key)) = parse_s3_path(s3_path) {
+            send_webhook(args, webhook_payload(&bucket, &key, None, start, Some(e))).await;
+        }
+    }
+}
+
+/// Picks up to `count` distinct part numbers out of `num_parts` at random, in ascending order
+fn sample_parts(num_parts: usize, count: usize) -> Vec<usize> {
+    use std::hash::BuildHasher;
+
+    let state = std::collections::hash_map::RandomState::new();
+    let mut parts: Vec<usize> = (1..=num_parts).collect();
+    parts.sort_by_key(|x| state.hash_one(x));
+    parts.truncate(count);
+    parts.sort();
+    parts
+}
+
+/// Downloads the given parts of an uploaded object and compares them byte for byte with the input
+async fn verify_parts(
+    destination: &Destination,
+    key: &str,
+    input: &std::path::Path,
+    block_size: usize,
+    bytes: usize,
+    parts: &[usize],
+) -> anyhow::Result<()> {
+    use std::io::Seek;
+
+    let bucket = &destination.bucket;
+    let client = s3_client(&destination.config());
+    let mut file = std::fs::File::open(input)?;
+    for part in parts {
+        let start = (part - 1) * block_size;
+        let end = bytes.min(start + block_size);
+        let response = client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .range(format!("bytes={start}-{}", end - 1))
+            .with_sse_c(destination.sse_customer_key.as_ref())
+            .send()
+            .await?;
+        let remote = response.body.collect().await?.into_bytes();
+        // a stale object can be shorter, which says nothing about the upload yet
+        if remote.len() != end - start {
+            anyhow::bail!(
+                "s3://{bucket}/{key}: got {} of part {part}, expected {}",
+                format_size(remote.len()),
+                format_size(end - start)
+            );
+        }
+        // the file moves to the blocking pool for the read and back
+        let (returned, local) = tokio::task::spawn_blocking(move || {
+            let mut local = vec![0_u8; end - start];
+            file.seek(std::io::SeekFrom::Start(start as u64))?;
+            file.read_exact(&mut local)?;
+            std::io::Result::Ok((file, local))
+        })
+        .await??;
+        file = returned;
+        if remote[..] != local[..] {
+            let e = format!("s3://{bucket}/{key}: part {part} differs from the input");
+            return Err(Mismatch(e).into());
+        }
+    }
+    Ok(())
+}
+
+/// Joins a key prefix and a name, adding a separating '/' if needed
+fn join_key(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() || prefix.ends_with('/') {
+        format!("{prefix}{name}")
+    } else {
+        format!("{prefix}/{name}")
+    }
+}
+
+/// A key below `prefix` that is unique to this process
+fn temporary_key(prefix: &str) -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or_default();
+    join_key(prefix, &format!(".s3put-{}-{nanos}", std::process::id()))
+}
+
+/// Percent-encodes a key for use in the copy source header, keeping '/' intact
+fn encode_key(key: &str) -> String {
+    let mut result = String::new();
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~/".contains(&byte) {
+            result.push(byte as char);
+        } else {
+            result.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    result
+}
+
+/// Splits the condition of --finalize-if-match into the If-Match and If-None-Match headers
+fn copy_condition(if_match: Option<&str>) -> (Option<String>, Option<String>) {
+    match if_match {
+        None => (None, None),
+        Some("none" | "*") => (None, Some("*".into())),
+        Some(x) => (Some(x.into()), None),
+    }
+}
+
+/// Copies the object of `destination` server-side to `key`, returning the new ETag and version
+///
+/// Objects above the 5GB limit of `copy_object` are copied with a multipart copy. With
+/// `if_match` the copy only succeeds if the object at `key` matches it, see --finalize-if-match.
+async fn copy_object(
+    destination: &Destination,
+    key: &str,
+    size: usize,
+    options: &ObjectOptions,
+    if_match: Option<&str>,
+) -> anyhow::Result<(String, Option<String>)> {
+    let (bucket, source_key) = (&destination.bucket, &destination.key);
+    let sse_customer_key = destination.sse_customer_key.as_ref();
+    let (if_match, if_none_match) = copy_condition(if_match);
+    let client = s3_client(&destination.config());
+    let copy_source = format!("{bucket}/{}", encode_key(source_key));
+    if size as u64 <= MAX_PUT_SIZE {
+        let response = client
+            .copy_object()
+            .bucket(bucket)
+            .key(key)
+            .copy_source(copy_source)
+            .set_copy_source_sse_customer_algorithm(
+                sse_customer_key.map(|_| SSE_C_ALGORITHM.into()),
+            )
+            .set_copy_source_sse_customer_key(sse_customer_key.map(|x| x.key.clone()))
+            .set_copy_source_sse_customer_key_md5(sse_customer_key.map(|x| x.key_md5.clone()))
+            .with_sse_c(sse_customer_key)
+            .with_object_options(options)
+            .set_if_match(if_match)
+            .set_if_none_match(if_none_match)
+            .send()
+            .await
+            .map_err(|e| match is_precondition_failed(&e) {
+                true => precondition_failed(bucket, key),
+                false => e.into(),
+            })?;
+        let etag = response.copy_object_result.and_then(|x| x.e_tag);
+        return Ok((etag.unwrap_or_default(), response.version_id));
+    }
+
+    let upload_id = match client
+        .create_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .with_sse_c(sse_customer_key)
+        .with_object_options(options)
+        .send()
+        .await?
+        .upload_id
+    {
+        None => anyhow::bail!("Could not get upload_id"),
+        Some(x) => x,
+    };
+    let mut parts = Vec::new();
+    let mut result: anyhow::Result<()> = Ok(());
+    for (index, begin) in (0..size).step_by(MAX_PUT_SIZE as usize).enumerate() {
+        let end = (begin + MAX_PUT_SIZE as usize).min(size) - 1;
+        let part_number = index as i32 + 1;
+        match client
+            .upload_part_copy()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .part_number(part_number)
+            .copy_source(&copy_source)
+            .copy_source_range(format!("bytes={begin}-{end}"))
+            .set_copy_source_sse_customer_algorithm(
+                sse_customer_key.map(|_| SSE_C_ALGORITHM.into()),
+            )
+            .set_copy_source_sse_customer_key(sse_customer_key.map(|x| x.key.clone()))
+            .set_copy_source_sse_customer_key_md5(sse_customer_key.map(|x| x.key_md5.clone()))
+            .with_sse_c(sse_customer_key)
+            .send()
+            .await
+        {
+            Ok(response) => parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(response.copy_part_result.and_then(|x| x.e_tag))
+                    .part_number(part_number)
+                    .build(),
+            ),
+            Err(e) => {
+                result = Err(e.into());
+                break;
+            }
+        }
+    }
+    let response = match result {
+        Ok(()) => client
+            .complete_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .with_sse_c(sse_customer_key)
+            .set_if_match(if_match)
+            .set_if_none_match(if_none_match)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| match is_precondition_failed(&e) {
+                true => precondition_failed(bucket, key),
+                false => e.into(),
+            }),
+        Err(e) => Err(e),
+    };
+    match response {
+        Ok(response) => Ok((response.e_tag.unwrap_or_default(), response.version_id)),
+        Err(e) => {
+            let _ = client
+                .abort_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .send()
+                .await;
+            Err(e)
+        }
+    }
+}
+
+/// Moves a completed upload from its temporary key to the final key, by copying it server-side
+async fn move_object(
+    destination: &Destination,
+    final_key: &str,
+    if_match: Option<&str>,
+    result: &mut UploadResult,
+    options: &ObjectOptions,
+) -> anyhow::Result<()> {
+    let (bucket, key) = (&destination.bucket, &destination.key);
+    let config = destination.config();
+    (result.etag, result.version_id) =
+        match copy_object(destination, final_key, result.bytes, options, if_match).await {
+            Ok(x) => x,
+            Err(e) => anyhow::bail!(
+            "Failed to copy s3://{bucket}/{key} to {final_key}, the temporary object was kept: {e}"
+        ),
+        };
+    // the location of the completed upload points to the temporary key
+    result.location = None;
+    let client = s3_client(&config);
+    if let Err(e) = client.delete_object().bucket(bucket).key(key).send().await {
+        tracing::warn!("Failed to delete temporary object s3://{bucket}/{key}: {e}");
+    }
+    Ok(())
+}
+
+/// Computes the ETag S3 reports for an upload of the file, with parts of `block_size` or with a
+/// single PUT if there is none
+///
+/// A single PUT has the hex MD5 of the content, a multipart upload the MD5 of the concatenated
+/// binary MD5s of its parts followed by `-` and the number of parts.
+fn local_etag(path: &std::path::Path, block_size: Option<usize>) -> std::io::Result<String> {
+    use md5::Md5;
+
+    let mut file = std::fs::File::open(path)?;
+    let block_size = match block_size {
+        None => {
+            let mut hasher = Md5::new();
+            std::io::copy(&mut file, &mut hasher)?;
+            return Ok(format!("\"{:x}\"", hasher.finalize()));
+        }
+        Some(x) => x,
+    };
+    let mut digests = Md5::new();
+    let mut num_parts = 0;
+    let mut buffer = vec![0_u8; block_size];
+    loop {
+        let (size, end_of_file) = read_part(&mut file, &mut buffer)?;
+        if size == 0 {
+            break;
+        }
+        digests.update(Md5::digest(&buffer[..size]));
+        num_parts += 1;
+        if end_of_file {
+            break;
+        }
+    }
+    Ok(format!("\"{:x}-{num_parts}\"", digests.finalize()))
+}
+
+/// The part size an upload of this many bytes uses, or none if it is a single PUT
+fn etag_block_size(args: &Args, size: u64) -> Option<usize> {
+    let single_put = args.no_multipart || args.multipart_threshold.is_some_and(|x| size < x as u64);
+    (!single_put).then_some(args.block_size)
+}
+
+/// Prints the ETag of each file of --checksum-only
+fn print_etags(args: &Args) -> anyhow::Result<()> {
+    for path in &args.checksum_only {
+        let size = match std::fs::metadata(path) {
+            Ok(x) if x.is_file() => x.len(),
+            Ok(_) => anyhow::bail!("{} is not a regular file", path.display()),
+            Err(e) => anyhow::bail!("Failed to open {}: {e}", path.display()),
+        };
+        match local_etag(path, etag_block_size(args, size)) {
+            Ok(etag) => println!("{}  {}", etag.trim_matches('"'), path.display()),
+            Err(e) => anyhow::bail!("Failed to read {}: {e}", path.display()),
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether the object at `s3_path` has the ETag the input would be uploaded with, for
+/// --sync
+///
+/// A missing object or one that cannot be looked up counts as changed.
+async fn in_sync(args: &Args, input: &std::path::Path, s3_path: &str) -> anyhow::Result<bool> {
+    let (bucket, mut key) = parse_s3_path(s3_path)?;
+    if args.key_template {
+        key = expand_placeholders(&key, &key_placeholders(Some(input)))?;
+    }
+    let size = match std::fs::metadata(input) {
+        Ok(x) if x.is_file() => x.len(),
+        _ => return Ok(false),
+    };
+    let sse_customer_key = args
+        .sse_c_key_file
+        .as_deref()
+        .map(SseCustomerKey::read)
+        .transpose()?;
+    let profile = args.profile.first().cloned();
+    let config = load_config(&bucket, profile.as_deref()).await?;
+    let client = s3_client(&config);
+    let head = |part_number: Option<i32>| {
+        client
+            .head_object()
+            .bucket(&bucket)
+            .key(&key)
+            .set_part_number(part_number)
+            .with_sse_c(sse_customer_key.as_ref())
+            .send()
+    };
+    let response = match head(None).await {
+        Ok(x) => x,
+        Err(e) => {
+            if !e.as_service_error().is_some_and(|x| x.is_not_found()) {
+                tracing::warn!("Failed to look up s3://{bucket}/{key}, uploading it: {e}");
+            }
+            return Ok(false);
+        }
+    };
+    let Some(remote) = response.e_tag else {
+        return Ok(false);
+    };
+    if response.content_length != Some(size as i64) {
+        return Ok(false);
+    }
+    // the size of the first part is the part size the object was uploaded with
+    let block_size = match remote.contains('-') {
+        false => None,
+        true => match head(Some(1)).await {
+            Ok(part) => match part.content_length {
+                Some(x) if x > 0 => Some(x as usize),
+                _ => return Ok(false),
+            },
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to look up the parts of s3://{bucket}/{key}, uploading it: {e}"
+                );
+                return Ok(false);
+            }
+        },
+    };
+    if block_size.is_some_and(|x| x != args.block_size) {
+        tracing::debug!(
+            bucket,
+            key,
+            block_size,
+            "Existing object has a different part size"
+        );
+    }
+    let input = input.to_path_buf();
+    let local = tokio::task::spawn_blocking(move || local_etag(&input, block_size));
+    Ok(local.await?? == remote)
+}
+
+/// What --append found at a destination
+enum Existing {
+    /// No object, or one that was copied into these parts
+    Parts(Vec<CompletedPart>),
+    /// An object too small to be copied as part, which has to be uploaded again
+    Content(bytes::Bytes),
+}
+
+/// Copies the existing object at the destination into the first parts of its upload, for
+/// --append
+///
+/// Each copied part has to have the minimum part size, so the object is split evenly into as
+/// few parts of at most 5GB as possible.
+async fn copy_existing(destination: &Destination) -> anyhow::Result<Existing> {
+    let (bucket, key) = (&destination.bucket, &destination.key);
+    let sse_customer_key = destination.sse_customer_key.as_ref();
+    let client = s3_client(&destination.config());
+    let head = match client
+        .head_object()
+        .bucket(bucket)
+        .key(key)
+        .with_sse_c(sse_customer_key)
+        .send()
+        .await
+    {
+        Ok(x) => x,
+        Err(e) if e.as_service_error().is_some_and(|x| x.is_not_found()) => {
+            tracing::info!("No object at {destination} yet, creating it");
+            return Ok(Existing::Parts(Vec::new()));
+        }
+        Err(e) => anyhow::bail!(
+            "Failed to look up the object to append to at {destination}: {e}{}",
+            request_ids(&e)
+        ),
+    };
+    let size = head.content_length.unwrap_or_default().max(0) as u64;
+    if size < MIN_PART_SIZE as u64 {
+        let response = match client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .set_if_match(head.e_tag)
+            .with_sse_c(sse_customer_key)
+            .send()
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => anyhow::bail!(
+                "Failed to download the object to append to at {destination}: {e}{}",
+                request_ids(&e)
+            ),
+        };
+        let data = match response.body.collect().await {
+            Ok(x) => x.into_bytes(),
+            Err(e) => anyhow::bail!("Failed to download the object to append to: {e}"),
+        };
+        tracing::info!(
+            "Appending to {} at {destination}, which is uploaded again",
+            format_size(data.len())
+        );
+        return Ok(Existing::Content(data));
+    }
+
+    let num_parts = size.div_ceil(MAX_PUT_SIZE);
+    let part_size = size.div_ceil(num_parts);
+    let copy_source = format!("{bucket}/{}", encode_key(key));
+    let mut parts = Vec::new();
+    for (index, begin) in (0..size).step_by(part_size as usize).enumerate() {
+        let end = (begin + part_size).min(size) - 1;
+        let part_number = index as i32 + 1;
+        let response = match client
+            .upload_part_copy()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(&destination.upload_id)
+            .part_number(part_number)
+            .copy_source(&copy_source)
+            .copy_source_range(format!("bytes={begin}-{end}"))
+            .set_copy_source_if_match(head.e_tag.clone())
+            .set_copy_source_sse_customer_algorithm(
+                sse_customer_key.map(|_| SSE_C_ALGORITHM.into()),
+            )
+            .set_copy_source_sse_customer_key(sse_customer_key.map(|x| x.key.clone()))
+            .set_copy_source_sse_customer_key_md5(sse_customer_key.map(|x| x.key_md5.clone()))
+            .with_sse_c(sse_customer_key)
+            .send()
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => anyhow::bail!(
+                "Failed to copy part {part_number} of the object to append to at {destination}: \
+                {e}{}",
+                request_ids(&e)
+            ),
+        };
+        parts.push(
+            CompletedPart::builder()
+                .set_e_tag(response.copy_part_result.and_then(|x| x.e_tag))
+                .part_number(part_number)
+                .build(),
+        );
+    }
+    tracing::info!(
+        "Appending to {} at {destination}, copied into {num_parts} parts",
+        format_size(size as usize)
+    );
+    Ok(Existing::Parts(parts))
+}
+
+/// Returns the ETag of an existing object, or `None` if there is none or it cannot be read
+async fn remote_etag(
+    bucket: &str,
+    key: &str,
+    config: aws_config::SdkConfig,
+    sse_customer_key: Option<&SseCustomerKey>,
+) -> Option<String> {
+    match s3_client(&config)
+        .head_object()
+        .bucket(bucket)
+        .key(key)
+        .with_sse_c(sse_customer_key)
+        .send()
+        .await
+    {
+        Ok(response) => response.e_tag,
+        Err(e) => {
+            if !e.as_service_error().is_some_and(|x| x.is_not_found()) {
+                tracing::warn!("Failed to look up s3://{bucket}/{key}, uploading it: {e}");
+            }
+            None
+        }
+    }
+}
+
+/// Above this size --no-multipart warns about buffering a non-seekable input in memory
+const BUFFER_WARN_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Maximum size of an object uploaded with a single PUT
+const MAX_PUT_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Body of a single PUT upload
+enum PutBody {
+    /// A regular file, streamed from disk on each attempt
+    File(PathBuf, u64),
+    /// A non-seekable input, buffered completely
+    Memory(Vec<u8>),
+}
+
+impl PutBody {
+    fn len(&self) -> usize {
+        match self {
+            PutBody::File(_, len) => *len as usize,
+            PutBody::Memory(data) => data.len(),
+        }
+    }
+}
+
+fn read_to_memory(mut input: impl std::io::Read) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    input
+        .by_ref()
+        .take(BUFFER_WARN_SIZE)
+        .read_to_end(&mut data)?;
+    if data.len() as u64 == BUFFER_WARN_SIZE {
+        tracing::warn!(
+            "Buffering more than {} of input in memory",
+            format_size(BUFFER_WARN_SIZE as usize)
+        );
+        input.read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+/// Uploads the body with a single PUT, returning the result and the number of retries
+async fn put_object(
+    bucket: &str,
+    key: &str,
+    mut config: aws_config::SdkConfig,
+    body: &PutBody,
+    retry_policy: &RetryPolicy,
+    sse_customer_key: Option<&SseCustomerKey>,
+    options: &ObjectOptions,
+) -> anyhow::Result<(UploadResult, u32, aws_config::SdkConfig)> {
+    let mut redirects = 0;
+    let mut retry_count = 0;
+    loop {
+        let client = s3_client(&config);
+        let stream = match body {
+            PutBody::File(path, _) => s3::primitives::ByteStream::from_path(path).await?,
+            PutBody::Memory(data) => data.clone().into(),
+        };
+        let _connection = connection().await;
+        match client
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .body(stream)
+            .content_length(body.len() as i64)
+            .with_sse_c(sse_customer_key)
+            .with_object_options(options)
+            .set_checksum_sha256(options.checksum_sha256.clone())
+            .set_checksum_crc32(options.checksum_crc32.clone())
+            .set_content_md5(options.content_md5.clone())
+            .set_checksum_algorithm(options.checksum_algorithm.map(ChecksumAlgorithm::sdk))
+            .customize()
+            .config_override(retry_override())
+            .send()
+            .await
+        {
+            Ok(response) => {
+                let checksum =
+                    (options.checksum_algorithm).and_then(|x| x.of(checksums!(response)));
+                let result = UploadResult {
+                    etag: response.e_tag.unwrap_or_default(),
+                    bytes: body.len(),
+                    location: None,
+                    version_id: response.version_id,
+                    checksum,
+                    parts: 1,
+                    retries: retry_count,
+                };
+                return Ok((result, retry_count, config));
+            }
+            Err(e) => {
+                tracing::debug!(bucket, key, "Request failed: {e:?}");
+                if let Some(x) = redirected_region(bucket, &e) {
+                    redirects += 1;
+                    if redirects > 3 {
+                        anyhow::bail!("Stopped following redirects after 3 hops");
+                    }
+                    tracing::info!(bucket, region = x, "Redirected to {x}");
+                    check_region(bucket, Some(&x))?;
+                    remember_region(bucket, &config, &x);
+                    config = with_region(config, x);
+                    continue;
+                }
+                if is_acl_not_supported(&e) {
+                    return Err(acl_not_supported(bucket));
+                }
+                retry_count += 1;
+                if retry_count > retry_policy.max_retries {
+                    anyhow::bail!("s3://{bucket}/{key}: {e}{}", request_ids(&e));
+                }
+                if !retry_policy.take_from_budget() {
+                    anyhow::bail!(
+                        "s3://{bucket}/{key}: retry budget exhausted: {e}{}",
+                        request_ids(&e)
+                    );
+                }
+                let backoff = Duration::from_secs(2_u64.pow(retry_count)).max(retry_after(&e));
+                tracing::warn!(
+                    bucket,
+                    key,
+                    retry = retry_count,
+                    "Upload failed (attempt {retry_count}/{}), retrying in {:.1}s: {e}{}",
+                    retry_policy.max_retries + 1,
+                    backoff.as_secs_f64(),
+                    retry_request_ids(&e)
+                );
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+/// Uploads the whole input to every destination with a single PUT each, for backends without
+/// working multipart support
+async fn put_objects(
+    args: &Args,
+    paths: Vec<(String, String)>,
+    configs: Vec<aws_config::SdkConfig>,
+    start: std::time::Instant,
+) -> anyhow::Result<()> {
+    let body = match &args.input {
+        _ if SPLIT.get().is_some() => {
+            PutBody::Memory(read_to_memory(SplitReader(SPLIT.get().unwrap()))?)
+        }
+        Some(path) => {
+            let file = match std::fs::File::open(path) {
+                Err(e) => {
+                    status!("Failed to open input file: {}", e);
+                    exit(1);
+                }
+                Ok(x) => x,
+            };
+            let metadata = file.metadata()?;
+            if metadata.is_file() {
+                PutBody::File(path.clone(), metadata.len())
+            } else {
+                PutBody::Memory(read_to_memory(file)?)
+            }
+        }
+        None => match &args.input_url {
+            Some(url) => PutBody::Memory(read_to_memory(open_url(url)?.0)?),
+            None => PutBody::Memory(read_to_memory(stdin_reader())?),
+        },
+    };
+    // only --content-addressed needs the hash before the upload, for --write-checksum a file is
+    // hashed through a second handle while it is uploaded
+    let hashing = match &body {
+        PutBody::File(path, _) if args.write_checksum.is_some() && !args.content_addressed => {
+            let path = path.clone();
+            Some(tokio::task::spawn_blocking(move || hash_file(&path)))
+        }
+        _ => None,
+    };
+    let digest = match &body {
+        _ if hashing.is_some() => None,
+        _ if !args.content_addressed && args.write_checksum.is_none() => None,
+        PutBody::File(path, _) => Some(hash_file(path)?),
+        PutBody::Memory(data) => Some(format!("{:x}", Sha256::digest(data))),
+    };
+    if body.len() == 0 && skip_empty_input(args)? {
+        for (bucket, key) in &paths {
+            record_skipped(args, bucket, key);
+        }
+        return Ok(());
+    }
+    let paths: Vec<_> = paths
+        .into_iter()
+        .map(|(bucket, key)| match &digest {
+            Some(digest) if args.content_addressed => (bucket, join_key(&key, digest)),
+            _ => (bucket, key),
+        })
+        .collect();
+    if body.len() as u64 > MAX_PUT_SIZE {
+        tracing::warn!(
+            "Input is larger than {}, the maximum size of a single PUT",
+            format_size(MAX_PUT_SIZE as usize)
+        );
+    }
+
+    tracing::info!("Uploading {} with a single PUT", format_size(body.len()));
+    let retry_policy = RetryPolicy::new(args);
+    let sse_customer_key = args
+        .sse_c_key_file
+        .as_deref()
+        .map(SseCustomerKey::read)
+        .transpose()?;
+    let options = ObjectOptions::new(args)?;
+    let results =
+        futures::future::join_all(paths.iter().zip(configs).map(|((bucket, key), config)| {
+            put_object(
+                bucket,
+                key,
+                config,
+                &body,
+                &retry_policy,
+                sse_customer_key.as_ref(),
+                &options,
+            )
+        }));
+    let results = before_deadline(results).await?;
+
+    let mut failed = false;
+    let mut retries = 0;
+    let mut uploaded_keys = Vec::new();
+    let mut etags = Vec::new();
+    for ((bucket, key), result) in paths.iter().zip(results) {
+        match result {
+            Ok((result, put_retries, config)) => {
+                retries += put_retries;
+                on_success(args, &config, bucket, key, &result, start).await?;
+                uploaded_keys.push(key.as_str());
+                etags.push(result.etag);
+            }
+            Err(e) => {
+                tracing::error!("Failed to upload to s3://{bucket}/{key}: {e}");
+                record_failure(args, bucket, key, start, &e);
+                failed = true;
+            }
+        }
+    }
+    let digest = match hashing {
+        Some(task) => match task.await? {
+            Ok(x) => Some(x),
+            Err(e) => anyhow::bail!("Failed to hash input file: {e}"),
+        },
+        None => digest,
+    };
+    if let (Some(path), Some(digest)) = (&args.write_checksum, &digest) {
+        tracing::info!("SHA-256 of the input is {digest}");
+        write_checksum(path, args.checksum_format, digest, &uploaded_keys)?;
+    }
+    if failed {
+        anyhow::bail!("Failed upload");
+    }
+    if let Some(path) = &args.etag_file {
+        write_etag_file(path, &etags)?;
+    }
+
+    print_summary(args, body.len(), 1, retries, start).await;
+    Ok(())
+}
+
+/// Number of parts read ahead of the uploads, from --read-ahead and --io-strategy
+fn read_ahead(args: &Args, num_tokens: usize) -> usize {
+    match args.io_strategy {
+        IoStrategy::Latency => args.read_ahead,
+        IoStrategy::Throughput => args.read_ahead.max(num_tokens),
+    }
+}
+
+/// Handles an empty input according to --allow-empty, returning whether to skip the upload
+fn skip_empty_input(args: &Args) -> anyhow::Result<bool> {
+    match args.allow_empty {
+        EmptyInput::Object => Ok(false),
+        EmptyInput::Skip => {
+            if !args.quiet {
+                status!("skipped upload, the input is empty");
+            }
+            Ok(true)
+        }
+        EmptyInput::Error => {
+            anyhow::bail!("The input is empty, pass --allow-empty object to upload an empty object")
+        }
+    }
+}
+
+/// Smallest size S3 accepts for all but the last part
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Largest size S3 accepts for a single part
+const MAX_PART_SIZE: usize = 5 * 1024 * 1024 * 1024;
+
+/// Largest object S3 accepts
+const MAX_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * 1024 * 1024;
+
+/// Largest number of parts S3 accepts for a multipart upload
+const MAX_PARTS: usize = 10000;
+
+/// Describes the limit of --max-parts for errors
+fn parts_limit(args: &Args) -> String {
+    match args.max_parts {
+        MAX_PARTS => format!("S3 allows at most {MAX_PARTS}"),
+        n => format!("--max-parts allows at most {n}"),
+    }
+}
+
+fn check_max_parts(max_parts: usize) -> anyhow::Result<()> {
+    if max_parts == 0 || max_parts > MAX_PARTS {
+        anyhow::bail!("--max-parts has to be between 1 and {MAX_PARTS}");
+    }
+    Ok(())
+}
+
+/// Checks that an input of `total` bytes fits into an S3 object of at most --max-parts parts
+fn check_input_size(args: &Args, total: usize) -> anyhow::Result<()> {
+    if total as u64 > MAX_OBJECT_SIZE {
+        anyhow::bail!(
+            "Input of {} is larger than {}, the maximum size of an S3 object",
+            format_size(total),
+            format_size(MAX_OBJECT_SIZE as usize)
+        );
+    }
+    check_part_count(args, total)
+}
+
+/// Checks that an input of `total` bytes fits into --max-parts parts
+fn check_part_count(args: &Args, total: usize) -> anyhow::Result<()> {
+    let num_parts = count_parts(args, total);
+    if num_parts > args.max_parts && !args.part_sizes.is_empty() {
+        anyhow::bail!(
+            "Input of {} needs {num_parts} parts with --part-sizes, but {}",
+            format_size(total),
+            parts_limit(args)
+        );
+    }
+    if num_parts > args.max_parts {
+        anyhow::bail!(
+            "Input of {} needs {num_parts} parts of {}, but {}, increase --block-size to at least \
+            {}",
+            format_size(total),
+            format_size(args.block_size),
+            parts_limit(args),
+            format_size(total.div_ceil(args.max_parts))
+        );
+    }
+    Ok(())
+}
+
+/// Size of the part, from --part-sizes or --block-size, `None` for the rest of the input
+fn part_size(args: &Args, part_number: i32) -> Option<usize> {
+    match args.part_sizes.get(part_number as usize - 1) {
+        Some(PartSize::Size(x)) => Some(*x),
+        Some(PartSize::Rest) => None,
+        None => Some(args.block_size),
+    }
+}
+
+/// Part sizes of --part-size-ramp, doubling from the minimum until they reach `block_size`
+fn ramp_part_sizes(block_size: usize) -> Vec<PartSize> {
+    std::iter::successors(Some(MIN_PART_SIZE), |x| Some(x * 2))
+        .take_while(|x| *x < block_size)
+        .map(PartSize::Size)
+        .collect()
+}
+
+fn check_part_sizes(part_sizes: &[PartSize]) -> anyhow::Result<()> {
+    for (index, size) in part_sizes.iter().enumerate() {
+        match size {
+            PartSize::Rest if index + 1 < part_sizes.len() => {
+                anyhow::bail!("Only the last of --part-sizes can be 'rest'")
+            }
+            PartSize::Rest => (),
+            PartSize::Size(x) => check_block_size(*x)?,
+        }
+    }
+    Ok(())
+}
+
+/// Number of parts an input of `total` bytes is uploaded in
+fn count_parts(args: &Args, total: usize) -> usize {
+    let mut remaining = total;
+    for (index, size) in args.part_sizes.iter().enumerate() {
+        match size {
+            _ if remaining == 0 => return index,
+            PartSize::Rest => return index + 1,
+            PartSize::Size(x) => remaining = remaining.saturating_sub(*x),
+        }
+    }
+    args.part_sizes.len() + remaining.div_ceil(args.block_size)
+}
+
+fn check_block_size(block_size: usize) -> anyhow::Result<()> {
+    if block_size < MIN_PART_SIZE {
+        anyhow::bail!("Part size too small, 5MB is the minimum");
+    }
+    if block_size > MAX_PART_SIZE {
+        anyhow::bail!("Part size too large, 5GB is the maximum");
+    }
+    Ok(())
+}
+
+/// Reads a manifest of `local_path<TAB>s3://bucket/key` lines, checking every line up front
+fn read_manifest(args: &Args, path: &std::path::Path) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Failed to read manifest: {e}"),
+    };
+    let mut jobs = Vec::new();
+    for (number, line) in content.lines().enumerate().map(|(i, x)| (i + 1, x)) {
+        jobs.extend(parse_manifest_line(args, number, line)?);
+    }
+    Ok(jobs)
+}
+
+/// Checks a line of a manifest, returning `None` for a blank line
+fn parse_manifest_line(
+    args: &Args,
+    number: usize,
+    line: &str,
+) -> anyhow::Result<Option<(PathBuf, String)>> {
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let (input, s3_path) = match line.split_once('\t') {
+        None => anyhow::bail!("Manifest line {number} should be 'local_path<TAB>s3://bucket/key'"),
+        Some(x) => x,
+    };
+    if let Err(e) = parse_s3_path(s3_path).and_then(|(x, _)| check_bucket_name(args, &x)) {
+        anyhow::bail!("Manifest line {number}: {e}");
+    }
+    if let Err(e) = std::fs::metadata(input) {
+        anyhow::bail!("Manifest line {number}: cannot access '{input}': {e}");
+    }
+    Ok(Some((PathBuf::from(input), s3_path.to_string())))
+}
+
+/// Reads the lines of a manifest from stdin as they arrive, up to --limit files
+///
+/// A line that is not valid fails on its own, a read error ends the manifest.
+fn stdin_manifest(args: &Args) -> impl Iterator<Item = anyhow::Result<(PathBuf, String)>> + '_ {
+    let mut lines = std::io::stdin().lines().enumerate();
+    let mut done = false;
+    let jobs = std::iter::from_fn(move || loop {
+        if done {
+            return None;
+        }
+        let (index, line) = lines.next()?;
+        let line = match line {
+            Ok(x) => x,
+            Err(e) => {
+                done = true;
+                return Some(Err(anyhow::anyhow!(
+                    "Failed to read manifest from stdin: {e}"
+                )));
+            }
+        };
+        if let Some(job) = parse_manifest_line(args, index + 1, &line).transpose() {
+            return Some(job);
+        }
+    });
+    jobs.take(args.limit.unwrap_or(usize::MAX))
+}
+
+/// Applies --sort and --limit to the files of a manifest
+/// Checks the flags that only apply to many files, which clap cannot check as --manifest
+/// conflicts with the S3 paths
+fn check_job_options(args: &Args) -> anyhow::Result<()> {
+    if (args.sort.is_some() || args.limit.is_some()) && args.manifest.is_none() && !args.recursive {
+        anyhow::bail!("--sort and --limit need --manifest or --recursive");
+    }
+    if args.sync && args.manifest.is_none() && !args.recursive {
+        anyhow::bail!("--sync needs --manifest or --recursive");
+    }
+    Ok(())
+}
+
+fn select_jobs(args: &Args, mut jobs: Vec<(PathBuf, String)>) -> Vec<(PathBuf, String)> {
+    let metadata = |path: &PathBuf| std::fs::metadata(path).ok();
+    match args.sort {
+        None => (),
+        Some(ManifestOrder::Name) => jobs.sort_by(|a, b| a.0.cmp(&b.0)),
+        Some(ManifestOrder::Size) => {
+            jobs.sort_by_cached_key(|x| metadata(&x.0).map_or(0, |x| x.len()))
+        }
+        Some(ManifestOrder::Mtime) => jobs.sort_by_cached_key(|x| {
+            std::cmp::Reverse(metadata(&x.0).and_then(|x| x.modified().ok()))
+        }),
+    }
+    if let Some(limit) = args.limit {
+        jobs.truncate(limit);
+    }
+    if args.sort.is_some() || args.limit.is_some() {
+        for (input, s3_path) in &jobs {
+            tracing::info!("Selected {} for {s3_path}", input.display());
+        }
+    }
+    jobs
+}
+
+/// Lists the files below the --recursive input directory, with their S3 paths below `s3_path`
+fn directory_jobs(dir: &std::path::Path, s3_path: &str) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let (bucket, prefix) = parse_s3_path(s3_path)?;
+    let mut jobs = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = match std::fs::read_dir(&current) {
+            Ok(x) => x,
+            Err(e) => anyhow::bail!("Failed to read directory {}: {e}", current.display()),
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(x) => x,
+                Err(e) => anyhow::bail!("Failed to read directory {}: {e}", current.display()),
+            };
+            let path = entry.path();
+            // the entry's own type, so that symlinked directories cannot form loops
+            if entry.file_type().is_ok_and(|x| x.is_dir()) {
+                pending.push(path);
+                continue;
+            }
+            match std::fs::metadata(&path) {
+                Ok(x) if x.is_file() => (),
+                Ok(x) if x.is_dir() => {
+                    tracing::warn!(
+                        "Skipping {}, symlinked directories are not followed",
+                        path.display()
+                    );
+                    continue;
+                }
+                _ => {
+                    tracing::warn!("Skipping {}, it is not a regular file", path.display());
+                    continue;
+                }
+            }
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+            let names: Option<Vec<_>> = relative.iter().map(|x| x.to_str()).collect();
+            let Some(names) = names else {
+                tracing::warn!("Skipping {}, its name is not valid UTF-8", path.display());
+                continue;
+            };
+            let key = join_key(&prefix, &names.join("/"));
+            jobs.push((path, format!("s3://{bucket}/{key}")));
+        }
+    }
+    jobs.sort();
+    Ok(jobs)
+}
+
+/// Temporary copy of stdin for --spool-dir, removed when dropped
+struct SpoolFile(PathBuf);
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            tracing::warn!("Failed to remove {}: {e}", self.0.display());
+        }
+    }
+}
+
+/// Copies stdin to a file in `dir` and uploads that file
+async fn upload_spooled(args: &Args, dir: &std::path::Path) -> anyhow::Result<()> {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or_default();
+    let path = dir.join(format!(".s3put-spool-{}-{nanos}", std::process::id()));
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| anyhow::anyhow!("Failed to create spool file {}: {e}", path.display()))?;
+    let spool = SpoolFile(path);
+    let size = tokio::task::spawn_blocking(move || {
+        std::io::copy(&mut std::io::stdin().lock(), &mut file)?;
+        file.sync_all()?;
+        file.metadata().map(|x| x.len())
+    })
+    .await?
+    .map_err(|e| anyhow::anyhow!("Failed to spool stdin to {}: {e}", spool.0.display()))?;
+    tracing::info!("Spooled {size} bytes from stdin to {}", spool.0.display());
+
+    let spooled_args = Args {
+        input: Some(spool.0.clone()),
+        spool_dir: None,
+        ..args.clone()
+    };
+    upload_input(&spooled_args).await
+}
+
+/// Input of --split-into, read by the uploads of its objects one after the other
+struct Split {
+    state: std::sync::Mutex<SplitState>,
+    /// Size of the whole input, if known
+    size: Option<u64>,
+    regular_file: bool,
+}
+
+struct SplitState {
+    reader: std::io::BufReader<Box<dyn std::io::Read + Send + Sync>>,
+    /// Bytes read before the current object
+    offset: u64,
+    /// Bytes left for the current object
+    remaining: u64,
+}
+
+static SPLIT: std::sync::OnceLock<Split> = std::sync::OnceLock::new();
+
+impl Split {
+    /// Starts the next object of at most `size` bytes
+    fn start(&self, size: usize) {
+        self.state.lock().unwrap().remaining = size as u64;
+    }
+
+    /// Size of the current object, if the size of the input is known
+    fn object_size(&self) -> Option<usize> {
+        let state = self.state.lock().unwrap();
+        let left = self.size.map(|x| x.saturating_sub(state.offset));
+        left.map(|x| x.min(state.remaining) as usize)
+    }
+
+    /// Ends the current object, returning its size and whether the input has ended
+    fn finish(&self) -> std::io::Result<(u64, bool)> {
+        use std::io::BufRead;
+
+        let mut state = self.state.lock().unwrap();
+        let full = state.remaining == 0;
+        state.remaining = 0;
+        let end = !full || state.reader.fill_buf()?.is_empty();
+        Ok((state.offset, end))
+    }
+}
+
+/// Reads the current object of the --split-into input
+struct SplitReader(&'static Split);
+
+impl std::io::Read for SplitReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self.0.state.lock().unwrap();
+        let len = buf
+            .len()
+            .min(state.remaining.try_into().unwrap_or(usize::MAX));
+        let read = state.reader.read(&mut buf[..len])?;
+        state.remaining -= read as u64;
+        state.offset += read as u64;
+        Ok(read)
+    }
+}
+
+/// Uploads the input as objects of at most `size` and a manifest listing them, for --split-into
+async fn upload_split(args: &Args, size: usize) -> anyhow::Result<()> {
+    if size == 0 {
+        anyhow::bail!("--split-into needs a size of at least one byte");
+    }
+    let mut paths = args
+        .s3_paths
+        .iter()
+        .map(|x| parse_s3_path(x))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // expanded once, so that all objects of a destination get the same prefix
+    if args.key_template {
+        let placeholders = key_placeholders(args.input.as_deref());
+        for (_, key) in &mut paths {
+            *key = expand_placeholders(key, &placeholders)?;
+        }
+    }
+    let (reader, total, regular_file): (Box<dyn std::io::Read + Send + Sync>, _, _) =
+        match (&args.input, &args.input_url) {
+            (Some(path), _) => {
+                let file = match std::fs::File::open(path) {
+                    Ok(x) => x,
+                    Err(e) => anyhow::bail!("Failed to open input file: {e}"),
+                };
+                let metadata = file.metadata()?;
+                let regular_file = metadata.is_file();
+                (
+                    Box::new(file),
+                    regular_file.then_some(metadata.len()),
+                    regular_file,
+                )
+            }
+            (None, Some(url)) => {
+                let (body, length) = open_url(url)?;
+                (body, length.map(|x| x as u64), false)
+            }
+            (None, None) => {
+                grow_stdin_pipe();
+                (stdin_reader(), None, false)
+            }
+        };
+    let split = SPLIT.get_or_init(|| Split {
+        state: std::sync::Mutex::new(SplitState {
+            reader: std::io::BufReader::with_capacity(args.block_size.min(1 << 20), reader),
+            offset: 0,
+            remaining: 0,
+        }),
+        size: total,
+        regular_file,
+    });
+
+    let mut objects = Vec::new();
+    let mut offset = 0;
+    for number in 1.. {
+        let suffix = format!(".part{number:04}");
+        let object_args = Args {
+            s3_paths: (paths.iter())
+                .map(|(bucket, key)| format!("s3://{bucket}/{key}{suffix}"))
+                .collect(),
+            key_template: false,
+            split_into: None,
+            ..args.clone()
+        };
+        let recorded = recorded_results();
+        let start = std::time::Instant::now();
+        split.start(size);
+        if let Err(e) = upload_input(&object_args).await {
+            record_failures(&object_args, &object_args.s3_paths, recorded, start, &e);
+            return Err(e);
+        }
+        let (end, last) = match tokio::task::spawn_blocking(|| split.finish()).await? {
+            Ok(x) => x,
+            Err(e) => anyhow::bail!("Failed to read input: {e}"),
+        };
+        objects.push((suffix, end - offset));
+        offset = end;
+        if last {
+            break;
+        }
+    }
+
+    let retry_policy = RetryPolicy::new(args);
+    let sse_customer_key = args
+        .sse_c_key_file
+        .as_deref()
+        .map(SseCustomerKey::read)
+        .transpose()?;
+    // the checksums of the options are those of the input, not of the manifest
+    let options = ObjectOptions {
+        checksum_sha256: None,
+        checksum_crc32: None,
+        content_md5: None,
+        ..ObjectOptions::new(args)?
+    };
+    for (i, (bucket, key)) in paths.iter().enumerate() {
+        let manifest = serde_json::json!({
+            "size": offset,
+            "objects": (objects.iter())
+                .map(|(suffix, size)| serde_json::json!({"key": format!("{key}{suffix}"), "size": size}))
+                .collect::<Vec<_>>(),
+        });
+        let body = PutBody::Memory(pretty_json(&manifest)?.into_bytes());
+        let profile = match args.profile.len() {
+            0 => None,
+            1 => args.profile.first(),
+            _ => args.profile.get(i),
+        };
+        let config = load_config(bucket, profile.map(|x| x.as_str())).await?;
+        let key = format!("{key}.manifest");
+        let sse = sse_customer_key.as_ref();
+        let put = put_object(bucket, &key, config, &body, &retry_policy, sse, &options);
+        if let Err(e) = before_deadline(put).await? {
+            anyhow::bail!("Failed to write manifest s3://{bucket}/{key}: {e}");
+        }
+        if !args.quiet {
+            status!(
+                "wrote manifest s3://{bucket}/{key} of {} objects",
+                objects.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Copy of stdin for --tee-on-upload, written as stdin is read
+struct Tee {
+    path: PathBuf,
+    /// `None` once writing the file failed
+    file: std::sync::Mutex<Option<std::io::BufWriter<std::fs::File>>>,
+}
+
+static TEE: std::sync::OnceLock<Tee> = std::sync::OnceLock::new();
+
+impl Tee {
+    /// Drops the copy after a failed write, it could only be retried from with missing data
+    fn discard(&self, file: &mut Option<std::io::BufWriter<std::fs::File>>, e: std::io::Error) {
+        tracing::warn!(
+            "Failed to write to {}, the upload cannot be retried from it: {e}",
+            self.path.display()
+        );
+        *file = None;
+        let _ = std::fs::remove_file(&self.path);
+    }
+
+    /// Copies the rest of stdin, returning whether the file has all of it
+    fn finish(&'static self) -> std::io::Result<bool> {
+        use std::io::Write;
+
+        let mut buffer = vec![0_u8; 1024 * 1024];
+        while TeeReader(self).read(&mut buffer)? != 0 {}
+        let mut file = self.file.lock().unwrap();
+        if let Some(writer) = file.as_mut() {
+            if let Err(e) = writer.flush().and_then(|_| writer.get_ref().sync_all()) {
+                self.discard(&mut file, e);
+            }
+        }
+        Ok(file.is_some())
+    }
+}
+
+/// Reads stdin, copying everything to the file of a [`Tee`]
+struct TeeReader(&'static Tee);
+
+impl std::io::Read for TeeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::Write;
+
+        // reading under the lock keeps the file in the order of stdin
+        let mut file = self.0.file.lock().unwrap();
+        let num_read = std::io::stdin().read(buf)?;
+        if let Some(writer) = file.as_mut() {
+            if let Err(e) = writer.write_all(&buf[..num_read]) {
+                self.0.discard(&mut file, e);
+            }
+        }
+        Ok(num_read)
+    }
+}
+
+/// Reads stdin through the --tee-on-upload copy, if any
+fn stdin_reader() -> Box<dyn std::io::Read + Send + Sync> {
+    match TEE.get() {
+        Some(tee) => Box::new(TeeReader(tee)),
+        None => Box::new(std::io::stdin()),
+    }
+}
+
+/// Uploads stdin while copying it to `path`, and uploads the copy if that fails
+async fn upload_teed(args: &Args, path: &std::path::Path) -> anyhow::Result<()> {
+    let file = match std::fs::File::create(path) {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Failed to create tee file {}: {e}", path.display()),
+    };
+    let tee = TEE.get_or_init(|| Tee {
+        path: path.to_path_buf(),
+        file: std::sync::Mutex::new(Some(std::io::BufWriter::new(file))),
+    });
+    let result = upload_input(args).await;
+    // the upload may have failed in the middle of stdin
+    let complete = match tokio::task::spawn_blocking(|| tee.finish()).await? {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::warn!("Failed to read the rest of stdin: {e}");
+            false
+        }
+    };
+    let e = match result {
+        Ok(()) if complete => {
+            remove_tee(args, path);
+            return Ok(());
+        }
+        Ok(()) => return Ok(()),
+        Err(e) if e.is::<Interrupted>() || e.is::<DeadlineExceeded>() || !complete => {
+            return Err(e)
+        }
+        Err(e) => e,
+    };
+    tracing::warn!("Upload failed, retrying from {}: {e}", path.display());
+    let retry_args = Args {
+        input: Some(path.to_path_buf()),
+        tee_on_upload: None,
+        ..args.clone()
+    };
+    let result = upload_input(&retry_args).await;
+    match &result {
+        Ok(()) => remove_tee(args, path),
+        Err(_) => status!("kept {} to upload again with --input", path.display()),
+    }
+    result
+}
+
+fn remove_tee(args: &Args, path: &std::path::Path) {
+    if args.keep_tee {
+        return;
+    }
+    if let Err(e) = std::fs::remove_file(path) {
+        tracing::warn!("Failed to remove {}: {e}", path.display());
+    }
+}
+
+/// Uploads every file of the manifest, reporting failures at the end
+async fn upload_manifest(args: &Args, manifest: &std::path::Path) -> anyhow::Result<()> {
+    if manifest.as_os_str() == "-" {
+        if args.sort.is_some() {
+            anyhow::bail!("--sort needs all lines of the manifest, it cannot read them from stdin");
+        }
+        return upload_jobs(args, stdin_manifest(args), None).await;
+    }
+    let jobs = select_jobs(args, read_manifest(args, manifest)?);
+    let total = jobs.len();
+    upload_jobs(args, jobs.into_iter().map(Ok), Some(total)).await
+}
+
+/// What became of a file of a manifest or --recursive
+enum JobOutcome {
+    Uploaded,
+    Skipped,
+    Failed(anyhow::Error),
+}
+
+/// Uploads the files of a manifest or --recursive concurrently, reporting failures at the end
+///
+/// All files share one pool of parts in flight, and as many files are uploaded at once as parts
+/// can be in flight, so that a file with a single part does not hold up the others.
+async fn upload_jobs(
+    args: &Args,
+    jobs: impl Iterator<Item = anyhow::Result<(PathBuf, String)>>,
+    total: Option<usize>,
+) -> anyhow::Result<()> {
+    if args.output == OutputFormat::AwsJson {
+        *MANIFEST_RESULTS.lock().unwrap() = Some(Vec::new());
+    }
+    let num_tokens = args.max_inflight.unwrap_or(2 * args.threads);
+    if num_tokens == 0 {
+        anyhow::bail!("At least one part has to be in flight");
+    }
+    if args.max_completions == 0 {
+        anyhow::bail!("At least one upload has to complete at once");
+    }
+    PART_POOL.get_or_init(|| tokio::sync::Semaphore::new(num_tokens));
+    COMPLETIONS.get_or_init(|| tokio::sync::Semaphore::new(args.max_completions));
+    let files = Arc::new(tokio::sync::Semaphore::new(num_tokens));
+    let runtime = tokio::runtime::Handle::current();
+    let mut running = tokio::task::JoinSet::new();
+    let mut outcomes = Vec::new();
+    let mut num_jobs = 0;
+    for job in jobs {
+        num_jobs += 1;
+        let (input, s3_path) = match job {
+            Ok(x) => x,
+            Err(e) => {
+                tracing::error!("{e}");
+                outcomes.push((format!("manifest entry {num_jobs}"), JobOutcome::Failed(e)));
+                continue;
+            }
+        };
+        let slot = files.clone().acquire_owned().await?;
+        while let Some(x) = running.try_join_next() {
+            outcomes.push(x?);
+        }
+        // the remaining files would only fail the same way
+        if (outcomes.iter())
+            .any(|x| matches!(&x.1, JobOutcome::Failed(e) if e.is::<DeadlineExceeded>()))
+        {
+            break;
+        }
+        if !args.quiet {
+            let total = total.map(|x| format!(" of {x}")).unwrap_or_default();
+            status!("file {num_jobs}{total}: {} to {s3_path}", input.display());
+        }
+        let job_args = Args {
+            s3_paths: vec![s3_path.clone()],
+            input: Some(input.clone()),
+            ..args.clone()
+        };
+        // waiting for parts blocks, so each file is uploaded on the blocking pool
+        let runtime = runtime.clone();
+        running.spawn_blocking(move || {
+            let outcome = runtime.block_on(upload_job(&job_args, &input, &s3_path));
+            drop(slot);
+            (s3_path, outcome)
+        });
+    }
+    while let Some(x) = running.join_next().await {
+        outcomes.push(x?);
+    }
+    let mut skipped = 0;
+    let mut failed = Vec::new();
+    let mut deadline_exceeded = None;
+    for (s3_path, outcome) in outcomes {
+        match outcome {
+            JobOutcome::Uploaded => (),
+            JobOutcome::Skipped => skipped += 1,
+            JobOutcome::Failed(e) if e.is::<DeadlineExceeded>() => deadline_exceeded = Some(e),
+            JobOutcome::Failed(_) => failed.push(s3_path),
+        }
+    }
+    if let Some(results) = MANIFEST_RESULTS.lock().unwrap().take() {
+        println!("{}", pretty_json(&results)?);
+    }
+    if let Some(e) = deadline_exceeded {
+        return Err(e);
+    }
+    if !args.quiet && args.sync {
+        status!(
+            "uploaded {}, skipped {skipped} unchanged and failed {} of {num_jobs} files",
+            num_jobs - skipped - failed.len(),
+            failed.len()
+        );
+    } else if !args.quiet {
+        status!("uploaded {} of {num_jobs} files", num_jobs - failed.len());
+    }
+    if !failed.is_empty() {
+        for s3_path in failed {
+            status!("failed: {s3_path}");
+        }
+        anyhow::bail!("Failed upload");
+    }
+    Ok(())
+}
+
+/// Uploads a file of a manifest or --recursive, unless --sync finds its object unchanged
+async fn upload_job(args: &Args, input: &std::path::Path, s3_path: &str) -> JobOutcome {
+    let start = std::time::Instant::now();
+    let recorded = recorded_results();
+    if args.sync {
+        match in_sync(args, input, s3_path).await {
+            Ok(true) => {
+                if let Ok((bucket, key)) = parse_s3_path(s3_path) {
+                    record_skipped(args, &bucket, &key);
+                }
+                if !args.quiet {
+                    status!("skipped, {s3_path} is unchanged");
+                }
+                return JobOutcome::Skipped;
+            }
+            Ok(false) => (),
+            Err(e) => {
+                tracing::error!("Failed to compare {} to {s3_path}: {e}", input.display());
+                record_failures(args, &args.s3_paths, recorded, start, &e);
+                return JobOutcome::Failed(e);
+            }
+        }
+    }
+    if let Err(e) = upload_input(args).await {
+        push_metrics(args, Metrics::failed(start)).await;
+        webhook_failure(args, &args.s3_paths, start, &e).await;
+        record_failures(args, &args.s3_paths, recorded, start, &e);
+        tracing::error!("Failed to upload {} to {s3_path}: {e}", input.display());
+        return JobOutcome::Failed(e);
+    }
+    JobOutcome::Uploaded
+}
+
+async fn run(args: &Args) -> anyhow::Result<()> {
+    check_block_size(args.block_size)?;
+    check_part_sizes(&args.part_sizes)?;
+    if let Some(format) = &args.success_format {
+        // fail before uploading, not after
+        let result = UploadResult {
+            etag: String::new(),
+            bytes: 0,
+            location: None,
+            version_id: None,
+            checksum: None,
+            parts: 0,
+            retries: 0,
+        };
+        let placeholders = success_placeholders(args, "", "", &result, Duration::ZERO);
+        let placeholders: Vec<_> = placeholders
+            .into_iter()
+            .map(|(name, value)| (name, value.map(|_| String::new())))
+            .collect();
+        expand_placeholders(format, &placeholders)?;
+    }
+    if args.resume.is_some() && args.s3_paths.len() != 1 {
+        anyhow::bail!("--resume needs exactly one S3 path");
+    }
+    check_job_options(args)?;
+    check_max_parts(args.max_parts)?;
+    if args.multipart_threshold.is_some_and(|x| x < MIN_PART_SIZE) {
+        anyhow::bail!("Multipart threshold too small, 5MB is the minimum");
+    }
+    if !args.checksum_only.is_empty() {
+        return print_etags(args);
+    }
+    for (name, _) in &args.headers {
+        if SDK_HEADERS.contains(&name.as_str()) {
+            tracing::warn!("Header '{name}' is set by the SDK, overriding it may break requests");
+        }
+    }
+    EXTRA_HEADERS.get_or_init(|| args.headers.clone());
+    if let Some(path) = &args.api_log {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path);
+        match file {
+            Ok(x) => API_LOG.get_or_init(|| std::sync::Mutex::new(x)),
+            Err(e) => anyhow::bail!("Failed to open {}: {e}", path.display()),
+        };
+    }
+    if let Some(region) = &args.expect_region {
+        EXPECTED_REGION.get_or_init(|| region.clone());
+    }
+    PROVIDER.get_or_init(|| args.provider);
+    if args.print_presigned_url && args.presign_expires > MAX_PRESIGN_EXPIRES {
+        anyhow::bail!("Presigned URLs can be valid for at most 7 days");
+    }
+    if let Some(max) = args.max_connections {
+        if max == 0 {
+            anyhow::bail!("At least one connection is needed");
+        }
+        CONNECTIONS.get_or_init(|| tokio::sync::Semaphore::new(max));
+    }
+    if let (Some(path), Some(limit)) = (&args.global_concurrency_file, args.global_concurrency) {
+        if limit == 0 {
+            anyhow::bail!("At least one global connection is needed");
+        }
+        let leases = GlobalLeases::open(path, limit)?;
+        let leases = GLOBAL_LEASES.get_or_init(|| leases);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(LEASE_TIMEOUT / 4);
+            loop {
+                interval.tick().await;
+                let renewed = tokio::task::spawn_blocking(|| leases.renew()).await;
+                if let Ok(Err(e)) = renewed {
+                    tracing::warn!("Failed to renew leases of --global-concurrency-file: {e}");
+                }
+            }
+        });
+    }
+    if args.tcp_keepalive.is_some()
+        || args.http_version.is_some()
+        || args.http2_initial_window.is_some()
+    {
+        let client = http_client(args)?;
+        HTTP_CLIENT.get_or_init(|| client);
+    }
+    SDK_RETRIES.get_or_init(|| {
+        use s3::config::retry::RetryConfig;
+        let retry_config = match args.sdk_retry_mode {
+            SdkRetryMode::Off => return RetryConfig::disabled(),
+            SdkRetryMode::Standard => RetryConfig::standard(),
+            SdkRetryMode::Adaptive => RetryConfig::adaptive(),
+        };
+        retry_config.with_max_attempts(args.sdk_retries.saturating_add(1))
+    });
+    if let Some(deadline) = args.deadline {
+        DEADLINE.get_or_init(|| (tokio::time::Instant::now() + deadline, deadline));
+    }
+    if let Some(path) = &args.credentials_file {
+        check_credentials_file(path, &args.credentials_profile)?;
+    }
+    CREDENTIALS_FILE.get_or_init(|| {
+        let path = args.credentials_file.clone()?;
+        Some((path, args.credentials_profile.clone()))
+    });
+    if args.credential_source == Some(CredentialSource::Sso) {
+        match args.profile.is_empty() {
+            true => check_sso_profile(None)?,
+            false => args
+                .profile
+                .iter()
+                .try_for_each(|x| check_sso_profile(Some(x)))?,
+        }
+    }
+    CREDENTIAL_SOURCE.get_or_init(|| args.credential_source);
+    if let Some(serial) = &args.mfa_serial {
+        let token = mfa_token(args, serial)?;
+        let credentials = mfa_session(args.profile.first().map(|x| x.as_str()), serial, &token);
+        let _ = MFA_CREDENTIALS.set(credentials.await?);
+    }
+
+    if args.self_test {
+        return self_test(args).await;
+    }
+    if args.estimate_cost {
+        return estimate_cost(args);
+    }
+    if args.show_plan {
+        show_plan(args)?;
+        if args.plan_only {
+            return Ok(());
+        }
+    }
+    if args.resume_incomplete {
+        return resume_incomplete(args).await;
+    }
+    if args.bench {
+        return bench(args).await;
+    }
+    if args.download {
+        return download(args).await;
+    }
+    if args.output == OutputFormat::Json {
+        *FILE_RESULTS.lock().unwrap() = Some(Vec::new());
+    }
+    if let (true, Some(dir)) = (args.recursive, &args.input) {
+        let [s3_path] = &args.s3_paths[..] else {
+            anyhow::bail!("--recursive needs exactly one S3 path");
+        };
+        let jobs = select_jobs(args, directory_jobs(dir, s3_path)?);
+        let total = jobs.len();
+        // a multipart upload of a file below one part is three requests instead of one
+        let args = Args {
+            multipart_threshold: args.multipart_threshold.or(Some(args.block_size)),
+            ..args.clone()
+        };
+        return upload_jobs(&args, jobs.into_iter().map(Ok), Some(total)).await;
+    }
+    match &args.manifest {
+        Some(manifest) => upload_manifest(args, manifest).await,
+        None => {
+            let start = std::time::Instant::now();
+            let result = match (&args.spool_dir, &args.tee_on_upload, args.split_into) {
+                (Some(dir), _, _) => upload_spooled(args, dir).await,
+                (None, Some(path), _) => upload_teed(args, path).await,
+                (None, None, Some(size)) => upload_split(args, size).await,
+                (None, None, None) => upload_input(args).await,
+            };
+            if let Err(e) = &result {
+                push_metrics(args, Metrics::failed(start)).await;
+                webhook_failure(args, &args.s3_paths, start, e).await;
+                record_failures(args, &args.s3_paths, 0, start, e);
+            }
+            result
+        }
+    }
+}
+
+/// AWS list prices in USD as `(region, storage class, per GB-month, per 1000 PUT requests)`, from
+/// https://aws.amazon.com/s3/pricing/
+///
+/// Storage of INTELLIGENT_TIERING is the price of its frequent access tier.
+const PRICES: &[(&str, &str, f64, f64)] = &[
+    ("us-east-1", "STANDARD", 0.023, 0.005),
+    ("us-east-1", "INTELLIGENT_TIERING", 0.023, 0.005),
+    ("us-east-1", "STANDARD_IA", 0.0125, 0.01),
+    ("us-east-1", "ONEZONE_IA", 0.01, 0.01),
+    ("us-east-1", "GLACIER_IR", 0.004, 0.02),
+    ("us-east-1", "GLACIER", 0.0036, 0.03),
+    ("us-east-1", "DEEP_ARCHIVE", 0.00099, 0.05),
+    ("us-east-2", "STANDARD", 0.023, 0.005),
+    ("us-east-2", "INTELLIGENT_TIERING", 0.023, 0.005),
+    ("us-east-2", "STANDARD_IA", 0.0125, 0.01),
+    ("us-east-2", "ONEZONE_IA", 0.01, 0.01),
+    ("us-east-2", "GLACIER_IR", 0.004, 0.02),
+    ("us-east-2", "GLACIER", 0.0036, 0.03),
+    ("us-east-2", "DEEP_ARCHIVE", 0.00099, 0.05),
+    ("us-west-2", "STANDARD", 0.023, 0.005),
+    ("us-west-2", "INTELLIGENT_TIERING", 0.023, 0.005),
+    ("us-west-2", "STANDARD_IA", 0.0125, 0.01),
+    ("us-west-2", "ONEZONE_IA", 0.01, 0.01),
+    ("us-west-2", "GLACIER_IR", 0.004, 0.02),
+    ("us-west-2", "GLACIER", 0.0036, 0.03),
+    ("us-west-2", "DEEP_ARCHIVE", 0.00099, 0.05),
+    ("eu-west-1", "STANDARD", 0.023, 0.005),
+    ("eu-west-1", "INTELLIGENT_TIERING", 0.023, 0.005),
+    ("eu-west-1", "STANDARD_IA", 0.0125, 0.01),
+    ("eu-west-1", "ONEZONE_IA", 0.01, 0.01),
+    ("eu-west-1", "GLACIER_IR", 0.004, 0.02),
+    ("eu-west-1", "GLACIER", 0.0036, 0.03),
+    ("eu-west-1", "DEEP_ARCHIVE", 0.00099, 0.05),
+    ("eu-central-1", "STANDARD", 0.0245, 0.0054),
+    ("eu-central-1", "INTELLIGENT_TIERING", 0.0245, 0.0054),
+    ("eu-central-1", "STANDARD_IA", 0.0135, 0.01),
+    ("eu-central-1", "ONEZONE_IA", 0.0108, 0.01),
+    ("eu-central-1", "GLACIER_IR", 0.005, 0.024),
+    ("eu-central-1", "GLACIER", 0.0045, 0.036),
+    ("eu-central-1", "DEEP_ARCHIVE", 0.0018, 0.06),
+];
+
+/// Prints what uploading the input would approximately cost, for --estimate-cost
+fn estimate_cost(args: &Args) -> anyhow::Result<()> {
+    let Some(input) = &args.input else {
+        anyhow::bail!("--estimate-cost needs an input file");
+    };
+    let size = match std::fs::metadata(input) {
+        Ok(x) if x.is_file() => x.len(),
+        Ok(_) => anyhow::bail!("--estimate-cost needs a regular file as input"),
+        Err(e) => anyhow::bail!("Failed to open input file: {e}"),
+    };
+    let region = std::env::var("AWS_REGION")
+        .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+        .ok()
+        .or(provider().default_region().map(str::to_string))
+        .unwrap_or_default();
+    let class = args
+        .storage_class
+        .as_ref()
+        .map_or("STANDARD", |x| x.as_str());
+    let Some((_, _, storage_price, put_price)) = PRICES
+        .iter()
+        .find(|(x, y, _, _)| *x == region && *y == class)
+    else {
+        anyhow::bail!("No built-in price for storage class {class} in region '{region}'");
+    };
+    if provider() != Provider::Aws {
+        tracing::warn!("Prices are those of AWS, not of the --provider");
+    }
+
+    let plan = UploadPlan::new(args, size as usize);
+    let (num_parts, requests_per_destination) = (plan.parts.len(), plan.requests);
+    let destinations = args.s3_paths.len();
+    let requests = requests_per_destination * destinations;
+    let stored = size * destinations as u64;
+    let gigabytes = stored as f64 / (1024 * 1024 * 1024) as f64;
+    println!("Approximate cost in {region} with {class}, from AWS list prices:");
+    println!(
+        "  {requests} requests for {num_parts} parts to {destinations} destinations: ${:.4}",
+        *put_price * requests as f64 / 1000.0
+    );
+    println!(
+        "  {} of storage: ${:.4} per month",
+        format_size(stored as usize),
+        storage_price * gigabytes
+    );
+    Ok(())
+}
+
+/// Byte ranges of the parts an input of `size` bytes is uploaded in, as `(start, size)`
+fn plan_parts(args: &Args, size: usize) -> Vec<(usize, usize)> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    // an empty input is still uploaded as one part
+    while start < size || parts.is_empty() {
+        let part_number = parts.len() as i32 + 1;
+        let len = part_size(args, part_number).map_or(size - start, |x| x.min(size - start));
+        parts.push((start, len));
+        start += len;
+    }
+    parts
+}
+
+/// The parts and requests of an upload, for --estimate-cost and --show-plan
+struct UploadPlan {
+    /// Byte ranges as `(start, size)`, a single one for a single PUT
+    parts: Vec<(usize, usize)>,
+    multipart: bool,
+    /// Requests per destination
+    requests: usize,
+}
+
+impl UploadPlan {
+    fn new(args: &Args, size: usize) -> Self {
+        match etag_block_size(args, size as u64) {
+            Some(_) => {
+                let parts = plan_parts(args, size);
+                // a multipart upload also needs a request to create and one to complete it
+                let requests = parts.len() + 2;
+                Self {
+                    parts,
+                    multipart: true,
+                    requests,
+                }
+            }
+            None => Self {
+                parts: vec![(0, size)],
+                multipart: false,
+                requests: 1,
+            },
+        }
+    }
+}
+
+/// Prints the parts, requests and object settings of the upload, for --show-plan
+fn show_plan(args: &Args) -> anyhow::Result<()> {
+    let Some(input) = &args.input else {
+        anyhow::bail!("--show-plan needs an input file");
+    };
+    let size = match std::fs::metadata(input) {
+        Ok(x) if x.is_file() => x.len() as usize,
+        Ok(_) => anyhow::bail!("--show-plan needs a regular file as input"),
+        Err(e) => anyhow::bail!("Failed to open input file: {e}"),
+    };
+    let options = ObjectOptions::new(args)?;
+    let UploadPlan {
+        parts,
+        multipart,
+        requests,
+    } = UploadPlan::new(args, size);
+    let storage_class = options.storage_class.as_ref().map(|x| x.as_str());
+    let encryption = match (&args.sse_c_key_file, &options.server_side_encryption) {
+        (Some(_), _) => Some("SSE-C".to_string()),
+        (None, Some(x)) => Some(match &options.ssekms_key_id {
+            Some(key) => format!("{} with key {key}", x.as_str()),
+            None => x.as_str().to_string(),
+        }),
+        (None, None) => None,
+    };
+    let checksum_algorithm = options.checksum_algorithm.map(ChecksumAlgorithm::name);
+    let checksum_type = options.checksum_type.as_ref().map(|x| x.as_str());
+
+    if args.output != OutputFormat::Text {
+        let parts: Vec<_> = (parts.iter().enumerate())
+            .map(|(i, (start, len))| {
+                serde_json::json!({
+                    "part_number": i + 1,
+                    "start": start,
+                    "end": (*len > 0).then(|| start + len - 1),
+                    "size": len,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            pretty_json(&serde_json::json!({
+                "input": input,
+                "size": size,
+                "destinations": args.s3_paths,
+                "multipart": multipart,
+                "requests": requests * args.s3_paths.len(),
+                "storage_class": storage_class,
+                "encryption": encryption,
+                "checksum_algorithm": checksum_algorithm,
+                "checksum_type": checksum_type,
+                "parts": parts,
+            }))?
+        );
+        return Ok(());
+    }
+    let destinations = args.s3_paths.len();
+    println!(
+        "Upload plan for {} ({}) to {destinations} destinations:",
+        input.display(),
+        format_size(size)
+    );
+    let upload = match multipart {
+        true => format!("multipart upload of {} parts", parts.len()),
+        false => "single PUT".to_string(),
+    };
+    println!(
+        "  {upload}, {requests} requests per destination, {} in total",
+        requests * destinations
+    );
+    let checksum = match (checksum_algorithm, checksum_type) {
+        (Some(algorithm), Some(checksum_type)) => format!("{algorithm} ({checksum_type})"),
+        (Some(algorithm), None) => algorithm.to_string(),
+        (None, _) => "SDK default".to_string(),
+    };
+    println!(
+        "  storage class {}, encryption {}, checksum {checksum}",
+        storage_class.unwrap_or("of the bucket"),
+        encryption.as_deref().unwrap_or("of the bucket"),
+    );
+    for (i, (start, len)) in parts.iter().enumerate() {
+        match len {
+            0 => println!("  part {}: empty", i + 1),
+            _ => println!(
+                "  part {}: bytes {start}-{} ({})",
+                i + 1,
+                start + len - 1,
+                format_size(*len)
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Size of the object uploaded by --self-test
+const SELF_TEST_SIZE: usize = 1024 * 1024;
+
+/// Prints the outcome of a --self-test step, passing on its error
+fn report_step<T>(step: &str, result: anyhow::Result<T>) -> anyhow::Result<T> {
+    match &result {
+        Ok(_) => println!("PASS  {step}"),
+        Err(e) => println!("FAIL  {step}: {e}"),
+    }
+    result
+}
+
+/// Uploads a small random object below each S3 path, reads it back and deletes it again
+async fn self_test(args: &Args) -> anyhow::Result<()> {
+    let mut failed = false;
+    for s3_path in &args.s3_paths {
+        println!("{s3_path}");
+        if let Err(e) = self_test_path(args, s3_path).await {
+            tracing::debug!("Self test of {s3_path} failed: {e}");
+            failed = true;
+        }
+    }
+    if failed {
+        anyhow::bail!("Self test failed");
+    }
+    Ok(())
+}
+
+async fn self_test_path(args: &Args, s3_path: &str) -> anyhow::Result<()> {
+    let (bucket, prefix) = parse_s3_path(s3_path)?;
+    check_bucket_name(args, &bucket)?;
+    let key = temporary_key(&prefix);
+    let profile = args.profile.first().cloned();
+
+    let config = report_step(
+        "load config",
+        load_config(&bucket, profile.as_deref()).await,
+    )?;
+    report_step("credentials", check_credentials(&config, &bucket).await)?;
+    let (config, upload_id) = report_step(
+        "start upload",
+        start_upload(&bucket, &key, config, None, &ObjectOptions::default()).await,
+    )?;
+    let destination = Destination {
+        bucket,
+        key,
+        profile,
+        config: Arc::new(std::sync::RwLock::new((0, config))),
+        upload_id,
+        sse_customer_key: None,
+        completed_parts: Vec::new(),
+    };
+
+    // chain hashes of the current time, random enough to not be compressed or deduplicated
+    let mut data = Vec::with_capacity(SELF_TEST_SIZE);
+    let mut block = Sha256::digest(format!("{:?}", std::time::SystemTime::now()));
+    while data.len() < SELF_TEST_SIZE {
+        data.extend_from_slice(&block);
+        block = Sha256::digest(block);
+    }
+    let data = bytes::Bytes::from(data);
+    let retry_policy = RetryPolicy::new(args);
+    let part = match report_step(
+        "upload part",
+        upload_part(
+            &destination,
+            &data,
+            1,
+            &retry_policy,
+            BodyOptions::new(args),
+        )
+        .await,
+    ) {
+        Ok(x) => x,
+        Err(e) => {
+            abort_upload(&destination).await;
+            return Err(e);
+        }
+    };
+    let result = match report_step(
+        "complete upload",
+        complete_upload(args, &destination, vec![part.part], data.len()).await,
+    ) {
+        Ok(x) => x,
+        Err(e) => {
+            abort_upload(&destination).await;
+            return Err(e);
+        }
+    };
+
+    let (bucket, key) = (&destination.bucket, &destination.key);
+    let client = s3_client(&destination.config());
+    let read_back = async {
+        let response = client.get_object().bucket(bucket).key(key).send().await?;
+        let remote = response.body.collect().await?.into_bytes();
+        if remote[..] != data[..] {
+            anyhow::bail!(
+                "got {} that differ from the uploaded data",
+                format_size(remote.len())
+            );
+        }
+        Ok(())
+    };
+    let checked = report_step("read back", read_back.await).and_then(|()| {
+        use md5::Md5;
+
+        let etag = format!("\"{:x}-1\"", Md5::digest(Md5::digest(&data)));
+        let result = match result.etag == etag {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!("expected {etag}, got {}", result.etag)),
+        };
+        report_step("etag", result)
+    });
+
+    if args.keep {
+        println!("KEPT  s3://{bucket}/{key}");
+    } else {
+        let deleted = client.delete_object().bucket(bucket).key(key).send().await;
+        report_step("delete", deleted.map_err(anyhow::Error::from))?;
+    }
+    checked
+}
+
+/// Random data of a fixed size for --bench, from xorshift so that generating it is no bottleneck
+struct RandomInput {
+    remaining: usize,
+    state: u64,
+}
+
+impl std::io::Read for RandomInput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = buf.len().min(self.remaining);
+        for chunk in buf[..size].chunks_mut(8) {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            chunk.copy_from_slice(&self.state.to_le_bytes()[..chunk.len()]);
+        }
+        self.remaining -= size;
+        Ok(size)
+    }
+}
+
+/// Outcome of one upload of --bench
+struct BenchRun {
+    num_tokens: usize,
+    duration: Duration,
+    /// Sorted part latencies
+    part_durations: Vec<Duration>,
+    retries: u32,
+}
+
+/// Downloads the object at the single S3 path for --download
+async fn download(args: &Args) -> anyhow::Result<()> {
+    use futures::StreamExt;
+
+    let start = std::time::Instant::now();
+    let [s3_path] = &args.s3_paths[..] else {
+        anyhow::bail!("--download needs exactly one S3 path");
+    };
+    let num_tokens = args.max_inflight.unwrap_or(2 * args.threads);
+    if num_tokens == 0 {
+        anyhow::bail!("At least one part has to be in flight");
+    }
+    let (bucket, key) = parse_s3_path(s3_path)?;
+    check_bucket_name(args, &bucket)?;
+    let sse_customer_key = args
+        .sse_c_key_file
+        .as_deref()
+        .map(SseCustomerKey::read)
+        .transpose()?;
+    let config = load_config(&bucket, args.profile.first().map(|x| x.as_str())).await?;
+    let client = s3_client(&config);
+    let head = client
+        .head_object()
+        .bucket(&bucket)
+        .key(&key)
+        .with_sse_c(sse_customer_key.as_ref())
+        .send()
+        .await;
+    let head = match head {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Failed to look up {s3_path}: {e}{}", request_ids(&e)),
+    };
+    let size = head.content_length.unwrap_or_default().max(0) as usize;
+    let mut partial = None;
+    let file = match &args.download_file {
+        Some(path) => {
+            let (file, output) = PartialFile::create(path)?;
+            partial = Some(file);
+            Some(output)
+        }
+        None => None,
+    };
+    // written on the blocking pool, with up to as many ranges waiting as are downloaded at once
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<bytes::Bytes>(num_tokens);
+    let writer = tokio::task::spawn_blocking(move || {
+        let mut output: Box<dyn std::io::Write> = match file {
+            Some(file) => Box::new(std::io::BufWriter::new(file)),
+            None => Box::new(std::io::stdout().lock()),
+        };
+        while let Some(data) = receiver.blocking_recv() {
+            output.write_all(&data)?;
+        }
+        output.flush()
+    });
+    let retry_policy = RetryPolicy::new(args);
+    let ranges = (0..size)
+        .step_by(args.block_size)
+        .map(|x| (x, size.min(x + args.block_size)));
+    let range = DownloadRange {
+        client: &client,
+        bucket: &bucket,
+        key: &key,
+        // every range has to come from the same version of the object
+        etag: head.e_tag.as_deref(),
+        sse_customer_key: sse_customer_key.as_ref(),
+        retry_policy: &retry_policy,
+        verify_checksum: args.verify_checksum,
+    };
+    let range = &range;
+    let mut parts = futures::stream::iter(
+        ranges.map(|(x, end)| async move { (x, end, range.get(x, end).await) }),
+    )
+    .buffered(num_tokens);
+    let (mut bytes, mut num_parts, mut retries) = (0, 0, 0);
+    let mut unverified = Vec::new();
+    while let Some((start, end, part)) = parts.next().await {
+        check_deadline()?;
+        let (data, part_retries, verified) = part?;
+        if args.verify_checksum && !verified {
+            unverified.push(format!("{start}-{}", end - 1));
+        }
+        let len = data.len();
+        // the writer only stops early after an error, which it returns below
+        if sender.send(data).await.is_err() {
+            break;
+        }
+        bytes += len;
+        num_parts += 1;
+        retries += part_retries;
+        tracing::info!("Downloaded {} of {}", format_size(bytes), format_size(size));
+    }
+    drop(sender);
+    if let Err(e) = writer.await? {
+        anyhow::bail!("Failed to write the download: {e}");
+    }
+    if !unverified.is_empty() {
+        tracing::warn!(
+            "S3 has no checksum for {} of {num_parts} ranges, they are not verified: bytes {}",
+            unverified.len(),
+            unverified.join(", ")
+        );
+    }
+    if let (Some(partial), Some(path)) = (partial, &args.download_file) {
+        partial.finish(path)?;
+    }
+    if !args.quiet {
+        let elapsed = start.elapsed().as_secs_f64();
+        status!(
+            "downloaded {} in {num_parts} parts, {elapsed:.1}s ({}/s), {retries} retries",
+            format_size(bytes),
+            format_size((bytes as f64 / elapsed) as usize),
+        );
+    }
+    Ok(())
+}
+
+/// Temporary file next to the --download-file, renamed to it once complete and removed if the
+/// download fails
+struct PartialFile(Option<PathBuf>);
+
+impl PartialFile {
+    fn create(target: &std::path::Path) -> anyhow::Result<(Self, std::fs::File)> {
+        let Some(name) = target.file_name() else {
+            anyhow::bail!("{} is not a file name", target.display());
+        };
+        let name = format!(".{}.s3put-{}", name.to_string_lossy(), std::process::id());
+        let path = target.with_file_name(name);
+        match std::fs::File::create(&path) {
+            Ok(file) => Ok((Self(Some(path)), file)),
+            Err(e) => anyhow::bail!("Failed to create {}: {e}", path.display()),
+        }
+    }
+
+    fn finish(mut self, target: &std::path::Path) -> anyhow::Result<()> {
+        let Some(path) = self.0.take() else {
+            return Ok(());
+        };
+        if let Err(e) = std::fs::rename(&path, target) {
+            self.0 = Some(path);
+            anyhow::bail!("Failed to move the download to {}: {e}", target.display());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!("Failed to remove {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+/// The object of --download and how to request ranges of it
+struct DownloadRange<'a> {
+    client: &'a s3::Client,
+    bucket: &'a str,
+    key: &'a str,
+    etag: Option<&'a str>,
+    sse_customer_key: Option<&'a SseCustomerKey>,
+    retry_policy: &'a RetryPolicy,
+    /// See --verify-checksum
+    verify_checksum: bool,
+}
+
+impl DownloadRange<'_> {
+    /// Downloads bytes `start..end`, returning them, the number of retries needed, and whether
+    /// they matched a checksum of S3
+    async fn get(&self, start: usize, end: usize) -> anyhow::Result<(bytes::Bytes, u32, bool)> {
+        let mut retry_count = 0;
+        loop {
+            let connection = connection().await;
+            let response = self
+                .client
+                .get_object()
+                .bucket(self.bucket)
+                .key(self.key)
+                .range(format!("bytes={start}-{}", end - 1))
+                .set_if_match(self.etag.map(String::from))
+                .with_sse_c(self.sse_customer_key)
+                .set_checksum_mode(
+                    (self.verify_checksum).then_some(s3::types::ChecksumMode::Enabled),
+                )
+                .customize()
+                .config_override(retry_override())
+                .send()
+                .await;
+            let e = match response {
+                Ok(x) => {
+                    let checksum = stored_checksum(checksums!(x));
+                    match x.body.collect().await.map(|x| x.into_bytes()) {
+                        Ok(x) if x.len() != end - start => anyhow::anyhow!(
+                            "got {} instead of {}",
+                            format_size(x.len()),
+                            format_size(end - start)
+                        ),
+                        Ok(x) => match checksum {
+                            Some((algorithm, expected)) if algorithm.digest(&x) != expected => {
+                                anyhow::anyhow!(
+                                    "the {} checksum does not match, expected {expected}",
+                                    algorithm.name()
+                                )
+                            }
+                            checksum => return Ok((x, retry_count, checksum.is_some())),
+                        },
+                        Err(e) => anyhow::anyhow!("{e}"),
+                    }
+                }
+                Err(e) if e.raw_response().is_some_and(|x| x.status().as_u16() == 412) => {
+                    anyhow::bail!(
+                        "s3://{}/{} changed during the download",
+                        self.bucket,
+                        self.key
+                    );
+                }
+                Err(e) => anyhow::anyhow!("{e}{}", request_ids(&e)),
+            };
+            drop(connection);
+            retry_count += 1;
+            if retry_count > self.retry_policy.max_retries || !self.retry_policy.take_from_budget()
+            {
+                anyhow::bail!("Failed downloading bytes {start}-{}: {e}", end - 1);
+            }
+            let backoff = Duration::from_secs(2_u64.pow(retry_count));
+            tracing::warn!(
+                "Downloading bytes {start}-{} failed, retrying in {}s: {e}",
+                end - 1,
+                backoff.as_secs()
+            );
+            tokio::time::sleep(backoff).await;
+        }
+    }
+}
+
+/// The checksum of a response that covers exactly its bytes, as `ChecksumAlgorithm::digest` has
+/// it
+///
+/// A checksum of a multipart object ending in `-<parts>` is one of the part checksums, which the
+/// bytes cannot be checked against.
+fn stored_checksum(checksums: [&Option<String>; 5]) -> Option<(ChecksumAlgorithm, String)> {
+    use clap::ValueEnum;
+
+    let algorithms = ChecksumAlgorithm::value_variants().iter().copied();
+    let mut stored = algorithms.zip(checksums).filter_map(|(algorithm, x)| {
+        let x = x.as_ref().filter(|x| !x.contains('-'))?;
+        Some((algorithm, x.clone()))
+    });
+    stored.next()
+}
+
+async fn bench(args: &Args) -> anyhow::Result<()> {
+    let counts = match args.bench_concurrency.is_empty() {
+        true => vec![args.max_inflight.unwrap_or(2 * args.threads)],
+        false => args.bench_concurrency.clone(),
+    };
+    if counts.contains(&0) {
+        anyhow::bail!("At least one part has to be in flight");
+    }
+    for s3_path in &args.s3_paths {
+        let (bucket, prefix) = parse_s3_path(s3_path)?;
+        check_bucket_name(args, &bucket)?;
+        let profile = args.profile.first().cloned();
+        let config = load_config(&bucket, profile.as_deref()).await?;
+        check_credentials(&config, &bucket).await?;
+        println!(
+            "{s3_path}: {} in parts of {}",
+            format_size(args.bench_size),
+            format_size(args.block_size)
+        );
+        println!(
+            "{:>9} {:>12} {:>8} {:>8} {:>8} {:>8}",
+            "in flight", "throughput", "p50", "p90", "max", "retries"
+        );
+        let mut best = None;
+        for num_tokens in &counts {
+            let run = bench_run(args, &bucket, &prefix, &profile, &config, *num_tokens).await?;
+            let rate = args.bench_size as f64 / run.duration.as_secs_f64().max(f64::EPSILON);
+            let percentile = |x: f64| {
+                let index = ((run.part_durations.len().max(1) - 1) as f64 * x).round() as usize;
+                let duration = run.part_durations.get(index).copied().unwrap_or_default();
+                format!("{:.2}s", duration.as_secs_f64())
+            };
+            println!(
+                "{:>9} {:>12} {:>8} {:>8} {:>8} {:>8}",
+                run.num_tokens,
+                format!("{}/s", format_size(rate as usize)),
+                percentile(0.5),
+                percentile(0.9),
+                percentile(1.0),
+                run.retries
+            );
+            if best.is_none_or(|(_, x)| rate > x) {
+                best = Some((run.num_tokens, rate));
+            }
+        }
+        if let (true, Some((num_tokens, _))) = (counts.len() > 1, best) {
+            println!("highest throughput with {num_tokens} parts in flight");
+        }
+    }
+    Ok(())
+}
+
+/// Uploads random data below `prefix` with `num_tokens` parts in flight, and deletes it again
+async fn bench_run(
+    args: &Args,
+    bucket: &str,
+    prefix: &str,
+    profile: &Option<String>,
+    config: &aws_config::SdkConfig,
+    num_tokens: usize,
+) -> anyhow::Result<BenchRun> {
+    let key = temporary_key(prefix);
+    let options = ObjectOptions::default();
+    let (config, upload_id) = start_upload(bucket, &key, config.clone(), None, &options).await?;
+    let destination = Destination {
+        bucket: bucket.to_string(),
+        key: key.clone(),
+        profile: profile.clone(),
+        config: Arc::new(std::sync::RwLock::new((0, config.clone()))),
+        upload_id,
+        sse_customer_key: None,
+        completed_parts: Vec::new(),
+    };
+    let destinations = Arc::new(vec![destination]);
+    let input = RandomInput {
+        remaining: args.bench_size,
+        // any seed but zero
+        state: 0x9e37_79b9_7f4a_7c15 ^ std::process::id() as u64,
+    };
+    let start = std::time::Instant::now();
+    let uploaded = upload(
+        args,
+        destinations.clone(),
+        Input::Reader(Box::new(input)),
+        None,
+        num_tokens,
+        Some(args.bench_size),
+        None,
+    )
+    .await;
+    let duration = start.elapsed();
+    let mut uploaded = match uploaded {
+        Ok(x) if !x.interrupted => x,
+        result => {
+            abort_upload(&destinations[0]).await;
+            return Err(result.err().unwrap_or_else(|| Interrupted.into()));
+        }
+    };
+    let parts = std::mem::take(&mut uploaded.parts[0]);
+    if let Err(e) = complete_upload(args, &destinations[0], parts, uploaded.bytes).await {
+        abort_upload(&destinations[0]).await;
+        return Err(e);
+    }
+    let client = s3_client(&config);
+    if let Err(e) = client.delete_object().bucket(bucket).key(&key).send().await {
+        tracing::warn!("Failed to delete s3://{bucket}/{key}: {e}");
+    }
+    uploaded.part_durations.sort();
+    Ok(BenchRun {
+        num_tokens,
+        duration,
+        part_durations: std::mem::take(&mut uploaded.part_durations),
+        retries: uploaded.retries,
+    })
+}
+
+/// The error to return for a failed upload once its cause is logged, keeping a passed deadline
+/// or a too low throughput
+fn failed_upload(e: anyhow::Error) -> anyhow::Error {
+    match e.is::<DeadlineExceeded>() || e.is::<ThroughputTooLow>() {
+        true => e,
+        false => anyhow::anyhow!("Failed upload"),
+    }
+}
+
+/// Asks a yes or no question on the terminal, failing unless the answer is yes
+fn confirm(args: &Args, question: &str) -> anyhow::Result<()> {
+    use std::io::IsTerminal;
+
+    // stdin is the input if there is no input file or URL
+    let stdin_input = args.input.is_none() && args.input_url.is_none();
+    if stdin_input || !std::io::stdin().is_terminal() {
+        anyhow::bail!(
+            "--interactive cannot ask '{question}' if stdin is not a terminal, pass --yes to upload \
+            anyway"
+        );
+    }
+    match ask(question)? {
+        true => Ok(()),
+        false => anyhow::bail!("Upload cancelled"),
+    }
+}
+
+/// Asks a yes or no question on stdin, returning whether the answer is yes
+fn ask(question: &str) -> std::io::Result<bool> {
+    log_write(format!("{question} [y/N] ").into_bytes());
+    flush_log();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+/// Asks before a large upload and before overwriting existing objects, for --interactive
+async fn confirm_upload(
+    args: &Args,
+    paths: &[(String, String)],
+    configs: &[aws_config::SdkConfig],
+    finalize_as: &[Option<String>],
+) -> anyhow::Result<()> {
+    let metadata = args.input.as_ref().and_then(|x| std::fs::metadata(x).ok());
+    let size = match metadata {
+        Some(x) if x.is_file() => Some(x.len() as usize),
+        _ => args.expected_size,
+    };
+    if let Some(size) = size.filter(|x| *x > args.confirm_above) {
+        let question = format!(
+            "Upload {} to {} destinations?",
+            format_size(size),
+            paths.len()
+        );
+        confirm(args, &question)?;
+    }
+
+    // the final key is not known yet, or the object is meant to be replaced
+    if args.content_addressed || args.append {
+        return Ok(());
+    }
+    let sse_customer_key = args
+        .sse_c_key_file
+        .as_deref()
+        .map(SseCustomerKey::read)
+        .transpose()?;
+    for (((bucket, key), config), finalize_as) in paths.iter().zip(configs).zip(finalize_as) {
+        let key = finalize_as.as_ref().unwrap_or(key);
+        let remote = remote_etag(bucket, key, config.clone(), sse_customer_key.as_ref()).await;
+        if remote.is_some() {
+            confirm(args, &format!("Overwrite s3://{bucket}/{key}?"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Uploads the input to all S3 paths of the arguments
+async fn upload_input(args: &Args) -> anyhow::Result<()> {
+    let start = std::time::Instant::now();
+    // a failed upload must not leave the ETag of an earlier one behind
+    if let Some(path) = &args.etag_file {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                anyhow::bail!("Failed to remove old ETag file: {e}")
+            }
+            _ => (),
+        }
+    }
+    let mut paths = args
+        .s3_paths
+        .iter()
+        .map(|x| parse_s3_path(x))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for (bucket, _) in &paths {
+        check_bucket_name(args, bucket)?;
+    }
+    if args.append && paths.len() != 1 {
+        anyhow::bail!("--append needs a single S3 path");
+    }
+    if args.key_template {
+        let placeholders = key_placeholders(args.input.as_deref());
+        for (_, key) in &mut paths {
+            *key = expand_placeholders(key, &placeholders)?;
+        }
+    }
+    if args.random_suffix {
+        let token = random_token();
+        for (bucket, key) in &mut paths {
+            *key = add_key_suffix(key, &token);
+            if !args.quiet {
+                status!("uploading to s3://{bucket}/{key}");
+            }
+        }
+    }
+
+    let profiles = match args.profile.len() {
+        0 => vec![None; paths.len()],
+        1 => vec![Some(args.profile[0].clone()); paths.len()],
+        n if n == paths.len() => args.profile.iter().cloned().map(Some).collect(),
+        n => anyhow::bail!(
+            "Got {n} profiles for {} destinations, pass one for all or one per destination",
+            paths.len()
+        ),
+    };
+    let finalize_as = match args.finalize_as.len() {
+        0 => vec![None; paths.len()],
+        1 => vec![Some(args.finalize_as[0].clone()); paths.len()],
+        n if n == paths.len() => args.finalize_as.iter().cloned().map(Some).collect(),
+        n => anyhow::bail!(
+            "Got {n} --finalize-as keys for {} destinations, pass one for all or one per destination",
+            paths.len()
+        ),
+    };
+    let finalize_if_match = match args.finalize_if_match.len() {
+        0 => vec![None; paths.len()],
+        1 => vec![Some(args.finalize_if_match[0].clone()); paths.len()],
+        n if n == paths.len() => args.finalize_if_match.iter().cloned().map(Some).collect(),
+        n => anyhow::bail!(
+            "Got {n} --finalize-if-match conditions for {} destinations, pass one for all or one \
+            per destination",
+            paths.len()
+        ),
+    };
+    let mut configs = Vec::new();
+    for ((bucket, _), profile) in paths.iter().zip(&profiles) {
+        let mut config = load_config(bucket, profile.as_deref()).await?;
+        check_credentials(&config, bucket).await?;
+        if let Some(suffix) = &args.user_agent_suffix {
+            config = config.into_builder().app_name(suffix.clone()).build();
+        }
+        configs.push(config);
+    }
+
+    let metadata = args.input.as_ref().and_then(|x| std::fs::metadata(x).ok());
+    let single_put = args.no_multipart
+        || metadata.is_some_and(|x| x.is_file() && etag_block_size(args, x.len()).is_none());
+
+    if args.skip_if_same {
+        let (input, size) = match args.input.as_ref().map(|x| (x, std::fs::metadata(x))) {
+            Some((x, Ok(metadata))) if metadata.is_file() => (x, metadata.len()),
+            _ => anyhow::bail!("--skip-if-same needs a regular file as input"),
+        };
+        // hashing the whole file would hold up a worker of the runtime
+        let (input, block_size) = (input.clone(), etag_block_size(args, size));
+        let etag = tokio::task::spawn_blocking(move || local_etag(&input, block_size)).await??;
+        let sse_customer_key = args
+            .sse_c_key_file
+            .as_deref()
+            .map(SseCustomerKey::read)
+            .transpose()?;
+        let mut same = true;
+        for (((bucket, key), config), finalize_as) in paths.iter().zip(&configs).zip(&finalize_as) {
+            let key = finalize_as.as_ref().unwrap_or(key);
+            let remote = remote_etag(bucket, key, config.clone(), sse_customer_key.as_ref()).await;
+            if remote.as_deref() != Some(etag.as_str()) {
+                same = false;
+                break;
+            }
+        }
+        if same {
+            if let Some(path) = &args.etag_file {
+                write_etag_file(path, &vec![etag; paths.len()])?;
+            }
+            for (bucket, key) in &paths {
+                record_skipped(args, bucket, key);
+            }
+            if !args.quiet {
+                status!("skipped upload, all destinations already have the same object");
+            }
+            return Ok(());
+        }
+    }
+
+    if !single_put && (args.content_sha256.is_some() || args.content_crc32.is_some()) {
+        anyhow::bail!(
+            "--content-sha256 and --content-crc32 only apply to uploads with a single PUT, pass \
+            --no-multipart"
+        );
+    }
+    if args.interactive && !args.yes {
+        confirm_upload(args, &paths, &configs, &finalize_as).await?;
+    }
+
+    if single_put {
+        if args.resume.is_some() {
+            anyhow::bail!(
+                "--resume needs a multipart upload, the input is below --multipart-threshold"
+            );
+        }
+        return put_objects(args, paths, configs, start).await;
+    }
+
+    let mut read_timeout = args.read_timeout;
+    let mut total_size = args.expected_size;
+    let mut seekable = false;
+    let mut input = if let Some(split) = SPLIT.get() {
+        total_size = split.object_size();
+        if split.regular_file {
+            read_timeout = None;
+        }
+        Input::Reader(Box::new(SplitReader(split)))
+    } else if let (true, Some(dir)) = (args.tar, &args.input) {
+        if !dir.is_dir() {
+            anyhow::bail!("--tar needs a directory as input");
+        }
+        tracing::info!("Uploading a tar archive of {}", dir.display());
+        read_timeout = None;
+        Input::Reader(Box::new(TarStream::new(dir)?))
+    } else if let Some(file) = &args.input {
+        let file = match std::fs::File::open(file) {
+            Err(e) => {
+                status!("Failed to open input file: {}", e);
+                exit(1);
+            }
+            Ok(x) => x,
+        };
+        let metadata = file.metadata()?;
+        if metadata.is_file() {
+            read_timeout = None;
+            // a followed file is still growing, so only the expected size is known
+            if !args.follow {
+                total_size = Some(metadata.len() as usize);
+            }
+            seekable = true;
+        } else if args.sparse {
+            tracing::warn!("--sparse needs a regular file as input, reading it as usual");
+        }
+        if args.follow {
+            Input::Reader(Box::new(FollowFile {
+                file,
+                idle_timeout: args.idle_timeout,
+                finish: watch_hangup(),
+                last_data: std::time::Instant::now(),
+            }))
+        } else if args.sparse && seekable {
+            Input::Reader(sparse_file(file, metadata.len()))
+        } else if args.mmap {
+            if !seekable {
+                anyhow::bail!("--mmap needs a regular file as input");
+            }
+            Input::map(file)?
+        } else {
+            Input::Reader(Box::new(file))
+        }
+    } else if let Some(url) = &args.input_url {
+        let (body, length) = open_url(url)?;
+        total_size = length.or(total_size);
+        Input::Reader(body)
+    } else {
+        grow_stdin_pipe();
+        Input::Reader(stdin_reader())
+    };
+
+    if args.verify_sample.is_some() && !seekable {
+        anyhow::bail!("--verify-sample needs a regular file as input, to read it again");
+    }
+
+    if let Some(total) = total_size {
+        check_input_size(args, total)?;
+    }
+
+    let num_tokens = args.max_inflight.unwrap_or(2 * args.threads);
+    if num_tokens == 0 {
+        anyhow::bail!("At least one part has to be in flight");
+    }
+    if args.ordered == Some(0) {
+        anyhow::bail!("The --ordered window has to be at least one part");
+    }
+    if args.read_ahead == 0 {
+        anyhow::bail!("At least one part has to be read ahead");
+    }
+    if args.read_block_size.is_some_and(|x| x < args.block_size) {
+        anyhow::bail!("--read-block-size has to be at least --block-size");
+    }
+    tracing::info!(
+        "Uploading parts of {} with {} threads, {num_tokens} parts in flight",
+        format_size(args.block_size),
+        args.threads
+    );
+    tracing::info!(
+        "Reading {} parts ahead, buffering up to {}",
+        read_ahead(args, num_tokens),
+        format_size(
+            (read_ahead(args, num_tokens) + num_tokens) * args.block_size
+                + args.read_block_size.unwrap_or_default()
+        )
+    );
+    let max_connections = num_tokens * paths.len();
+    match args.max_connections {
+        Some(x) if x < max_connections => {
+            tracing::info!("At most {x} connections, instead of {max_connections}")
+        }
+        _ => tracing::info!("At most {max_connections} connections"),
+    }
+
+    let trace: Option<Box<dyn std::io::Write>> = match &args.trace_out {
+        None => None,
+        Some(path) if path.as_os_str() == "-" => Some(Box::new(std::io::stderr())),
+        Some(path) => match std::fs::File::create(path) {
+            Ok(x) => Some(Box::new(std::io::BufWriter::new(x))),
+            Err(e) => anyhow::bail!("Failed to create trace file: {e}"),
+        },
+    };
+
+    let sse_customer_key = args
+        .sse_c_key_file
+        .as_deref()
+        .map(SseCustomerKey::read)
+        .transpose()?;
+    let options = ObjectOptions::new(args)?;
+
+    let mut state = match (&args.state_dir, &args.input) {
+        (Some(dir), Some(input)) => Some(UploadState::new(dir, input, &paths, args.block_size)?),
+        (Some(_), None) => anyhow::bail!("--state-dir needs a regular file as input"),
+        _ => None,
+    };
+    let resumed = match &state {
+        Some(state) => match state.load()? {
+            Some(uploads) => {
+                let sse = sse_customer_key.as_ref();
+                resume_uploads(args, state, uploads, &paths, &configs, &profiles, sse).await?
+            }
+            None => None,
+        },
+        None => None,
+    };
+
+    // start multi-part uploads
+    let prefixes: Vec<_> = paths.iter().map(|(_, key)| key.clone()).collect();
+    let mut destinations = Vec::new();
+    if let Some(resumed) = resumed {
+        destinations = resumed;
+    } else {
+        // a followed file is still growing, and --append needs the copied parts
+        let mut put_fallback = (!args.no_multipart_fallback && !args.follow && !args.append)
+            .then(|| (paths.clone(), configs.clone()));
+        for (((bucket, key), config), profile) in paths.into_iter().zip(configs).zip(profiles) {
+            let key = match args.content_addressed {
+                true => temporary_key(&key),
+                false => key,
+            };
+            let sse = sse_customer_key.as_ref();
+            match handle_existing_uploads(args, &bucket, &key, &config, &profile, sse).await {
+                Ok(Some(destination)) => {
+                    destinations.push(destination);
+                    continue;
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    futures::future::join_all(destinations.iter().map(abort_upload)).await;
+                    return Err(e);
+                }
+            }
+            match start_upload(&bucket, &key, config, sse_customer_key.as_ref(), &options).await {
+                Ok((config, upload_id)) => destinations.push(Destination {
+                    bucket,
+                    key,
+                    profile,
+                    config: Arc::new(std::sync::RwLock::new((0, config))),
+                    upload_id,
+                    sse_customer_key: sse_customer_key.clone(),
+                    completed_parts: Vec::new(),
+                }),
+                Err(e) => {
+                    futures::future::join_all(destinations.iter().map(abort_upload)).await;
+                    if let (true, Some((paths, configs))) =
+                        (e.is::<MultipartUnsupported>(), put_fallback.take())
+                    {
+                        tracing::warn!(
+                            "Bucket '{bucket}' does not support multipart uploads, uploading with \
+                            a single PUT instead"
+                        );
+                        return put_objects(args, paths, configs, start).await;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        if let Some(state) = &mut state {
+            if let Err(e) = state.save(&destinations) {
+                tracing::warn!("Failed to write state file, the upload cannot be resumed: {e}");
+            }
+        }
+    }
+    if args.append {
+        // --append only allows a single destination
+        match copy_existing(&destinations[0]).await {
+            Ok(Existing::Parts(parts)) => destinations[0].completed_parts = parts,
+            Ok(Existing::Content(data)) => {
+                total_size = total_size.map(|x| x + data.len());
+                input = input.prepend(data)?;
+            }
+            Err(e) => {
+                futures::future::join_all(destinations.iter().map(abort_upload)).await;
+                return Err(e);
+            }
+        }
+    }
+    let destinations = Arc::new(destinations);
+
+    let uploaded = upload(
+        args,
+        destinations.clone(),
+        input,
+        read_timeout,
+        num_tokens,
+        total_size,
+        trace,
+    );
+    let uploaded = before_deadline(uploaded)
+        .await
+        .unwrap_or_else(|e| Err(e.into()));
+    let uploaded = match uploaded {
+        Ok(x) => x,
+        Err(e) if state.is_some() => {
+            tracing::error!("Upload failed, run again with the same --state-dir to resume: {e}");
+            return Err(failed_upload(e));
+        }
+        Err(e) => {
+            let failure = e.downcast_ref::<UploadFailure>();
+            let reason = failure.map_or(FailureReason::Other, |x| x.reason);
+            let part_number = failure.and_then(|x| x.part_number);
+            match args.keep_on_failure {
+                true => tracing::error!(%reason, part_number, "Upload failed: {e}"),
+                false => tracing::error!(%reason, part_number, "Aborting upload: {e}"),
+            }
+            if let OutputFormat::AwsJson = args.output {
+                print_json(aws_json_error(reason, part_number, &e.to_string()))?;
+            }
+            match args.keep_on_failure {
+                true => futures::future::join_all(destinations.iter().map(keep_upload)).await,
+                false => futures::future::join_all(destinations.iter().map(abort_upload)).await,
+            };
+            return Err(failed_upload(e));
+        }
+    };
+
+    if uploaded.interrupted {
+        match args.on_signal {
+            _ if state.is_some() => {
+                tracing::warn!("Kept upload, run again with the same --state-dir to resume");
+            }
+            SignalAction::Abort => {
+                futures::future::join_all(destinations.iter().map(abort_upload)).await;
+            }
+            SignalAction::Keep => {
+                for (destination, parts) in destinations.iter().zip(&uploaded.parts) {
+                    print_kept_upload(destination, parts.len());
+                }
+            }
+        }
+        return Err(Interrupted.into());
+    }
+
+    if let Some(sidecar) = &options.sidecar {
+        if let Err(e) = check_sidecar(args, sidecar, &uploaded) {
+            tracing::error!("Aborting upload: {e}");
+            futures::future::join_all(destinations.iter().map(abort_upload)).await;
+            return Err(failed_upload(e));
+        }
+    }
+    if uploaded.num_parts == 0 {
+        futures::future::join_all(destinations.iter().map(abort_upload)).await;
+        if let Some(state) = &state {
+            state.remove();
+        }
+        if skip_empty_input(args)? {
+            for destination in destinations.iter() {
+                record_skipped(args, &destination.bucket, &destination.key);
+            }
+            return Ok(());
+        }
+    }
+
+    // the part checksums are gone once the parts are passed to the completion
+    let expected_checksums: Vec<_> = (uploaded.parts.iter())
+        .map(|x| {
+            let full_object = uploaded.checksum.as_deref();
+            (args.checksum_algorithm.is_some()).then(|| expected_checksum(args, x, full_object))
+        })
+        .collect();
+
+    // finalize uploads, each destination independently of the others, failing dropped ones
+    let bytes = uploaded.bytes;
+    let results = futures::future::join_all(
+        (destinations
+            .iter()
+            .zip(uploaded.parts)
+            .zip(uploaded.dropped))
+        .map(|((destination, parts), dropped)| async move {
+            match dropped {
+                Some(e) => Err(e),
+                None => complete_upload(args, destination, parts, bytes).await,
+            }
+        }),
+    )
+    .await;
+
+    let mut failed = 0;
+    let mut uploaded_keys = Vec::new();
+    let mut etags = Vec::new();
+    for (((((destination, result), prefix), finalize_as), if_match), expected_checksum) in
+        destinations
+            .iter()
+            .zip(results)
+            .zip(&prefixes)
+            .zip(&finalize_as)
+            .zip(&finalize_if_match)
+            .zip(expected_checksums)
+    {
+        let mut result = match result {
+            Ok(x) => x,
+            Err(e) if args.keep_on_failure => {
+                tracing::error!(
+                    upload_id = destination.upload_id,
+                    "Upload to {destination} failed: {e}"
+                );
+                keep_upload(destination).await;
+                record_failure(args, &destination.bucket, &destination.key, start, &e);
+                failed += 1;
+                continue;
+            }
+            Err(e) => {
+                tracing::error!(
+                    upload_id = destination.upload_id,
+                    "Aborting upload to {destination}: {e}"
+                );
+                abort_upload(destination).await;
+                record_failure(args, &destination.bucket, &destination.key, start, &e);
+                failed += 1;
+                continue;
+            }
+        };
+        result.retries = uploaded.retries;
+        // the checksum of the completion is free to check, some backends do not send one though
+        if let (Some(Ok(expected)), Some(actual)) = (&expected_checksum, &result.checksum) {
+            if actual != expected {
+                let e = format!(
+                    "{destination} was completed with checksum {actual}, expected {expected}"
+                );
+                let e = anyhow::Error::from(Mismatch(e));
+                tracing::error!("Checksum verification failed: {e}");
+                delete_mismatched(args, destination, &result).await;
+                record_failure(args, &destination.bucket, &destination.key, start, &e);
+                failed += 1;
+                continue;
+            }
+        }
+        if let (true, Some(expected)) = (args.post_verify_checksum, expected_checksum) {
+            let verified = match expected {
+                Ok(expected) => {
+                    verify_with_retries(args, || verify_checksum(destination, &expected)).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = verified {
+                tracing::error!("Checksum verification failed: {e}");
+                // an object that could not be read back may still be fine
+                if e.is::<Mismatch>() {
+                    delete_mismatched(args, destination, &result).await;
+                }
+                record_failure(args, &destination.bucket, &destination.key, start, &e);
+                failed += 1;
+                continue;
+            }
+            if !args.quiet {
+                status!("verified the checksum of {destination}");
+            }
+        }
+
+        let (final_key, if_match) = match &uploaded.digest {
+            Some(digest) if args.content_addressed => (Some(join_key(prefix, digest)), None),
+            _ => (finalize_as.clone(), if_match.as_deref()),
+        };
+        let key = match final_key {
+            None => destination.key.clone(),
+            Some(final_key) => {
+                let moved = move_object(destination, &final_key, if_match, &mut result, &options);
+                if let Err(e) = moved.await {
+                    tracing::error!("{e}");
+                    record_failure(args, &destination.bucket, &final_key, start, &e);
+                    failed += 1;
+                    continue;
+                }
+                final_key
+            }
+        };
+        if let (Some(count), Some(input)) = (args.verify_sample, &args.input) {
+            let parts = sample_parts(uploaded.num_parts, count);
+            let verify = || {
+                let (block_size, bytes) = (args.block_size, uploaded.bytes);
+                verify_parts(destination, &key, input, block_size, bytes, &parts)
+            };
+            if let Err(e) = verify_with_retries(args, verify).await {
+                tracing::error!("Verification failed: {e}");
+                record_failure(args, &destination.bucket, &key, start, &e);
+                failed += 1;
+                continue;
+            }
+            if !args.quiet {
+                let parts: Vec<_> = parts.iter().map(|x| x.to_string()).collect();
+                status!(
+                    "verified parts {} of s3://{}/{key}",
+                    parts.join(", "),
+                    destination.bucket
+                );
+            }
+        }
+        on_success(
+            args,
+            &destination.config(),
+            &destination.bucket,
+            &key,
+            &result,
+            start,
+        )
+        .await?;
+        uploaded_keys.push(key);
+        etags.push(result.etag);
+    }
+    if let (Some(path), Some(digest)) = (&args.write_checksum, &uploaded.digest) {
+        tracing::info!("SHA-256 of the input is {digest}");
+        write_checksum(path, args.checksum_format, digest, &uploaded_keys)?;
+    }
+    if let Some(state) = &state {
+        state.remove();
+    }
+    match failed {
+        0 => {}
+        n if destinations.len() > 1 => {
+            anyhow::bail!(
+                "Failed upload to {n} of {} destinations",
+                destinations.len()
+            )
+        }
+        _ => anyhow::bail!("Failed upload"),
+    }
+    if let Some(path) = &args.etag_file {
+        write_etag_file(path, &etags)?;
+    }
+
+    print_summary(
+        args,
+        uploaded.bytes,
+        uploaded.num_parts,
+        uploaded.retries,
+        start,
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Resolves once SIGINT or SIGTERM is received
+async fn wait_for_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            x = tokio::signal::ctrl_c() => x,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// Sets the returned flag on the first signal, and exits on the second one
+fn watch_signals() -> tokio::sync::watch::Receiver<bool> {
+    let (sender, receiver) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        if let Err(e) = wait_for_signal().await {
+            tracing::warn!("Failed to install signal handler: {e}");
+            return;
+        }
+        tracing::warn!("Interrupted, waiting for parts in flight, signal again to exit now");
+        let _ = sender.send(true);
+        if wait_for_signal().await.is_ok() {
+            exit(130);
+        }
+    });
+    receiver
+}
+
+/// What --state-dir records about a multipart upload, to resume it when run again
+#[derive(serde::Serialize, serde::Deserialize)]
+struct UploadState {
+    /// Input file with its size and modification time, to notice a changed input
+    input: PathBuf,
+    size: u64,
+    modified: Duration,
+    block_size: usize,
+    s3_paths: Vec<String>,
+    /// Key and upload ID of the upload to each S3 path
+    uploads: Vec<(String, String)>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl UploadState {
+    fn new(
+        dir: &std::path::Path,
+        input: &std::path::Path,
+        paths: &[(String, String)],
+        block_size: usize,
+    ) -> anyhow::Result<Self> {
+        let input = std::fs::canonicalize(input)?;
+        let metadata = std::fs::metadata(&input)?;
+        if !metadata.is_file() {
+            anyhow::bail!("--state-dir needs a regular file as input");
+        }
+        let modified = metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let s3_paths: Vec<_> = paths
+            .iter()
+            .map(|(bucket, key)| format!("s3://{bucket}/{key}"))
+            .collect();
+        // one file per input and destinations, so different uploads never share state
+        let mut hasher = Sha256::new();
+        hasher.update(input.as_os_str().as_encoded_bytes());
+        for x in &s3_paths {
+            hasher.update([0]);
+            hasher.update(x);
+        }
+        Ok(Self {
+            path: dir.join(format!("{:x}.json", hasher.finalize())),
+            input,
+            size: metadata.len(),
+            modified,
+            block_size,
+            s3_paths,
+            uploads: Vec::new(),
+        })
+    }
+
+    /// Returns the uploads of an earlier run, if it recorded one for the same unchanged input
+    ///
+    /// Fails if the recorded upload used a different block size, as its parts would not line up.
+    fn load(&self) -> anyhow::Result<Option<Vec<(String, String)>>> {
+        let Ok(content) = std::fs::read_to_string(&self.path) else {
+            return Ok(None);
+        };
+        let state: UploadState = match serde_json::from_str(&content) {
+            Ok(x) => x,
+            Err(e) => {
+                tracing::warn!("Ignoring invalid state file {}: {e}", self.path.display());
+                return Ok(None);
+            }
+        };
+        if (&state.input, state.size, state.modified, &state.s3_paths)
+            != (&self.input, self.size, self.modified, &self.s3_paths)
+            || state.uploads.len() != self.s3_paths.len()
+        {
+            tracing::info!("Input changed since the recorded upload, starting over");
+            return Ok(None);
+        }
+        if state.block_size != self.block_size {
+            anyhow::bail!(
+                "The recorded upload uses a --block-size of {}, resume it with the same block size \
+                or remove {} to start over",
+                format_size(state.block_size),
+                self.path.display()
+            );
+        }
+        Ok(Some(state.uploads))
+    }
+
+    /// Checks that the parts of a resumed upload have the sizes this block size gives them
+    fn check_parts(
+        &self,
+        destination: &Destination,
+        parts: &[s3::types::Part],
+    ) -> anyhow::Result<()> {
+        let block_size = self.block_size as u64;
+        for part in parts {
+            let (Some(number), Some(size)) = (part.part_number, part.size) else {
+                continue;
+            };
+            let offset = (number.max(1) as u64 - 1) * block_size;
+            let expected = self.size.saturating_sub(offset).min(block_size);
+            if size as u64 != expected {
+                anyhow::bail!(
+                    "Part {number} of the upload to {destination} has {}, but a --block-size of {} \
+                    gives {}, resume it with the block size it was started with or remove {} to \
+                    start over",
+                    format_size(size as usize),
+                    format_size(self.block_size),
+                    format_size(expected as usize),
+                    self.path.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, destinations: &[Destination]) -> anyhow::Result<()> {
+        self.uploads = destinations
+            .iter()
+            .map(|x| (x.key.clone(), x.upload_id.clone()))
+            .collect();
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn remove(&self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("Failed to remove state file {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Reads all state files of a --state-dir, skipping invalid ones
+fn read_states(dir: &std::path::Path) -> anyhow::Result<Vec<UploadState>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => anyhow::bail!("Failed to read state directory {}: {e}", dir.display()),
+    };
+    let mut states = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|x| x != "json") {
+            continue;
+        }
+        let parsed = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| Ok(serde_json::from_str::<UploadState>(&x)?));
+        match parsed {
+            Ok(state) => states.push(UploadState { path, ..state }),
+            Err(e) => tracing::warn!("Ignoring invalid state file {}: {e}", path.display()),
+        }
+    }
+    Ok(states)
+}
+
+/// Checks that the input of a recorded upload is still the same file
+fn check_recorded_input(state: &UploadState) -> anyhow::Result<()> {
+    let paths = state
+        .s3_paths
+        .iter()
+        .map(|x| parse_s3_path(x))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let dir = state.path.parent().unwrap_or(std::path::Path::new("."));
+    let current = match UploadState::new(dir, &state.input, &paths, state.block_size) {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("Cannot read {}: {e}", state.input.display()),
+    };
+    if (current.size, current.modified) != (state.size, state.modified) {
+        anyhow::bail!("{} changed since the upload started", state.input.display());
+    }
+    Ok(())
+}
+
+/// Resumes the unfinished uploads below the S3 paths that --state-dir has a record of, and asks
+/// whether to abort the others, for --resume-incomplete
+async fn resume_incomplete(args: &Args) -> anyhow::Result<()> {
+    use std::io::IsTerminal;
+
+    let states = match &args.state_dir {
+        Some(dir) => read_states(dir)?,
+        None => Vec::new(),
+    };
+    let sse_customer_key = args
+        .sse_c_key_file
+        .as_deref()
+        .map(SseCustomerKey::read)
+        .transpose()?;
+    let profile = args.profile.first().cloned();
+    let mut resumed = std::collections::BTreeSet::new();
+    let mut failed = Vec::new();
+    for s3_path in &args.s3_paths {
+        let (bucket, prefix) = parse_s3_path(s3_path)?;
+        check_bucket_name(args, &bucket)?;
+        let config = load_config(&bucket, profile.as_deref()).await?;
+        let uploads = match uploads_below(&bucket, &prefix, &config).await {
+            Ok(x) => x,
+            Err(e) => anyhow::bail!("Cannot list uploads below {s3_path}: {e}"),
+        };
+        if !args.quiet {
+            status!("{} unfinished uploads below {s3_path}", uploads.len());
+        }
+        for upload in uploads {
+            let (Some(key), Some(upload_id)) = (upload.key(), upload.upload_id()) else {
+                continue;
+            };
+            let initiated = match upload.initiated() {
+                Some(x) => format!(" from {x}"),
+                None => String::new(),
+            };
+            let recorded = states.iter().enumerate().find(|(_, state)| {
+                let mut uploads = state.uploads.iter();
+                uploads.any(|(k, id)| k == key && id == upload_id)
+            });
+            let source = match recorded {
+                // all destinations of a record are resumed together
+                Some((i, _)) if resumed.contains(&i) => continue,
+                Some((i, state)) => match check_recorded_input(state) {
+                    Ok(()) => Some((i, state)),
+                    Err(e) => {
+                        tracing::warn!("Cannot resume upload {upload_id}: {e}");
+                        None
+                    }
+                },
+                None => None,
+            };
+            let Some((i, state)) = source else {
+                let destination = Destination {
+                    bucket: bucket.clone(),
+                    key: key.to_string(),
+                    profile: profile.clone(),
+                    config: Arc::new(std::sync::RwLock::new((0, config.clone()))),
+                    upload_id: upload_id.to_string(),
+                    sse_customer_key: sse_customer_key.clone(),
+                    completed_parts: Vec::new(),
+                };
+                if args.dry_run {
+                    status!("{destination}: upload {upload_id}{initiated} has no local source");
+                } else if std::io::stdin().is_terminal()
+                    && ask(&format!(
+                        "Abort upload {upload_id} to {destination}{initiated}, it has no local \
+                        source?"
+                    ))?
+                {
+                    abort_upload(&destination).await;
+                } else {
+                    let parts = list_parts(&destination).await.map_or(0, |x| x.len());
+                    print_kept_upload(&destination, parts);
+                }
+                continue;
+            };
+            if args.dry_run {
+                status!(
+                    "s3://{bucket}/{key}: upload {upload_id}{initiated} can be resumed from {}",
+                    state.input.display()
+                );
+                continue;
+            }
+            resumed.insert(i);
+            if !args.quiet {
+                status!(
+                    "resuming upload {upload_id} to s3://{bucket}/{key} from {}",
+                    state.input.display()
+                );
+            }
+            let resume_args = Args {
+                s3_paths: state.s3_paths.clone(),
+                input: Some(state.input.clone()),
+                block_size: state.block_size,
+                key_template: false,
+                random_suffix: false,
+                resume_incomplete: false,
+                ..args.clone()
+            };
+            if let Err(e) = upload_input(&resume_args).await {
+                tracing::error!("Failed to resume upload {upload_id} to s3://{bucket}/{key}: {e}");
+                failed.push(format!("s3://{bucket}/{key}"));
+            }
+        }
+    }
+    if !failed.is_empty() {
+        for s3_path in failed {
+            status!("failed: {s3_path}");
+        }
+        anyhow::bail!("Failed upload");
+    }
+    Ok(())
+}
+
+/// Continues the uploads recorded by an earlier run, or returns `None` if one of them cannot be
+/// continued
+///
+/// Parts whose ETag is not the MD5 of the same range of the input anymore are uploaded again.
+async fn resume_uploads(
+    args: &Args,
+    state: &UploadState,
+    uploads: Vec<(String, String)>,
+    paths: &[(String, String)],
+    configs: &[aws_config::SdkConfig],
+    profiles: &[Option<String>],
+    sse_customer_key: Option<&SseCustomerKey>,
+) -> anyhow::Result<Option<Vec<Destination>>> {
+    let mut destinations = Vec::new();
+    for ((((bucket, _), config), profile), (key, upload_id)) in
+        paths.iter().zip(configs).zip(profiles).zip(uploads)
+    {
+        let mut destination = Destination {
+            bucket: bucket.clone(),
+            key,
+            profile: profile.clone(),
+            config: Arc::new(std::sync::RwLock::new((0, config.clone()))),
+            upload_id,
+            sse_customer_key: sse_customer_key.cloned(),
+            completed_parts: Vec::new(),
+        };
+        let parts = match list_parts(&destination).await {
+            Ok(x) => x,
+            Err(e) => {
+                tracing::warn!(
+                    "Cannot resume upload {} to {destination}, starting over: {e}",
+                    destination.upload_id
+                );
+                return Ok(None);
+            }
+        };
+        state.check_parts(&destination, &parts)?;
+        // with SSE-C and SSE-KMS, ETags are not the MD5 of the content
+        if sse_customer_key.is_some() || args.sse_kms {
+            destination.completed_parts = parts.iter().map(completed_part).collect();
+            tracing::info!(
+                upload_id = destination.upload_id,
+                "Resuming upload to {destination}, the server has {} parts, which cannot be \
+                validated against the input with encryption",
+                parts.len()
+            );
+            destinations.push(destination);
+            continue;
+        }
+        destination.completed_parts =
+            match matching_parts(&state.input, state.block_size, &parts).await {
+                Ok(x) => x,
+                Err(e) => anyhow::bail!("Failed to read input file: {e}"),
+            };
+        let reused = destination.completed_parts.len();
+        match parts.len() - reused {
+            0 => tracing::info!(
+                upload_id = destination.upload_id,
+                "Resuming upload to {destination}, validated and reusing all {reused} parts"
+            ),
+            changed => tracing::warn!(
+                upload_id = destination.upload_id,
+                "Resuming upload to {destination}, validated {} parts: reusing {reused}, \
+                uploading {changed} again that no longer match the input",
+                parts.len()
+            ),
+        }
+        destinations.push(destination);
+    }
+    Ok(Some(destinations))
+}
+
+/// Prints how to finish an upload kept after a signal
+fn print_kept_upload(destination: &Destination, num_parts: usize) {
+    status!(
+        "Kept upload {} to {destination} with {num_parts} parts, continue it with \
+        `--resume {}` or remove it with \
+        `aws s3api abort-multipart-upload --bucket {} --key {} --upload-id {}`",
+        destination.upload_id,
+        destination.upload_id,
+        destination.bucket,
+        destination.key,
+        destination.upload_id
+    );
+}
+
+/// Prints how to continue a failed upload of --keep-on-failure
+async fn keep_upload(destination: &Destination) {
+    let parts = list_parts(destination).await.map_or(0, |x| x.len());
+    print_kept_upload(destination, parts);
+}
+
+/// Diagnostic output, written to stderr by a single thread so that lines from concurrent
+/// part tasks never interleave
+enum LogMessage {
+    Line(Vec<u8>),
+    Flush(std::sync::mpsc::Sender<()>),
+}
+
+static LOG: std::sync::OnceLock<std::sync::mpsc::Sender<LogMessage>> = std::sync::OnceLock::new();
+
+fn start_log_writer() {
+    let _ = LOG.set(log_writer(std::io::stderr()));
+}
+
+/// Spawns the thread that writes the queued lines to `output`
+fn log_writer(
+    mut output: impl std::io::Write + Send + 'static,
+) -> std::sync::mpsc::Sender<LogMessage> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for message in receiver {
+            match message {
+                LogMessage::Line(x) => {
+                    let _ = output.write_all(&x);
+                }
+                LogMessage::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+    sender
+}
+
+fn log_write(line: Vec<u8>) {
+    use std::io::Write;
+    let line = match LOG.get() {
+        Some(sender) => match sender.send(LogMessage::Line(line)) {
+            Ok(()) => return,
+            Err(std::sync::mpsc::SendError(LogMessage::Line(x))) => x,
+            Err(_) => return,
+        },
+        None => line,
+    };
+    let _ = std::io::stderr().write_all(&line);
+}
+
+/// Waits until all diagnostic output queued so far is written
+fn flush_log() {
+    if let Some(sender) = LOG.get() {
+        let (done, wait) = std::sync::mpsc::channel();
+        if sender.send(LogMessage::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+/// Exits the process without losing queued diagnostic output
+fn exit(code: i32) -> ! {
+    flush_log();
+    std::process::exit(code)
+}
+
+/// Buffers a single log event and queues it as a whole once the event is formatted
+struct EventBuffer(Vec<u8>);
+
+impl std::io::Write for EventBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventBuffer {
+    fn drop(&mut self) {
+        if !self.0.is_empty() {
+            log_write(std::mem::take(&mut self.0));
+        }
+    }
+}
+
+fn init_logging(args: &Args) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => "error",
+        (false, 0) => "warn",
+        (false, 1) => "info",
+        (false, 2) => "debug",
+        (false, _) => "trace",
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(format!("s3put={level}")));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(|| EventBuffer(Vec::new()))
+        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stderr()))
+        .without_time()
+        .with_target(false)
+        .init();
+}
+
+fn main() {
+    let mut args = Args::parse();
+    if args.part_size_ramp {
+        args.part_sizes = ramp_part_sizes(args.block_size);
+    }
+    start_log_writer();
+    init_logging(&args);
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(args.threads + 2) // we need 2 extra threads for blocking I/O
+        .enable_io()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    let result = rt.block_on(run(&args));
+    if let Some(leases) = GLOBAL_LEASES.get() {
+        leases.release(None);
+    }
+    if args.output == OutputFormat::Json {
+        if let Err(e) = print_file_results() {
+            status!("Error: Failed to print results: {e}");
+        }
+    }
+    if let Err(e) = result {
+        status!("Error: {}", e);
+        match e.is::<DeadlineExceeded>() {
+            true => exit(DEADLINE_EXIT_CODE),
+            false => exit(1),
+        }
+    }
+    flush_log();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+
+    const MB: usize = 1024 * 1024;
+
+    fn args(extra: &[&str]) -> Args {
+        let mut args = Args::parse_from(["s3put"].iter().chain(extra).chain(&["s3://bucket/key"]));
+        if args.part_size_ramp {
+            args.part_sizes = ramp_part_sizes(args.block_size);
+        }
+        args
+    }
+
+    /// An empty directory for a test, removed first if an earlier run left it behind
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("s3put-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1KB").unwrap(), 1024);
+        assert_eq!(parse_size("5mb").unwrap(), 5 * MB);
+        assert_eq!(parse_size("2GB").unwrap(), 2048 * MB);
+        assert!(parse_size("5").is_err());
+        assert!(parse_size("5TB").is_err());
+        assert!(parse_size("MB").is_err());
+    }
+
+    #[test]
+    fn block_sizes() {
+        let size = |x| check_block_size(parse_size(x).unwrap());
+        assert!(size("5MB").is_ok());
+        assert!(size("4GB").is_ok());
+        assert!(size("5GB").is_ok());
+        assert!(size("6GB").is_err());
+        assert!(size("4MB").is_err());
+        assert!(check_block_size(MIN_PART_SIZE - 1).is_err());
+        assert!(check_block_size(MAX_PART_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("5M").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("1.5s").is_err());
+    }
+
+    #[test]
+    fn placeholders() {
+        let placeholders = [
+            ("year", Ok("2024".to_string())),
+            ("basename", Err("it needs an input file".to_string())),
+        ];
+        let expand = |x| expand_placeholders(x, &placeholders);
+        assert_eq!(expand("backups/{year}/db").unwrap(), "backups/2024/db");
+        assert_eq!(expand("{year}{year}").unwrap(), "20242024");
+        assert_eq!(expand("no placeholders").unwrap(), "no placeholders");
+        let e = expand("{basename}").unwrap_err().to_string();
+        assert!(e.contains("it needs an input file"), "{e}");
+        let e = expand("{month}").unwrap_err().to_string();
+        assert!(e.contains("known are {year}, {basename}"), "{e}");
+        assert!(expand("{year").is_err());
+    }
+
+    #[test]
+    fn key_suffix() {
+        assert_eq!(add_key_suffix("dir/file.tar.gz", "x"), "dir/file-x.tar.gz");
+        assert_eq!(add_key_suffix("dir/file", "x"), "dir/file-x");
+        assert_eq!(add_key_suffix("dir.d/file", "x"), "dir.d/file-x");
+        assert_eq!(add_key_suffix(".bashrc", "x"), ".bashrc-x");
+        assert_eq!(add_key_suffix("dir/", "x"), "dir/-x");
+
+        let tokens: std::collections::HashSet<_> = (0..1000).map(|_| random_token()).collect();
+        assert_eq!(tokens.len(), 1000);
+        assert!(tokens
+            .iter()
+            .all(|x| x.len() == 16 && x.bytes().all(|x| x.is_ascii_hexdigit())));
+    }
+
+    #[test]
+    fn part_counts() {
+        let args = args(&["--block-size", "5MB"]);
+        assert_eq!(count_parts(&args, 0), 0);
+        assert_eq!(count_parts(&args, 5 * MB), 1);
+        assert_eq!(count_parts(&args, 5 * MB + 1), 2);
+
+        let args = self::args(&["--block-size", "5MB", "--part-sizes", "6MB,7MB"]);
+        assert_eq!(count_parts(&args, 20 * MB), 4);
+
+        let args = self::args(&["--part-sizes", "6MB,rest"]);
+        assert_eq!(count_parts(&args, 3 * MB), 1);
+        assert_eq!(count_parts(&args, 100 * MB), 2);
+    }
+
+    #[test]
+    fn part_limits() {
+        assert!(check_max_parts(0).is_err());
+        assert!(check_max_parts(1).is_ok());
+        assert!(check_max_parts(10000).is_ok());
+        assert!(check_max_parts(10001).is_err());
+
+        let args = args(&["--block-size", "5MB"]);
+        assert!(check_part_count(&args, 10000 * 5 * MB).is_ok());
+        let e = check_part_count(&args, 10000 * 5 * MB + 1).unwrap_err();
+        assert!(e.to_string().contains("needs 10001 parts"), "{e}");
+
+        let args = self::args(&["--block-size", "5MB", "--max-parts", "1"]);
+        assert!(check_part_count(&args, 5 * MB).is_ok());
+        assert!(check_part_count(&args, 5 * MB + 1).is_err());
+        let args = self::args(&["--block-size", "5MB", "--max-parts", "3"]);
+        assert!(check_part_count(&args, 15 * MB).is_ok());
+        let e = check_part_count(&args, 15 * MB + 1).unwrap_err();
+        assert!(
+            e.to_string().contains("--max-parts allows at most 3"),
+            "{e}"
+        );
+        let args = self::args(&["--max-parts", "2", "--part-sizes", "6MB,7MB,8MB"]);
+        assert!(check_part_count(&args, 13 * MB).is_ok());
+        assert!(check_part_count(&args, 13 * MB + 1).is_err());
+    }
+
+    #[test]
+    fn object_sizes() {
+        let args = args(&["--block-size", "1GB"]);
+        let max = MAX_OBJECT_SIZE as usize;
+        assert!(check_input_size(&args, max).is_ok());
+        let e = check_input_size(&args, max + 1).unwrap_err();
+        assert!(
+            e.to_string().contains("maximum size of an S3 object"),
+            "{e}"
+        );
+        // too many parts is reported as such below 5TB
+        let args = self::args(&["--block-size", "5MB"]);
+        let e = check_input_size(&args, max).unwrap_err();
+        assert!(e.to_string().contains("increase --block-size"), "{e}");
+    }
+
+    #[test]
+    fn part_plans() {
+        let args = args(&["--block-size", "5MB"]);
+        assert_eq!(plan_parts(&args, 0), [(0, 0)]);
+        assert_eq!(
+            plan_parts(&args, 12 * MB),
+            [(0, 5 * MB), (5 * MB, 5 * MB), (10 * MB, 2 * MB)]
+        );
+
+        let args = self::args(&["--block-size", "5MB", "--part-sizes", "6MB,7MB"]);
+        assert_eq!(
+            plan_parts(&args, 20 * MB),
+            [
+                (0, 6 * MB),
+                (6 * MB, 7 * MB),
+                (13 * MB, 5 * MB),
+                (18 * MB, 2 * MB)
+            ]
+        );
+
+        let args = self::args(&["--part-sizes", "6MB,rest"]);
+        assert_eq!(
+            plan_parts(&args, 100 * MB),
+            [(0, 6 * MB), (6 * MB, 94 * MB)]
+        );
+        assert_eq!(
+            plan_parts(&args, 100 * MB).len(),
+            count_parts(&args, 100 * MB)
+        );
+    }
+
+    #[test]
+    fn part_size_ramp() {
+        let args = args(&["--block-size", "32MB", "--part-size-ramp"]);
+        let sizes: Vec<_> = plan_parts(&args, 100 * MB).iter().map(|x| x.1).collect();
+        assert_eq!(sizes, [5 * MB, 10 * MB, 20 * MB, 32 * MB, 32 * MB, MB]);
+        assert_eq!(count_parts(&args, 100 * MB), 6);
+    }
+
+    #[test]
+    fn composite_checksum() {
+        let args = args(&["--checksum-algorithm", "sha256"]);
+        let data: [&[u8]; 2] = [b"first part", b"second part"];
+        let parts: Vec<_> = data
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                CompletedPart::builder()
+                    .part_number(i as i32 + 1)
+                    .checksum_sha256(ChecksumAlgorithm::Sha256.digest(x))
+                    .build()
+            })
+            .collect();
+        let mut digests = Sha256::new();
+        for x in data {
+            digests.update(Sha256::digest(x));
+        }
+        let value = base64::engine::general_purpose::STANDARD.encode(digests.finalize());
+        let expected = format!("sha256:{value}-2");
+        assert_eq!(expected_checksum(&args, &parts, None).unwrap(), expected);
+        // the part order of the completion does not matter
+        let reversed: Vec<_> = parts.iter().rev().cloned().collect();
+        assert_eq!(expected_checksum(&args, &reversed, None).unwrap(), expected);
+        assert_eq!(
+            expected_checksum(&args, &parts, Some("abc=")).unwrap(),
+            "sha256:abc="
+        );
+
+        let missing = [CompletedPart::builder().part_number(1).build()];
+        assert!(expected_checksum(&args, &missing, None).is_err());
+        assert!(expected_checksum(&self::args(&[]), &parts, None).is_err());
+    }
+
+    #[test]
+    fn bucket_names() {
+        let args = args(&[]);
+        for name in [
+            "my-bucket",
+            "logs.example.com",
+            "abc",
+            "my-bucket--use1-az4--x-s3",
+        ] {
+            assert!(check_bucket_name(&args, name).is_ok(), "{name}");
+        }
+        for name in [
+            "ab",
+            "My_Bucket",
+            "xn--bucket",
+            "bucket-s3alias",
+            &"a".repeat(64),
+        ] {
+            assert!(check_bucket_name(&args, name).is_err(), "{name}");
+        }
+        let args = self::args(&["--skip-bucket-validation"]);
+        assert!(check_bucket_name(&args, "My_Bucket").is_ok());
+    }
+
+    #[test]
+    fn job_options() {
+        let parse = |x: &[&str]| Args::try_parse_from(["s3put"].iter().chain(x)).unwrap();
+        let args = parse(&["--sort", "size", "-i", "file", "s3://bucket/key"]);
+        assert!(check_job_options(&args).is_err());
+        let args = parse(&["--limit", "3", "-i", "file", "s3://bucket/key"]);
+        assert!(check_job_options(&args).is_err());
+        let args = parse(&["--sort", "size", "--limit", "3", "--manifest", "jobs.tsv"]);
+        assert!(check_job_options(&args).is_ok());
+        let args = parse(&["--limit", "3", "--recursive", "-i", "dir", "s3://bucket/"]);
+        assert!(check_job_options(&args).is_ok());
+        for flag in [
+            "--bench",
+            "--self-test",
+            "--download",
+            "--write-checksum=sums",
+        ] {
+            let args = ["s3put", "--recursive", "-i", "dir", flag, "s3://bucket/"];
+            assert!(Args::try_parse_from(args).is_err(), "{flag}");
+        }
+        assert!(check_job_options(&parse(&["s3://bucket/key"])).is_ok());
+        let args = parse(&["--sync", "-i", "file", "s3://bucket/key"]);
+        assert!(check_job_options(&args).is_err());
+        assert!(check_job_options(&parse(&["--sync", "--manifest", "jobs.tsv"])).is_ok());
+        let args = [
+            "s3put",
+            "--manifest",
+            "jobs.tsv",
+            "--write-checksum",
+            "sums",
+        ];
+        assert!(Args::try_parse_from(args).is_err());
+        let args = parse(&["--sync", "--recursive", "-i", "dir", "s3://bucket/"]);
+        assert!(check_job_options(&args).is_ok());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn directory() {
+        let dir = temp_dir("directory");
+        std::fs::create_dir_all(dir.join("sub/deep")).unwrap();
+        std::fs::write(dir.join("b.txt"), "b").unwrap();
+        std::fs::write(dir.join("sub/deep/a.txt"), "a").unwrap();
+        std::os::unix::fs::symlink("../b.txt", dir.join("sub/link")).unwrap();
+        std::os::unix::fs::symlink("..", dir.join("sub/loop")).unwrap();
+
+        let jobs = directory_jobs(&dir, "s3://bucket/prefix").unwrap();
+        let expected = [
+            (dir.join("b.txt"), "s3://bucket/prefix/b.txt"),
+            (
+                dir.join("sub/deep/a.txt"),
+                "s3://bucket/prefix/sub/deep/a.txt",
+            ),
+            (dir.join("sub/link"), "s3://bucket/prefix/sub/link"),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(x, y)| (x, y.to_string()))
+            .collect();
+        assert_eq!(jobs, expected);
+
+        let jobs = directory_jobs(&dir, "s3://bucket/").unwrap();
+        assert_eq!(jobs[0].1, "s3://bucket/b.txt");
+        assert!(directory_jobs(&dir.join("missing"), "s3://bucket/").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Output shared with the test that reads it
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            // written in pieces, so that lines written at once would show
+            let len = buf.len().min(7);
+            self.0.lock().unwrap().extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn log_lines() {
+        // the only test that logs through the log writer
+        let captured = Captured::default();
+        assert!(LOG.set(log_writer(captured.clone())).is_ok());
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                std::thread::spawn(move || {
+                    let subscriber = tracing_subscriber::fmt()
+                        .with_writer(|| EventBuffer(Vec::new()))
+                        .with_ansi(false)
+                        .without_time()
+                        .with_target(false)
+                        .finish();
+                    tracing::subscriber::with_default(subscriber, || {
+                        for line in 0..100 {
+                            tracing::warn!(
+                                part_number = line,
+                                "Retrying {thread} {line}: {}",
+                                "x".repeat(200)
+                            );
+                        }
+                    });
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        flush_log();
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 800);
+        let mut seen = std::collections::HashSet::new();
+        for line in lines {
+            let (_, event) = line.split_once("WARN ").unwrap();
+            let (message, fields) = event.split_once(": ").unwrap();
+            let (thread, number) = message
+                .strip_prefix("Retrying ")
+                .unwrap()
+                .split_once(' ')
+                .unwrap();
+            assert_eq!(
+                fields,
+                format!("{} part_number={number}", "x".repeat(200)),
+                "{line}"
+            );
+            assert!(seen.insert((thread.to_string(), number.to_string())));
+        }
+    }
+
+    #[test]
+    fn tar_parts() {
+        let dir = temp_dir("tar");
+        let first: Vec<u8> = (0..1500).map(|x| x as u8).collect();
+        let second: Vec<u8> = (0..2500).map(|x| (x * 7) as u8).collect();
+        std::fs::write(dir.join("a.bin"), &first).unwrap();
+        std::fs::write(dir.join("b.bin"), &second).unwrap();
+
+        // parts of 1000 bytes end within the files and their headers, and span both files
+        let mut input = Input::Reader(Box::new(TarStream::new(&dir).unwrap()));
+        let mut archive = Vec::new();
+        loop {
+            let (buffer, end_of_file) = input.read_part(Some(1000)).unwrap();
+            assert!(buffer.len() == 1000 || end_of_file);
+            archive.extend_from_slice(&buffer);
+            if end_of_file {
+                break;
+            }
+        }
+        let mut files = std::collections::BTreeMap::new();
+        for entry in tar::Archive::new(&archive[..]).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().into_owned();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            if let Some(name) = path.file_name() {
+                files.insert(name.to_string_lossy().into_owned(), content);
+            }
+        }
+        assert_eq!(files["a.bin"], first);
+        assert_eq!(files["b.bin"], second);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn destination(bucket: &str, key: &str) -> Destination {
+        Destination {
+            bucket: bucket.into(),
+            key: key.into(),
+            profile: None,
+            config: Arc::new(std::sync::RwLock::new((
+                0,
+                aws_config::SdkConfig::builder().build(),
+            ))),
+            upload_id: "upload".into(),
+            sse_customer_key: None,
+            completed_parts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn resumed_part_sizes() {
+        let dir = temp_dir("resume");
+        let input = dir.join("input");
+        std::fs::write(&input, vec![0_u8; 12 * MB]).unwrap();
+        let paths = [("bucket".to_string(), "key".to_string())];
+        let state = UploadState::new(&dir, &input, &paths, 5 * MB).unwrap();
+        let destination = destination("bucket", "key");
+        let part = |number, size: usize| {
+            s3::types::Part::builder()
+                .part_number(number)
+                .size(size as i64)
+                .build()
+        };
+        let parts = [part(1, 5 * MB), part(2, 5 * MB), part(3, 2 * MB)];
+        assert!(state.check_parts(&destination, &parts).is_ok());
+        // parts may be missing, e.g. those still in flight when the upload stopped
+        assert!(state.check_parts(&destination, &parts[1..]).is_ok());
+        assert!(state.check_parts(&destination, &[part(1, 8 * MB)]).is_err());
+        assert!(state.check_parts(&destination, &[part(3, 5 * MB)]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resumed_block_size() {
+        let dir = temp_dir("state");
+        let input = dir.join("input");
+        std::fs::write(&input, vec![0_u8; 12 * MB]).unwrap();
+        let paths = [("bucket".to_string(), "key".to_string())];
+        let mut state = UploadState::new(&dir, &input, &paths, 5 * MB).unwrap();
+        assert!(state.load().unwrap().is_none());
+        state.save(&[destination("bucket", "key")]).unwrap();
+
+        let uploads = [("key".to_string(), "upload".to_string())];
+        let same = UploadState::new(&dir, &input, &paths, 5 * MB).unwrap();
+        assert_eq!(same.load().unwrap().unwrap(), uploads);
+        let changed = UploadState::new(&dir, &input, &paths, 8 * MB).unwrap();
+        let e = changed.load().unwrap_err().to_string();
+        assert!(e.contains("uses a --block-size of 5.0MB"), "{e}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Returns at most 3 bytes per read, like a slow pipe
+    struct Trickle(std::io::Cursor<Vec<u8>>);
+
+    impl std::io::Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = buf.len().min(3);
+            self.0.read(&mut buf[..size])
+        }
+    }
+
+    #[test]
+    fn chunked_reads() {
+        let data: Vec<u8> = (0..100).collect();
+        let input = Input::Reader(Box::new(Trickle(std::io::Cursor::new(data.clone()))));
+        let Input::Reader(mut reader) = input.buffered(Some(40)) else {
+            unreachable!();
+        };
+        // every read is served from a chunk filled up to its size despite the short reads
+        let mut buffer = [0_u8; 64];
+        assert_eq!(reader.read(&mut buffer).unwrap(), 40);
+        assert_eq!(buffer[..40], data[..40]);
+        assert_eq!(reader.read(&mut buffer[..10]).unwrap(), 10);
+        assert_eq!(reader.read(&mut buffer).unwrap(), 30);
+        assert_eq!(reader.read(&mut buffer).unwrap(), 20);
+        assert_eq!(buffer[..20], data[80..]);
+        assert_eq!(reader.read(&mut buffer).unwrap(), 0);
+    }
+
+    /// Sizes and end flags of the parts of `size` read from a trickling input of `len` bytes
+    fn read_parts(len: u8, size: usize) -> Vec<(usize, bool)> {
+        let data: Vec<u8> = (0..len).collect();
+        let mut input = Input::Reader(Box::new(Trickle(std::io::Cursor::new(data.clone()))));
+        let mut parts = Vec::new();
+        let mut read = Vec::new();
+        loop {
+            let (buffer, end_of_file) = input.read_part(Some(size)).unwrap();
+            read.extend_from_slice(&buffer);
+            parts.push((buffer.len(), end_of_file));
+            if end_of_file {
+                assert_eq!(read, data);
+                return parts;
+            }
+        }
+    }
+
+    #[test]
+    fn part_reads() {
+        // an input ending at a part boundary only tells with an empty part after it
+        let aligned = read_parts(12, 4);
+        assert_eq!(aligned, [(4, false), (4, false), (4, false), (0, true)]);
+        assert_eq!(
+            read_parts(13, 4),
+            [(4, false), (4, false), (4, false), (1, true)]
+        );
+        assert_eq!(read_parts(11, 4), [(4, false), (4, false), (3, true)]);
+        assert_eq!(read_parts(1, 4), [(1, true)]);
+        assert_eq!(read_parts(0, 4), [(0, true)]);
+
+        let mut buffer = [0_u8; 8];
+        let mut empty = std::io::empty();
+        assert_eq!(read_part(&mut empty, &mut buffer).unwrap(), (0, true));
+        assert_eq!(read_part(&mut empty, &mut []).unwrap(), (0, false));
+        let mut input = Trickle(std::io::Cursor::new(vec![1; 8]));
+        assert_eq!(read_part(&mut input, &mut buffer).unwrap(), (8, false));
+        assert_eq!(read_part(&mut input, &mut buffer).unwrap(), (0, true));
+    }
+
+    /// A destination whose requests `respond` answers instead of S3
+    fn mocked_destination(
+        bucket: &str,
+        respond: impl Fn(http::Request<s3::primitives::SdkBody>) -> http::Response<String>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Destination {
+        let credentials = s3::config::Credentials::new("old-key", "secret", None, None, "test");
+        let config = aws_config::SdkConfig::builder()
+            .http_client(aws_smithy_http_client::test_util::infallible_client_fn(
+                respond,
+            ))
+            .region(s3::config::Region::new("us-east-1"))
+            .credentials_provider(s3::config::SharedCredentialsProvider::new(credentials))
+            .retry_config(s3::config::retry::RetryConfig::disabled())
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .build();
+        Destination {
+            config: Arc::new(std::sync::RwLock::new((0, config))),
+            ..destination(bucket, "key")
+        }
+    }
+
+    fn respond(status: u16, body: &str) -> http::Response<String> {
+        let response = http::Response::builder().status(status);
+        let response = response.header("ETag", "\"etag\"");
+        response.body(body.to_string()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn expired_credentials() {
+        // the reload reads the credentials from the environment, as the default chain does
+        for (name, value) in [
+            ("AWS_ACCESS_KEY_ID", "new-key"),
+            ("AWS_SECRET_ACCESS_KEY", "secret"),
+            ("AWS_REGION", "us-east-1"),
+            ("AWS_EC2_METADATA_DISABLED", "true"),
+            ("AWS_CONFIG_FILE", "/nonexistent"),
+            ("AWS_SHARED_CREDENTIALS_FILE", "/nonexistent"),
+        ] {
+            std::env::set_var(name, value);
+        }
+        for name in [
+            "AWS_SESSION_TOKEN",
+            "AWS_PROFILE",
+            "AWS_ENDPOINT_URL",
+            "AWS_ENDPOINT_URL_S3",
+        ] {
+            std::env::remove_var(name);
+        }
+        let bucket = "expiring-bucket";
+        let key = (None, None, bucket.to_string());
+        BUCKET_REGIONS.lock().unwrap().insert(key, None);
+
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = requests.clone();
+        let destination = mocked_destination(bucket, move |request| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            let authorization = request.headers()["authorization"].to_str().unwrap();
+            match authorization.contains("Credential=old-key/") {
+                true => respond(400, "<Error><Code>ExpiredToken</Code></Error>"),
+                false => respond(200, ""),
+            }
+        });
+        // without retries, only the reload lets it succeed
+        let args = args(&["--max-retries", "0"]);
+        let part = upload_part(
+            &destination,
+            &bytes::Bytes::from_static(b"part"),
+            1,
+            &RetryPolicy::new(&args),
+            BodyOptions::new(&args),
+        )
+        .await
+        .unwrap();
+        assert_eq!(part.retries, 0);
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+        assert_eq!(destination.config.read().unwrap().0, 1);
+    }
+
+    #[tokio::test]
+    async fn panicking_part() {
+        let (sender, receiver) = channel::bounded(1);
+        let token = Token {
+            sender,
+            result: None,
+        };
+        spawn_part(token, 3, async { panic!("injected") });
+        let received = tokio::task::spawn_blocking(move || receiver.recv()).await;
+        let Err(e) = received.unwrap().unwrap() else {
+            panic!("the part did not fail");
+        };
+        assert_eq!(e.to_string(), "Upload of part 3 panicked");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn cancelled_parts() {
+        const PARTS: usize = 2000;
+        let (sender, receiver) = channel::bounded(PARTS);
+        let tasks: Vec<_> = (0..PARTS)
+            .map(|i| {
+                let token = Token {
+                    sender: sender.clone(),
+                    result: None,
+                };
+                spawn_part(token, i as i32 + 1, async move {
+                    match i % 2 {
+                        0 => std::future::pending().await,
+                        _ => tokio::task::yield_now().await,
+                    }
+                    Ok(Vec::new())
+                })
+            })
+            .collect();
+        for task in &tasks {
+            task.abort();
+        }
+        drop(sender);
+
+        // every token comes back exactly once, whether its part finished or was cancelled
+        let results = tokio::task::spawn_blocking(move || {
+            let results: Vec<_> = (0..PARTS)
+                .map(|_| receiver.recv_timeout(Duration::from_secs(10)).unwrap())
+                .collect();
+            assert!(receiver.recv().is_err());
+            results
+        })
+        .await
+        .unwrap();
+        let cancelled = results
+            .iter()
+            .filter(|x| matches!(x, Err(e) if e.to_string() == "Part upload was cancelled"))
+            .count();
+        let finished = results.iter().filter(|x| matches!(x, Ok(Some(_)))).count();
+        assert!(cancelled >= PARTS / 2, "{cancelled}");
+        assert_eq!(cancelled + finished, PARTS);
+    }
+
+    /// A page of a ListParts response, with the next marker if truncated
+    fn parts_page(numbers: std::ops::RangeInclusive<i32>, next: Option<i32>) -> String {
+        let parts: String = numbers
+            .map(|x| format!("<Part><PartNumber>{x}</PartNumber><Size>5</Size></Part>"))
+            .collect();
+        let truncated = match next {
+            Some(x) => format!(
+                "<IsTruncated>true</IsTruncated><NextPartNumberMarker>{x}</NextPartNumberMarker>"
+            ),
+            None => "<IsTruncated>false</IsTruncated>".to_string(),
+        };
+        format!("<ListPartsResult>{truncated}{parts}</ListPartsResult>")
+    }
+
+    #[tokio::test]
+    async fn listed_parts() {
+        let markers = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = markers.clone();
+        let destination = mocked_destination("bucket", move |request| {
+            let query = request.uri().query().unwrap_or_default().to_string();
+            let marker = query
+                .split('&')
+                .find_map(|x| x.strip_prefix("part-number-marker="))
+                .map(str::to_string);
+            seen.lock().unwrap().push(marker.clone());
+            match marker.as_deref() {
+                None => respond(200, &parts_page(1..=2, Some(2))),
+                Some("2") => respond(200, &parts_page(3..=4, Some(4))),
+                _ => respond(200, &parts_page(5..=5, None)),
+            }
+        });
+        let parts = list_parts(&destination).await.unwrap();
+        let numbers: Vec<_> = parts.iter().filter_map(|x| x.part_number).collect();
+        assert_eq!(numbers, [1, 2, 3, 4, 5]);
+        let expected = [None, Some("2".to_string()), Some("4".to_string())];
+        assert_eq!(*markers.lock().unwrap(), expected);
+
+        // a truncated page without a new marker would be listed again forever
+        let destination =
+            mocked_destination("bucket", |_| respond(200, &parts_page(1..=2, Some(2))));
+        assert!(list_parts(&destination).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn completed_uploads() {
+        let args = args(&[]);
+        let parts = || {
+            let part = CompletedPart::builder().part_number(1).e_tag("\"etag\"");
+            vec![part.build()]
+        };
+        let completed = "<CompleteMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key>\
+            <ETag>\"etag-1\"</ETag></CompleteMultipartUploadResult>";
+        let destination = mocked_destination("bucket", move |_| respond(200, completed));
+        let result = complete_upload(&args, &destination, parts(), 5)
+            .await
+            .unwrap();
+        assert_eq!(result.etag, "\"etag-1\"");
+
+        // S3 can send 200 and only report the failure in the body
+        let failed = "<Error><Code>InternalError</Code><Message>We encountered an internal \
+            error.</Message></Error>";
+        let destination = mocked_destination("bucket", move |_| respond(200, failed));
+        let Err(e) = complete_upload(&args, &destination, parts(), 5).await else {
+            panic!("an error body completed the upload");
+        };
+        let message = e.to_string();
+        assert!(
+            message.starts_with("Failed to complete upload of 1 parts"),
+            "{e}"
+        );
+        assert!(!message.contains("no ETag"), "{e}");
+
+        let incomplete = "<CompleteMultipartUploadResult><Bucket>bucket</Bucket>\
+            </CompleteMultipartUploadResult>";
+        let destination = mocked_destination("bucket", move |_| respond(200, incomplete));
+        let Err(e) = complete_upload(&args, &destination, parts(), 5).await else {
+            panic!("a result without an ETag completed the upload");
+        };
+        assert!(e.to_string().contains("no ETag"), "{e}");
+    }
+
+    /// A reader that fails after `remaining` bytes, like a disk with a bad sector
+    struct Failing {
+        remaining: usize,
+    }
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::Error::other("bad sector"));
+            }
+            let len = buf.len().min(self.remaining);
+            buf[..len].fill(1);
+            self.remaining -= len;
+            Ok(len)
+        }
+    }
+
+    /// Held by tests that run upload(), which reacts to the signal of `interrupted_upload`
+    static UPLOADS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn failing_input() {
+        let _uploads = UPLOADS.lock().await;
+        let args = args(&["--block-size", "5MB"]);
+        let destination = mocked_destination("bucket", |_| respond(200, ""));
+        let input = Input::Reader(Box::new(Failing {
+            remaining: 5 * 1024 * 1024 + 100,
+        }));
+        let destinations = Arc::new(vec![destination]);
+        let Err(e) = upload(&args, destinations, input, None, 2, None, None).await else {
+            panic!("the upload of a failing input succeeded");
+        };
+        // the first part was complete, the read failed in the second
+        let failure = e.downcast_ref::<UploadFailure>().unwrap();
+        assert!(matches!(failure.reason, FailureReason::SourceRead));
+        assert_eq!(failure.part_number, Some(2));
+        assert!(failure.message.contains("offset 5242880"), "{e}");
+        assert!(failure.message.contains("bad sector"), "{e}");
+    }
+
+    /// An input that sends SIGTERM to the process once `signal_at` bytes are read, and then
+    /// slows down
+    #[cfg(target_os = "linux")]
+    struct Signalling {
+        read: usize,
+        signal_at: usize,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Read for Signalling {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.read >= self.signal_at {
+                if self.signal_at > 0 {
+                    // the signal handler of the upload is installed by now
+                    std::thread::sleep(Duration::from_millis(200));
+                    // SAFETY: kill only sends a signal, which the test has a handler for
+                    unsafe { libc::kill(std::process::id() as i32, libc::SIGTERM) };
+                    self.signal_at = 0;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            // bounded, in case the signal is not seen
+            let len = buf
+                .len()
+                .min((100 * 1024 * 1024_usize).saturating_sub(self.read));
+            buf[..len].fill(1);
+            self.read += len;
+            Ok(len)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn interrupted_upload() {
+        let _uploads = UPLOADS.lock().await;
+        // a handler keeps SIGTERM from ending the test process before upload() listens to it
+        let _terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let destination = mocked_destination("bucket", move |request| {
+            seen.lock().unwrap().push(request.method().to_string());
+            respond(200, "")
+        });
+        let args = args(&["--block-size", "5MB"]);
+        let input = Input::Reader(Box::new(Signalling {
+            read: 0,
+            signal_at: 10 * 1024 * 1024,
+        }));
+        let destinations = Arc::new(vec![destination.clone()]);
+        let uploaded = upload(&args, destinations, input, None, 2, None, None).await;
+        let uploaded = uploaded.unwrap();
+        assert!(uploaded.interrupted);
+        assert!(uploaded.num_parts < 20, "{}", uploaded.num_parts);
+        // every part that was sent completed before the upload returned
+        let sent = requests.lock().unwrap().len();
+        assert_eq!(uploaded.parts[0].len(), sent);
+        assert_eq!(uploaded.num_parts, sent);
+
+        abort_upload(&destination).await;
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.last().map(String::as_str), Some("DELETE"));
+        assert_eq!(requests.len(), sent + 1);
+    }
+
+    #[test]
+    fn retry_after_headers() {
+        use aws_smithy_runtime_api::http::{Response, StatusCode};
+        let error = |value: Option<&'static str>| {
+            let status = StatusCode::try_from(503).unwrap();
+            let mut response = Response::new(status, s3::primitives::SdkBody::empty());
+            if let Some(value) = value {
+                response.headers_mut().insert("Retry-After", value);
+            }
+            s3::error::SdkError::service_error((), response)
+        };
+        assert_eq!(retry_after(&error(Some("7"))), Duration::from_secs(7));
+        assert_eq!(retry_after(&error(Some(" 120 "))), Duration::from_secs(120));
+        assert_eq!(retry_after(&error(None)), Duration::ZERO);
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        assert_eq!(retry_after(&error(Some(date))), Duration::ZERO);
+        assert_eq!(retry_after(&error(Some("-1"))), Duration::ZERO);
+        // an error without a response, e.g. a timeout, has no header either
+        let timeout = s3::error::SdkError::<(), Response>::timeout_error("timed out");
+        assert_eq!(retry_after(&timeout), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn connection_reset() {
+        use std::io::Write;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            // the first connection is dropped with most of the part still unread, which resets it
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 100]).unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0];
+            while !request.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            let head = String::from_utf8(request).unwrap().to_lowercase();
+            if head.contains("expect: 100-continue") {
+                stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+            }
+            let length = head
+                .lines()
+                .find_map(|x| x.strip_prefix("content-length: "))
+                .and_then(|x| x.trim().parse().ok())
+                .unwrap();
+            std::io::copy(&mut (&mut stream).take(length), &mut std::io::sink()).unwrap();
+            let response = "HTTP/1.1 200 OK\r\nETag: \"etag\"\r\nContent-Length: 0\r\n\r\n";
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        let client = aws_smithy_http_client::hyper_014::HyperClientBuilder::new()
+            .build(hyper::client::HttpConnector::new());
+        let credentials = s3::config::Credentials::new("key", "secret", None, None, "test");
+        let config = aws_config::SdkConfig::builder()
+            .http_client(client)
+            .endpoint_url(endpoint)
+            .region(s3::config::Region::new("us-east-1"))
+            .credentials_provider(s3::config::SharedCredentialsProvider::new(credentials))
+            .retry_config(s3::config::retry::RetryConfig::disabled())
+            .behavior_version(aws_config::BehaviorVersion::latest())
+            .build();
+        let destination = Destination {
+            config: Arc::new(std::sync::RwLock::new((0, config))),
+            ..destination("bucket", "key")
+        };
+        let args = args(&[]);
+        let buffer = bytes::Bytes::from(vec![1; 512 * 1024]);
+        let upload = upload_part(
+            &destination,
+            &buffer,
+            1,
+            &RetryPolicy::new(&args),
+            BodyOptions::new(&args),
+        )
+        .await
+        .unwrap();
+        server.join().unwrap();
+        // the lost connection is retried on top of the retries, but still counted
+        assert_eq!(upload.retries, 1);
+        assert_eq!(upload.part.e_tag(), Some("\"etag\""));
+    }
+
+    #[tokio::test]
+    async fn verified_ranges() {
+        let get = |checksum: &'static str| async move {
+            let destination = mocked_destination("bucket", move |_| {
+                let response = http::Response::builder().status(206);
+                let response = response.header("x-amz-checksum-crc32", checksum);
+                response.body("hello".to_string()).unwrap()
+            });
+            let client = s3_client(&destination.config());
+            let retry_policy = RetryPolicy::new(&args(&["--max-retries", "0"]));
+            let range = DownloadRange {
+                client: &client,
+                bucket: "bucket",
+                key: "key",
+                etag: None,
+                sse_customer_key: None,
+                retry_policy: &retry_policy,
+                verify_checksum: true,
+            };
+            range
+                .get(0, 5)
+                .await
+                .map(|(data, _, verified)| (data, verified))
+        };
+        let checksum = ChecksumAlgorithm::Crc32.digest(b"hello");
+        let checksum: &'static str = Box::leak(checksum.into_boxed_str());
+        let (data, verified) = get(checksum).await.unwrap();
+        assert_eq!(&data[..], b"hello");
+        assert!(verified);
+        // the checksum of a part of a multipart object does not cover the range
+        let (_, verified) = get("AAAAAA==-2").await.unwrap();
+        assert!(!verified);
+        assert!(get("AAAAAA==").await.is_err());
 
-    if let Err(e) = rt.block_on(async move { run(&args).await }) {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+        let checksums = [&None, &Some("a-2".into()), &None, &Some("b".into()), &None];
+        let stored = stored_checksum(checksums).map(|(x, checksum)| (x.name(), checksum));
+        assert_eq!(stored, Some(("sha1", "b".to_string())));
     }
 }